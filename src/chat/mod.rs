@@ -0,0 +1,226 @@
+use bevy::prelude::*;
+use bevy_egui::{EguiContexts, EguiSet, EguiStartupSet};
+use egui::{Align2, Color32, Frame, Id, Key, RichText, TextEdit};
+use std::collections::VecDeque;
+
+use crate::client::ServerConnection;
+use crate::input_n_state::{AppParams, MultiplayerState};
+use crate::players::team::PlayerTeam;
+use crate::protocol::MultiplayerMessage;
+use crate::server::chat::{clean_text, MAX_CHAT_BYTES, SERVER_CHAT_ID};
+use crate::{ClientId, FirstPerson};
+
+//Chat lines are shown for CHAT_SHOW_SECS and fade out over the last CHAT_FADE_SECS.
+const CHAT_SHOW_SECS: f64 = 10.0;
+const CHAT_FADE_SECS: f64 = 2.0;
+//Lines kept for the history shown while typing.
+const CHAT_HISTORY: usize = 50;
+const CHAT_HISTORY_SHOWN: usize = 10;
+
+//Chat relayed by the server.
+#[derive(Event)]
+pub struct ChatEvent {
+    pub client_id: u64,
+    pub team_only: bool,
+    pub text: String,
+}
+
+struct ChatLine {
+    sender: String,
+    team_only: bool,
+    text: String,
+    time: f64,
+}
+
+#[derive(Resource, Default)]
+struct ChatLog {
+    lines: VecDeque<ChatLine>,
+}
+
+impl ChatLog {
+    fn push(&mut self, line: ChatLine) {
+        if self.lines.len() >= CHAT_HISTORY {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+}
+
+//The chat input line, open while typing.
+#[derive(Resource, Default)]
+pub struct ChatInput {
+    pub open: bool,
+    //The key opening the chat is not an input for the chat line.
+    just_opened: bool,
+    team_only: bool,
+    text: String,
+}
+
+pub struct ChatPlugin;
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ChatEvent>();
+        app.init_resource::<ChatLog>();
+        app.init_resource::<ChatInput>();
+        app.add_systems(OnEnter(MultiplayerState::Connected), reset_chat);
+        app.add_systems(
+            Update,
+            (
+                receive_chat.after(crate::players::update_world_from_server_messages),
+                open_chat,
+            )
+                .run_if(in_state(MultiplayerState::Connected)),
+        );
+        app.add_systems(
+            Update,
+            chat_overlay
+                .after(crate::input_n_state::keyboard_event_system)
+                .after(receive_chat)
+                .after(open_chat)
+                .after(EguiStartupSet::InitContexts)
+                .after(EguiSet::InitContexts)
+                .run_if(in_state(MultiplayerState::Connected)),
+        );
+    }
+}
+
+fn reset_chat(mut log: ResMut<ChatLog>, mut input: ResMut<ChatInput>) {
+    *log = ChatLog::default();
+    *input = ChatInput::default();
+}
+
+fn receive_chat(
+    time: Res<Time<Real>>,
+    mut chat: EventReader<ChatEvent>,
+    mut log: ResMut<ChatLog>,
+    names: Query<(&ClientId, &Name)>,
+) {
+    for event in chat.read() {
+        let sender = if event.client_id == SERVER_CHAT_ID {
+            "Server".to_string()
+        } else {
+            names
+                .iter()
+                .find(|(cid, _)| cid.id == event.client_id)
+                .map_or(event.client_id.to_string(), |(_, name)| name.to_string())
+        };
+        log.push(ChatLine {
+            sender,
+            team_only: event.team_only,
+            text: event.text.clone(),
+            time: time.elapsed_secs_f64(),
+        });
+    }
+}
+
+//Enter opens the chat, Y the team chat when playing in a team.
+fn open_chat(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut input: ResMut<ChatInput>,
+    first_person: Query<Option<&PlayerTeam>, With<FirstPerson>>,
+) {
+    if input.open {
+        return;
+    }
+    let in_team = matches!(first_person.get_single(), Ok(Some(_)));
+    if keys.just_pressed(KeyCode::Enter) {
+        input.open = true;
+        input.just_opened = true;
+        input.team_only = false;
+    } else if keys.just_pressed(KeyCode::KeyY) && in_team {
+        input.open = true;
+        input.just_opened = true;
+        input.team_only = true;
+    }
+}
+
+fn chat_overlay(
+    mut contexts: EguiContexts,
+    time: Res<Time<Real>>,
+    (mut log, mut input): (ResMut<ChatLog>, ResMut<ChatInput>),
+    connection: ServerConnection,
+    app_params: Res<AppParams>,
+) {
+    let now = time.elapsed_secs_f64();
+    let mut send: Option<String> = None;
+    egui::Area::new(Id::new("chat"))
+        .anchor(Align2::LEFT_BOTTOM, [12.0, -48.0])
+        .show(contexts.ctx_mut(), |ui| {
+            //The history while typing, otherwise the recent lines fading out.
+            let shown = if input.open {
+                CHAT_HISTORY_SHOWN
+            } else {
+                log.lines.len()
+            };
+            let lines = log.lines.iter().skip(log.lines.len().saturating_sub(shown));
+            Frame::none()
+                .fill(Color32::from_black_alpha(if input.open { 120 } else { 0 }))
+                .inner_margin(4.0)
+                .show(ui, |ui| {
+                    for line in lines {
+                        let age = now - line.time;
+                        if !input.open && age > CHAT_SHOW_SECS {
+                            continue;
+                        }
+                        let alpha = if input.open {
+                            1.0
+                        } else {
+                            ((CHAT_SHOW_SECS - age) / CHAT_FADE_SECS).clamp(0.0, 1.0) as f32
+                        };
+                        let color = if line.team_only {
+                            Color32::from_rgb(120, 230, 120)
+                        } else {
+                            Color32::from_rgb(240, 240, 240)
+                        };
+                        let team = if line.team_only { "(Team) " } else { "" };
+                        ui.label(
+                            RichText::new(format!("{}{}: {}", team, line.sender, line.text))
+                                .color(color.gamma_multiply(alpha)),
+                        );
+                    }
+                });
+            if input.open {
+                let hint = if input.team_only { "Team:" } else { "Say:" };
+                let response = ui
+                    .horizontal(|ui| {
+                        ui.label(RichText::new(hint).color(Color32::WHITE));
+                        ui.add(
+                            TextEdit::singleline(&mut input.text)
+                                .char_limit(MAX_CHAT_BYTES)
+                                .desired_width(300.0),
+                        )
+                    })
+                    .inner;
+                response.request_focus();
+                if input.just_opened {
+                    input.just_opened = false;
+                } else if ui.input(|i| i.key_pressed(Key::Enter)) {
+                    send = Some(std::mem::take(&mut input.text));
+                    input.open = false;
+                } else if ui.input(|i| i.key_pressed(Key::Escape)) {
+                    input.text.clear();
+                    input.open = false;
+                }
+            }
+        });
+    let Some(text) = send else {
+        return;
+    };
+    //Cut to MAX_CHAT_BYTES as the server does, the char_limit of the input counts characters.
+    let text = clean_text(&text);
+    if text.is_empty() {
+        return;
+    }
+    connection.send(MultiplayerMessage::Chat {
+        client_id: connection.client_id(),
+        team_only: input.team_only,
+        text: text.clone(),
+    });
+    //The server does not echo chat to the sender.
+    log.push(ChatLine {
+        sender: app_params.player_name.clone(),
+        team_only: input.team_only,
+        text,
+        time: now,
+    });
+}
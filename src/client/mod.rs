@@ -1,18 +1,24 @@
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
-use renetcode::{ClientAuthentication, ConnectToken, NetcodeClient, NETCODE_MAX_PACKET_BYTES};
+use renetcode::{
+    ClientAuthentication, ConnectToken, DisconnectReason as NetcodeDisconnect, NetcodeClient,
+    NETCODE_MAX_PACKET_BYTES,
+};
 use std::{
-    io::{Error, Read, Write},
+    io::Error,
     net::{SocketAddr, UdpSocket},
     sync::{
         mpsc,
-        mpsc::{Receiver, Sender},
+        mpsc::{Receiver, Sender, TryRecvError},
         Mutex,
     },
-    time::{Instant, SystemTime, UNIX_EPOCH},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::input_n_state::MultiplayerState;
-use crate::server::*;
+use crate::input_n_state::{AppParams, MultiplayerState};
+use crate::protocol::MultiplayerMessage;
+use crate::token::request_connect_token;
 use crate::*;
 
 #[derive(Resource)]
@@ -24,145 +30,142 @@ pub struct MultiplayerMessageReceiver {
     pub receiver: Mutex<Receiver<MultiplayerMessage>>,
 }
 
-#[repr(u8)]
-pub enum MultiplayerMessage {
-    Connect {
-        client_id: u64,
-        location: Vec3,
-        direction: Vec3,
-        name: String,
-    },
-    Disconnect {
-        client_id: u64,
-    },
-    Move {
-        client_id: u64,
-        location: Vec3,
-    },
-    //    Rotate {
-    //        client_id: u64,
-    //        direction: Vec2,
-    //    },
-    None,
+//Sends messages to the server from bevy systems.
+#[derive(SystemParam)]
+pub struct ServerConnection<'w> {
+    sender: Res<'w, MultiplayerMessageSender>,
+    r_client: Res<'w, RenetClient>,
 }
-impl MultiplayerMessage {
-    pub fn get_id(&self) -> u8 {
-        match self {
-            MultiplayerMessage::None => 0,
-            MultiplayerMessage::Connect { .. } => 1,
-            MultiplayerMessage::Disconnect { .. } => 2,
-            MultiplayerMessage::Move { .. } => 3,
-            //MultiplayerMessage::Rotate { .. } => 4,
+
+impl ServerConnection<'_> {
+    pub fn client_id(&self) -> u64 {
+        self.r_client.get_client_id()
+    }
+
+    pub fn send(&self, message: MultiplayerMessage) {
+        if let Err(e) = self.sender.sender.send(message) {
+            warn!("Could not send MultiplayerMessage to the server. {}", e);
         }
     }
+}
 
-    pub fn get_buf(&self) -> Result<[u8; 100], Error> {
-        let buf = [0u8; 100];
-        let mut cursor = std::io::Cursor::new(buf);
+//A Ping from the server, answered with a Pong.
+#[derive(Event)]
+pub struct PingEvent(pub u32);
 
-        match self {
-            MultiplayerMessage::Connect {
-                client_id,
-                location,
-                direction,
-                name,
-            } => {
-                cursor.write(&self.get_id().to_le_bytes())?;
-                cursor.write(&client_id.to_le_bytes())?;
-                cursor.write(&location.x.to_le_bytes())?;
-                cursor.write(&location.y.to_le_bytes())?;
-                cursor.write(&location.z.to_le_bytes())?;
-                cursor.write(&direction.x.to_le_bytes())?;
-                cursor.write(&direction.y.to_le_bytes())?;
-                cursor.write(&direction.z.to_le_bytes())?;
-                let size: u8 = name.len() as u8;
-                cursor.write(&[size])?;
-                cursor.write(&name.as_bytes())?;
-                Ok(cursor.into_inner())
-            }
-            MultiplayerMessage::Disconnect { client_id } => {
-                cursor.write(&self.get_id().to_le_bytes())?;
-                cursor.write(&client_id.to_le_bytes())?;
-                Ok(cursor.into_inner())
-            }
-            MultiplayerMessage::Move {
-                client_id,
-                location,
-            } => {
-                cursor.write(&self.get_id().to_le_bytes())?;
-                cursor.write(&client_id.to_le_bytes())?;
-                cursor.write(&location.x.to_le_bytes())?;
-                cursor.write(&location.y.to_le_bytes())?;
-                cursor.write(&location.z.to_le_bytes())?;
-                Ok(cursor.into_inner())
-            }
-            MultiplayerMessage::None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No message??",
-                ))
-            }
-        }
+fn answer_pings(mut pings: EventReader<PingEvent>, connection: ServerConnection) {
+    for PingEvent(sequence) in pings.read() {
+        connection.send(MultiplayerMessage::Pong {
+            client_id: connection.client_id(),
+            sequence: *sequence,
+        });
     }
+}
 
-    pub fn get(buf: &[u8]) -> Result<MultiplayerMessage, Error> {
-        let cursor = &mut std::io::Cursor::new(buf);
-        let message_id: [u8; 1] = read_bytes::<1>(cursor)?;
-
-        match message_id {
-            [1] => {
-                //let _id: u16 = u16::from_le_bytes(read_bytes::<2>(cursor)?);
-                let client_id: u64 = u64::from_le_bytes(read_bytes::<8>(cursor)?);
-                let location: Vec3 = Vec3::new(
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                );
-                let direction: Vec3 = Vec3::new(
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                );
-                let size: u8 = u8::from_le_bytes(read_bytes::<1>(cursor)?);
-                let mut _name = vec![0u8; size as usize];
-                let name_array = _name.as_mut_slice();
-                assert_eq!(size as usize, name_array.len());
-                cursor.read_exact(name_array)?;
-                let name = String::from_utf8(_name)
-                    .expect("Error extract name from buf for MultiplayerMessage");
-                Ok(MultiplayerMessage::Connect {
-                    client_id,
-                    location,
-                    direction,
-                    name,
-                })
-            }
-            [2] => {
-                let client_id: u64 = u64::from_le_bytes(read_bytes::<8>(cursor)?);
-                Ok(MultiplayerMessage::Disconnect { client_id })
-            }
-            [3] => {
-                let client_id: u64 = u64::from_le_bytes(read_bytes::<8>(cursor)?);
-                let location: Vec3 = Vec3::new(
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                    f32::from_le_bytes(read_bytes::<4>(cursor)?),
-                );
-                Ok(MultiplayerMessage::Move {
-                    client_id,
-                    location,
-                })
-            }
-            _ => Ok(MultiplayerMessage::None),
+//Why the server disconnected this client, shown in the server window.
+#[derive(Resource, Default)]
+pub struct DisconnectReason(pub Option<String>);
+
+//Reconnect attempts after the connection is lost, shown as "Reconnecting... (n/5)".
+pub const RECONNECT_ATTEMPTS: u32 = 5;
+//The wait before the first attempt, doubled after each failed attempt.
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+
+//The wait before an attempt after a failed one.
+fn retry_delay(attempt: u32) -> Duration {
+    FIRST_RETRY * 2u32.pow(attempt.min(RECONNECT_ATTEMPTS))
+}
+
+//The last server connected to and the reconnect attempts after losing the connection.
+#[derive(Resource, Default)]
+pub struct Reconnect {
+    //The player name and the server url of the last connection.
+    server: Option<(String, String)>,
+    //The attempt in progress, counting from 1.
+    pub attempt: u32,
+    //Set while reconnecting.
+    lost_at: Option<Instant>,
+    next_attempt: Option<Instant>,
+    //The connect token requested on a thread, the token service may take a while to answer.
+    token: Option<Mutex<Receiver<Result<ConnectToken, String>>>>,
+}
+
+impl Reconnect {
+    //Keep the server of a new connection, any reconnect in progress is abandoned.
+    pub fn remember(&mut self, user_name: &str, url: &str) {
+        self.server = Some((user_name.to_string(), url.to_string()));
+        self.stop();
+    }
+
+    pub fn is_reconnecting(&self) -> bool {
+        self.lost_at.is_some()
+    }
+
+    //The player gave up, the server is not reconnected to until the next Connect.
+    pub fn cancel(&mut self) {
+        self.server = None;
+        self.stop();
+    }
+
+    pub fn stop(&mut self) {
+        self.attempt = 0;
+        self.lost_at = None;
+        self.next_attempt = None;
+        self.token = None;
+    }
+
+    //The connection timed out or an attempt failed. False when the attempts are used up or
+    //the window is over.
+    fn connection_lost(&mut self, now: Instant, window: Duration) -> bool {
+        if self.server.is_none() || window.is_zero() {
+            return false;
+        }
+        let lost_at = *self.lost_at.get_or_insert(now);
+        self.token = None;
+        if self.attempt >= RECONNECT_ATTEMPTS {
+            self.stop();
+            return false;
         }
+        let next_attempt = now + retry_delay(self.attempt);
+        if next_attempt > lost_at + window {
+            self.stop();
+            return false;
+        }
+        self.next_attempt = Some(next_attempt);
+        true
+    }
+}
+
+pub fn reconnect_window(app_params: &AppParams) -> Duration {
+    Duration::from_secs(app_params.reconnect_secs as u64)
+}
+
+//The netcode handshake and the protocol Hello must complete in this time.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+//The Hello is unreliable, it is sent again until the server accepts the player.
+const HELLO_RETRY: Duration = Duration::from_millis(250);
+
+//A netcode disconnect reason for the server window.
+pub fn disconnect_message(reason: NetcodeDisconnect) -> &'static str {
+    match reason {
+        NetcodeDisconnect::ConnectTokenExpired => "The connect token expired, connect again.",
+        NetcodeDisconnect::ConnectionRequestTimedOut
+        | NetcodeDisconnect::ConnectionResponseTimedOut => "The server did not answer.",
+        NetcodeDisconnect::ConnectionDenied => "The server is full.",
+        NetcodeDisconnect::ConnectionTimedOut => "The connection to the server timed out.",
+        NetcodeDisconnect::DisconnectedByServer => "Disconnected by the server.",
+        NetcodeDisconnect::DisconnectedByClient => "Disconnected.",
     }
 }
 
-#[inline]
-pub fn read_bytes<const N: usize>(src: &mut impl std::io::Read) -> Result<[u8; N], Error> {
-    let mut data = [0u8; N];
-    src.read_exact(&mut data)?;
-    Ok(data)
+//The server may be back soon.
+fn is_timeout(reason: NetcodeDisconnect) -> bool {
+    matches!(
+        reason,
+        NetcodeDisconnect::ConnectionTimedOut
+            | NetcodeDisconnect::ConnectionResponseTimedOut
+            | NetcodeDisconnect::ConnectionRequestTimedOut
+    )
 }
 
 #[derive(Resource)]
@@ -172,6 +175,16 @@ pub struct RenetClient {
     buffer: [u8; NETCODE_MAX_PACKET_BYTES],
     last_updated: Instant,
     client_id_16: u16,
+    //The protocol handshake is sent once netcode has connected, the last Hello sent.
+    hello_sent: Option<Instant>,
+    //Set once the server accepts the Hello, ending the Connecting state.
+    accepted: bool,
+    connect_started: Instant,
+    //The connect token requested on a thread by connect(), netcode starts when it arrives.
+    token: Option<Mutex<Receiver<Result<ConnectToken, String>>>>,
+    server_addr: Option<SocketAddr>,
+    //Why netcode disconnected.
+    netcode_reason: Option<NetcodeDisconnect>,
     //The renclient:
     //sender sends messages from the server to a channel for bevy to receive;
     //sender: None,
@@ -198,55 +211,114 @@ impl RenetClient {
             buffer: [0u8; NETCODE_MAX_PACKET_BYTES],
             last_updated: Instant::now(),
             client_id_16: 0,
+            hello_sent: None,
+            accepted: false,
+            connect_started: Instant::now(),
+            token: None,
+            server_addr: None,
+            netcode_reason: None,
             sender,
             receiver,
         }
     }
 
+    //Start a connection without blocking the frame, do_connecting connects once the token
+    //service answers.
     pub fn connect(
         &mut self,
-        user_name: &String,
-        connection: &String,
-    ) -> (Sender<MultiplayerMessage>, Receiver<MultiplayerMessage>) {
+        user_name: &str,
+        connection: &str,
+    ) -> Result<(Sender<MultiplayerMessage>, Receiver<MultiplayerMessage>), String> {
         //let server_addr: SocketAddr = format!("127.0.0.1:{}", PORT).parse().unwrap();
-        println!("Attempting connection: {} ...", connection);
-        let server_addr: SocketAddr = connection.parse().unwrap();
-        let username = Username(user_name.clone());
+        info!("Attempting connection: {} ...", connection);
+        let server_addr: SocketAddr = connection
+            .parse()
+            .map_err(|_| format!("{} is not an address with a port.", connection))?;
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        println!(
+        debug!(
             "Starting connecting at {:?} with username {}",
-            now, username.0,
+            now, user_name,
         );
-        let client_id = now.as_millis() as u64;
-        self.client_id_16 = client_id as u16;
-        let connect_token = ConnectToken::generate(
-            now,
-            PROTOCOL_ID,
-            300,
-            client_id,
-            15,
-            vec![server_addr],
-            Some(&username.to_netcode_user_data()),
-            PRIVATE_KEY,
-        )
-        .unwrap();
+        //The token service signs the token with the server private key.
+        let (sender, receiver) = mpsc::channel();
+        let user_name = user_name.to_string();
+        thread::spawn(move || {
+            let _ = sender.send(request_connect_token(server_addr, &user_name));
+        });
+        self.token = Some(Mutex::new(receiver));
+        self.server_addr = Some(server_addr);
+        self.connect_started = Instant::now();
+        Ok(self.open_channels())
+    }
+
+    pub fn connect_with_token(
+        &mut self,
+        connect_token: ConnectToken,
+    ) -> Result<(Sender<MultiplayerMessage>, Receiver<MultiplayerMessage>), String> {
+        self.connect_started = Instant::now();
+        self.start_netcode(connect_token)?;
+        Ok(self.open_channels())
+    }
+
+    fn start_netcode(&mut self, connect_token: ConnectToken) -> Result<(), String> {
+        self.client_id_16 = connect_token.client_id as u16;
         let authentication = ClientAuthentication::Secure { connect_token };
-        //self.udp_socket = Some(UdpSocket::bind("127.0.0.1:0").unwrap());
-        self.udp_socket = Some(UdpSocket::bind("0.0.0.0:4999").unwrap());
-        self.udp_socket
-            .as_ref()
-            .unwrap()
+        //Any free port, more clients on a host and a reconnect while the old socket is open.
+        let udp_socket = UdpSocket::bind("0.0.0.0:0")
+            .map_err(|e| format!("Could not bind the client socket. {}", e))?;
+        udp_socket
             .set_nonblocking(true)
-            .unwrap();
+            .map_err(|e| format!("Could not configure the client socket. {}", e))?;
+        self.udp_socket = Some(udp_socket);
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        self.client = Some(NetcodeClient::new(now, authentication).unwrap());
+        self.client = Some(
+            NetcodeClient::new(now, authentication)
+                .map_err(|e| format!("Invalid connect token. {}", e))?,
+        );
         self.last_updated = Instant::now();
+        self.hello_sent = None;
+        self.accepted = false;
+        self.netcode_reason = None;
+        Ok(())
+    }
+
+    fn open_channels(&mut self) -> (Sender<MultiplayerMessage>, Receiver<MultiplayerMessage>) {
         let (tx_server, rx_app) = mpsc::channel::<MultiplayerMessage>();
         let tx_app = self.sender.to_owned();
         self.sender = tx_server;
         (tx_app, rx_app)
     }
 
+    //Start netcode once the token requested by connect() arrives, false while waiting.
+    fn poll_token(&mut self) -> Result<bool, String> {
+        let Some(token) = &self.token else {
+            return Ok(true);
+        };
+        let result = match token.lock().map(|receiver| receiver.try_recv()) {
+            Ok(Err(TryRecvError::Empty)) => return Ok(false),
+            Ok(Ok(result)) => result,
+            _ => Err("The token request stopped.".to_string()),
+        };
+        self.token = None;
+        self.start_netcode(result?)?;
+        Ok(true)
+    }
+
+    //The server and the time since the connection attempt started, until netcode disconnects.
+    pub fn connecting_to(&self) -> Option<(SocketAddr, Duration)> {
+        let addr = match &self.client {
+            Some(client) if client.is_disconnected() => return None,
+            Some(client) => client.server_addr(),
+            None => self.server_addr?,
+        };
+        Some((addr, self.connect_started.elapsed()))
+    }
+
+    //Netcode timed out, the server may be back soon.
+    fn connection_lost(&self) -> bool {
+        self.netcode_reason.is_some_and(is_timeout)
+    }
+
     pub fn server_transact(&mut self) -> Result<(), Error> {
         //Runs in the bevy app loop while the state is connected.
         //Checks the tcp socket for messages from the server for processing by the bevy app.
@@ -257,12 +329,13 @@ impl RenetClient {
         let r_client = self.client.as_mut().expect("RenetClient not initialized!");
         if let Some(err) = r_client.disconnect_reason() {
             //Send a message to bevy to : Command.remove_resource<RenetClient>()
-            println!("Client error: {:?}", err);
+            error!("Client error: {:?}", err);
+            self.netcode_reason = Some(err);
             let msg = MultiplayerMessage::Disconnect {
                 client_id: r_client.client_id(),
             };
             if let Err(e) = self.sender.send(msg) {
-                println!("Could not send disconnect message to bevy. {}", e);
+                error!("Could not send disconnect message to bevy. {}", e);
             }
         }
         let r_socket = self
@@ -272,20 +345,25 @@ impl RenetClient {
 
         //Send data from this client to all other clients via the server.
         if r_client.is_connected() {
+            let hello_due = !matches!(self.hello_sent, Some(sent) if sent.elapsed() < HELLO_RETRY);
+            if !self.accepted && hello_due {
+                //The server adds the player once the protocol version is accepted.
+                let (addr, payload) = r_client
+                    .generate_payload_packet(&MultiplayerMessage::Hello {}.get_buf()?)
+                    .map_err(|e| Error::other(e.to_string()))?;
+                r_socket.send_to(payload, addr)?;
+                self.hello_sent = Some(Instant::now());
+            }
             let mut _rx = self.receiver.lock().unwrap();
             for message in _rx.try_iter() {
                 //let (addr, payload) = r_client.generate_payload_packet(&message.get_buf()?).unwrap();
                 let (addr, payload) = r_client
                     .generate_payload_packet(&message.get_buf()?)
-                    .unwrap();
-                self.udp_socket
-                    .as_ref()
-                    .unwrap()
-                    .send_to(payload, addr)
-                    .unwrap();
+                    .map_err(|e| Error::other(e.to_string()))?;
+                r_socket.send_to(payload, addr)?;
             }
         } else {
-            println!("Client is not yet connected");
+            debug!("Client is not yet connected");
         }
 
         loop {
@@ -310,27 +388,39 @@ impl RenetClient {
                         match server_message {
                             Ok(msg) => {
                                 let msg_id = msg.get_id();
-                                println!("Received msg type {} from server.", msg_id);
+                                trace!("Received msg type {} from server.", msg_id);
+                                //Refused players are told why before the disconnect.
+                                if !matches!(
+                                    msg,
+                                    MultiplayerMessage::Kicked { .. }
+                                        | MultiplayerMessage::VersionMismatch { .. }
+                                ) {
+                                    self.accepted = true;
+                                }
                                 if let Err(e) = self.sender.send(msg) {
-                                    eprintln!(
+                                    warn!(
                                         "Received a faulty  MultiplayerMessage from the server? {}",
                                         e
                                     );
                                 }
                             }
                             Err(e) => {
-                                eprintln!("Error receiving server message: {}", e);
+                                warn!("Error receiving server message: {}", e);
                             }
                         }
                     }
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                Err(e) => panic!("Socket error: {}", e),
+                //An unreachable server, netcode times out.
+                Err(e) => {
+                    debug!("Socket error: {}", e);
+                    break;
+                }
             };
         }
 
         if let Some((packet, addr)) = r_client.update(Instant::now() - self.last_updated) {
-            r_socket.send_to(packet, addr).unwrap();
+            r_socket.send_to(packet, addr)?;
         }
         self.last_updated = Instant::now();
         Ok(())
@@ -345,10 +435,12 @@ impl RenetClient {
             .expect("RenetClient not initialized!");
         match client.disconnect() {
             Ok((addr, packet)) => {
-                r_socket.send_to(packet, addr).unwrap();
+                if let Err(e) = r_socket.send_to(packet, addr) {
+                    warn!("Could not send the disconnect. {}", e);
+                }
             }
             Err(e) => {
-                println!("Error during disconnect. {}", e);
+                error!("Error during disconnect. {}", e);
             }
         };
     }
@@ -367,17 +459,57 @@ impl RenetClient {
 }
 
 pub fn do_multiplayer_disconnect(mut r_client: ResMut<RenetClient>) {
-    println!("doing disconnect...");
+    debug!("doing disconnect...");
     r_client.disconnect();
 }
 
+//Insert the resources of a new connection, the caller sets the Connecting state.
+pub fn insert_connection(
+    commands: &mut Commands,
+    r_client: RenetClient,
+    (sender, receiver): (Sender<MultiplayerMessage>, Receiver<MultiplayerMessage>),
+) {
+    commands.insert_resource(r_client);
+    commands.insert_resource(MultiplayerMessageSender { sender });
+    commands.insert_resource(MultiplayerMessageReceiver {
+        receiver: Mutex::new(receiver),
+    });
+}
+
+pub fn remove_connection(commands: &mut Commands) {
+    commands.remove_resource::<MultiplayerMessageSender>();
+    commands.remove_resource::<MultiplayerMessageReceiver>();
+    commands.remove_resource::<RenetClient>();
+}
+
+//Abandon a connection attempt that has not connected, the server is told so it does not keep
+//the client until it times out.
+pub fn cancel_connecting(
+    r_client: &mut RenetClient,
+    commands: &mut Commands,
+    next_multiplayer: &mut NextState<MultiplayerState>,
+) {
+    info!("Connection attempt cancelled.");
+    //A token request in progress is abandoned, its reply goes nowhere.
+    r_client.token = None;
+    if !r_client.is_disconnected() {
+        r_client.disconnect();
+    }
+    remove_connection(commands);
+    next_multiplayer.set(MultiplayerState::Disconnected);
+}
+
 //Also called from mod player::update_world_from_server_messages on a server disconnect.
+#[allow(clippy::too_many_arguments)]
 pub fn do_finish_disconnect(
     r_client: ResMut<RenetClient>,
     mut commands: Commands,
     mut players: Query<(Entity, &ClientId, Option<&FirstPerson>)>,
     mut next_multiplayer: ResMut<NextState<MultiplayerState>>,
     multiplayer_state: Res<State<MultiplayerState>>,
+    mut reconnect: ResMut<Reconnect>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    app_params: Res<AppParams>,
 ) {
     if multiplayer_state.get().eq(&MultiplayerState::Disconnecting) {
         match r_client.client.as_ref() {
@@ -386,7 +518,7 @@ pub fn do_finish_disconnect(
                     players
                         .iter_mut()
                         .for_each(|(entity, _client_id, first_person)| {
-                            println!("removing entity: {} for disconnect.", entity);
+                            debug!("removing entity: {} for disconnect.", entity);
                             match first_person {
                                 Some(_) => {
                                     commands.entity(entity).remove::<ClientId>();
@@ -397,16 +529,28 @@ pub fn do_finish_disconnect(
                             }
                         });
 
-                    commands.remove_resource::<MultiplayerMessageSender>();
-                    commands.remove_resource::<MultiplayerMessageReceiver>();
-                    commands.remove_resource::<RenetClient>();
-                    println!("disconnected.");
+                    remove_connection(&mut commands);
+                    info!("disconnected.");
+                    let was_reconnecting = reconnect.is_reconnecting();
+                    if r_client.connection_lost()
+                        && reconnect.connection_lost(Instant::now(), reconnect_window(&app_params))
+                    {
+                        info!("Connection lost, reconnecting.");
+                    } else if r_client.connection_lost() || was_reconnecting {
+                        reconnect.stop();
+                        disconnect_reason.0 = Some(match r_client.netcode_reason {
+                            Some(reason) => {
+                                format!("Connection lost. {}", disconnect_message(reason))
+                            }
+                            None => "Connection lost.".to_string(),
+                        });
+                    } else {
+                        reconnect.stop();
+                    }
                 }
             }
             None => {
-                println!(
-                    "do_finish_disconnect: Client is not disconnected in disconnecting state??"
-                );
+                warn!("do_finish_disconnect: Client is not disconnected in disconnecting state??");
             }
         }
     } else if multiplayer_state
@@ -422,12 +566,152 @@ pub fn do_finish_disconnect(
 
 pub fn do_multiplayer_server(mut r_client: ResMut<RenetClient>) {
     if let Err(e) = r_client.server_transact() {
-        println!("Error transacting with server. {}", e);
+        error!("Error transacting with server. {}", e);
     }
 }
 
-pub fn set_connected(mut next_multiplayer: ResMut<NextState<MultiplayerState>>) {
-    next_multiplayer.set(MultiplayerState::Connected);
+//Drive the netcode handshake and the Hello until the server accepts the player, a failure
+//returns to Disconnected with the reason for the server window.
+pub fn do_connecting(
+    mut r_client: ResMut<RenetClient>,
+    mut commands: Commands,
+    mut next_multiplayer: ResMut<NextState<MultiplayerState>>,
+    mut reconnect: ResMut<Reconnect>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    app_params: Res<AppParams>,
+) {
+    let failure = match r_client.poll_token() {
+        Err(e) => Some((false, e)),
+        Ok(false) => None,
+        Ok(true) => {
+            if let Err(e) = r_client.server_transact() {
+                error!("Error transacting with server. {}", e);
+            }
+            if r_client.accepted {
+                if reconnect.is_reconnecting() {
+                    info!("Reconnected.");
+                    reconnect.stop();
+                }
+                next_multiplayer.set(MultiplayerState::Connected);
+                return;
+            }
+            r_client
+                .netcode_reason
+                .map(|reason| (is_timeout(reason), disconnect_message(reason).to_string()))
+        }
+    };
+    //The token request counts against the timeout.
+    let (lost, reason) = match failure {
+        Some(failure) => failure,
+        None if r_client.connect_started.elapsed() >= CONNECT_TIMEOUT => {
+            if r_client.client.as_ref().is_some_and(|c| c.is_connected()) {
+                r_client.disconnect();
+                (
+                    true,
+                    "The server did not accept the player in time.".to_string(),
+                )
+            } else if r_client.token.is_some() {
+                (
+                    false,
+                    "The token service did not answer in time.".to_string(),
+                )
+            } else {
+                (true, "The server did not answer in time.".to_string())
+            }
+        }
+        None => return,
+    };
+    warn!("Connection failed. {}", reason);
+    remove_connection(&mut commands);
+    next_multiplayer.set(MultiplayerState::Disconnected);
+    if lost
+        && reconnect.is_reconnecting()
+        && reconnect.connection_lost(Instant::now(), reconnect_window(&app_params))
+    {
+        return;
+    }
+    let was_reconnecting = reconnect.is_reconnecting();
+    reconnect.stop();
+    //A Kicked or VersionMismatch message gave the reason already.
+    if disconnect_reason.0.is_none() {
+        disconnect_reason.0 = Some(if was_reconnecting {
+            format!("Connection lost, could not reconnect. {}", reason)
+        } else {
+            reason
+        });
+    }
+}
+
+//Request a token and connect again while reconnecting, without blocking the frame.
+pub fn do_reconnect(
+    mut reconnect: ResMut<Reconnect>,
+    mut commands: Commands,
+    mut next_multiplayer: ResMut<NextState<MultiplayerState>>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    app_params: Res<AppParams>,
+) {
+    let Some((user_name, url)) = reconnect.server.clone() else {
+        reconnect.stop();
+        return;
+    };
+    let now = Instant::now();
+    let result = match &reconnect.token {
+        Some(token) => match token.lock().map(|receiver| receiver.try_recv()) {
+            Ok(Err(TryRecvError::Empty)) => return,
+            Ok(Ok(result)) => result,
+            _ => Err("The token request stopped.".to_string()),
+        },
+        None => {
+            if reconnect.next_attempt.is_some_and(|next| now < next) {
+                return;
+            }
+            let server_addr: SocketAddr = match url.parse() {
+                Ok(addr) => addr,
+                Err(_) => {
+                    reconnect.stop();
+                    return;
+                }
+            };
+            reconnect.attempt += 1;
+            info!(
+                "Reconnecting to {} ({}/{}).",
+                url, reconnect.attempt, RECONNECT_ATTEMPTS
+            );
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = sender.send(request_connect_token(server_addr, &user_name));
+            });
+            reconnect.token = Some(Mutex::new(receiver));
+            return;
+        }
+    };
+    match result {
+        Ok(connect_token) => {
+            reconnect.token = None;
+            let mut r_client = RenetClient::new();
+            match r_client.connect_with_token(connect_token) {
+                Ok(channels) => {
+                    insert_connection(&mut commands, r_client, channels);
+                    next_multiplayer.set(MultiplayerState::Connecting);
+                }
+                Err(e) => {
+                    reconnect.stop();
+                    disconnect_reason.0 =
+                        Some(format!("Connection lost, could not reconnect. {}", e));
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Reconnect failed. {}", e);
+            if !reconnect.connection_lost(now, reconnect_window(&app_params)) {
+                disconnect_reason.0 = Some(format!("Connection lost, could not reconnect. {}", e));
+            }
+        }
+    }
+}
+
+pub fn is_reconnecting(reconnect: Res<Reconnect>) -> bool {
+    reconnect.is_reconnecting()
 }
 
 pub fn set_disconnected(mut next_multiplayer: ResMut<NextState<MultiplayerState>>) {
@@ -437,6 +721,19 @@ pub fn set_disconnected(mut next_multiplayer: ResMut<NextState<MultiplayerState>
 pub struct ClientPlugin;
 impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
+        app.add_event::<PingEvent>();
+        app.init_resource::<DisconnectReason>();
+        app.init_resource::<Reconnect>();
+        app.add_systems(
+            Update,
+            do_reconnect.run_if(in_state(MultiplayerState::Disconnected).and(is_reconnecting)),
+        );
+        app.add_systems(
+            Update,
+            answer_pings
+                .after(crate::players::update_world_from_server_messages)
+                .run_if(in_state(MultiplayerState::Connected)),
+        );
         app.add_systems(
             Update,
             do_multiplayer_server
@@ -454,7 +751,12 @@ impl Plugin for ClientPlugin {
                     .and(resource_exists::<RenetClient>),
             ),
         );
-        app.add_systems(OnEnter(MultiplayerState::Connecting), set_connected);
+        app.add_systems(
+            Update,
+            do_connecting
+                .before(crate::players::update_world_from_server_messages)
+                .run_if(in_state(MultiplayerState::Connecting).and(resource_exists::<RenetClient>)),
+        );
 
         app.add_systems(
             Update,
@@ -468,10 +770,13 @@ impl Plugin for ClientPlugin {
 #[cfg(test)]
 mod test {
 
-    use crate::client::MultiplayerMessage;
+    use crate::players::Movement;
+    use crate::protocol::MultiplayerMessage;
+    use crate::server::game_mode::Team;
     use crate::server::*;
+    use crate::token::request_connect_token;
     use bevy::prelude::*;
-    use renetcode::{ClientAuthentication, ConnectToken, NetcodeClient, NETCODE_MAX_PACKET_BYTES};
+    use renetcode::{ClientAuthentication, NetcodeClient, NETCODE_MAX_PACKET_BYTES};
     use std::{
         net::{SocketAddr, UdpSocket},
         time::Instant,
@@ -487,8 +792,9 @@ mod test {
         let mess = MultiplayerMessage::Connect {
             client_id: 77u64,
             location: Vec3::new(1.1f32, 2.2f32, 3.3f32),
-            direction: Vec3::new(4.4f32, 5.5f32, 6.6f32),
+            rotation: Vec2::new(4.4f32, 5.5f32),
             name: "ikky".to_string(),
+            team: Some(Team::Red),
         };
         let buf = mess.get_buf().unwrap();
         println!("buf:{:?}", buf);
@@ -497,13 +803,15 @@ mod test {
             MultiplayerMessage::Connect {
                 client_id,
                 location,
-                direction,
+                rotation,
                 name,
+                team,
             } => {
                 assert_eq!(client_id, 77);
                 assert_eq!(location, Vec3::new(1.1f32, 2.2f32, 3.3f32));
-                assert_eq!(direction, Vec3::new(4.4f32, 5.5f32, 6.6f32));
+                assert_eq!(rotation, Vec2::new(4.4f32, 5.5f32));
                 assert_eq!(name, "ikky".to_string());
+                assert_eq!(team, Some(Team::Red));
             }
             _ => panic!("test_multiplayermessage_connect fail!"),
         };
@@ -526,16 +834,25 @@ mod test {
     fn test_multiplayermessage_move() {
         let mess = MultiplayerMessage::Move {
             client_id: 78u64,
+            tick: 9,
+            sequence: 3,
             location: Vec3::new(1., 2., 3.),
+            rotation: Vec2::new(0.1, 0.2),
         };
         let buf = mess.get_buf().unwrap();
         let move_msg: MultiplayerMessage = MultiplayerMessage::get(&buf).unwrap().into();
         match move_msg {
             MultiplayerMessage::Move {
                 client_id,
+                tick,
+                sequence,
                 location,
+                rotation,
             } => {
                 assert_eq!(client_id, 78);
+                assert_eq!(rotation, Vec2::new(0.1, 0.2));
+                assert_eq!(tick, 9);
+                assert_eq!(sequence, 3);
                 assert_eq!(location.x, 1.);
                 assert_eq!(location.y, 2.);
                 assert_eq!(location.z, 3.);
@@ -544,26 +861,76 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_multiplayermessage_input() {
+        let mess = MultiplayerMessage::Input {
+            client_id: 79u64,
+            sequence: 12,
+            movement: Movement::Left,
+            rotation: Vec2::new(0.5, -0.25),
+        };
+        let buf = mess.get_buf().unwrap();
+        let input_msg: MultiplayerMessage = MultiplayerMessage::get(&buf).unwrap();
+        match input_msg {
+            MultiplayerMessage::Input {
+                client_id,
+                sequence,
+                movement,
+                rotation,
+            } => {
+                assert_eq!(client_id, 79);
+                assert_eq!(sequence, 12);
+                assert_eq!(movement, Movement::Left);
+                assert_eq!(rotation, Vec2::new(0.5, -0.25));
+            }
+            _ => panic!("test_multiplayermessage_input fail!"),
+        }
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        use super::{Reconnect, RECONNECT_ATTEMPTS};
+        let mut reconnect = Reconnect::default();
+        let now = Instant::now();
+        let window = Duration::from_secs(60);
+        //No server to reconnect to.
+        assert!(!reconnect.connection_lost(now, window));
+        reconnect.remember("shrubbo", "127.0.0.1:5000");
+        assert!(reconnect.connection_lost(now, window));
+        assert!(reconnect.is_reconnecting());
+        assert_eq!(reconnect.next_attempt, Some(now + Duration::from_secs(1)));
+        for attempt in 1..RECONNECT_ATTEMPTS {
+            reconnect.attempt = attempt;
+            assert!(reconnect.connection_lost(now, window));
+            assert_eq!(
+                reconnect.next_attempt,
+                Some(now + Duration::from_secs(1 << attempt))
+            );
+        }
+        reconnect.attempt = RECONNECT_ATTEMPTS;
+        assert!(!reconnect.connection_lost(now, window));
+        assert!(!reconnect.is_reconnecting());
+        //The window ends the attempts early.
+        reconnect.remember("shrubbo", "127.0.0.1:5000");
+        assert!(reconnect.connection_lost(now, Duration::from_secs(3)));
+        reconnect.attempt = 2;
+        assert!(!reconnect.connection_lost(now, Duration::from_secs(3)));
+        assert!(!reconnect.connection_lost(now, Duration::ZERO));
+        //A cancelled reconnect does not start again when the attempt in flight times out.
+        assert!(reconnect.connection_lost(now, window));
+        reconnect.cancel();
+        assert!(!reconnect.is_reconnecting());
+        assert!(!reconnect.connection_lost(now, window));
+    }
+
     fn client_main(user_name: String) {
         let server_addr: SocketAddr = format!("127.0.0.1:{}", PORT).parse().unwrap();
-        let username = Username(user_name);
         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
         println!(
             "Stating connecting at {:?} with username {}",
-            now, username.0,
+            now, user_name,
         );
-        let client_id = now.as_millis() as u64;
-        let connect_token = ConnectToken::generate(
-            now,
-            PROTOCOL_ID,
-            300,
-            client_id,
-            15,
-            vec![server_addr],
-            Some(&username.to_netcode_user_data()),
-            PRIVATE_KEY,
-        )
-        .unwrap();
+        let connect_token = request_connect_token(server_addr, &user_name).unwrap();
         let auth = ClientAuthentication::Secure { connect_token };
         client(auth);
     }
@@ -638,4 +1005,41 @@ mod test {
         //needs the server running.
         client_main("shrubbo".to_string());
     }
+
+    #[test]
+    fn test_connect_failures() {
+        use super::{disconnect_message, is_timeout, RenetClient};
+        use renetcode::DisconnectReason;
+        let mut r_client = RenetClient::new();
+        assert_eq!(
+            r_client.connect("ikky", "shooter.example").err(),
+            Some("shooter.example is not an address with a port.".to_string())
+        );
+        assert!(r_client.connecting_to().is_none());
+        //The token is requested on a thread, no token service answers on port 2.
+        let server_addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(r_client.connect("ikky", "127.0.0.1:1").is_ok());
+        assert_eq!(
+            r_client.connecting_to().map(|(addr, _)| addr),
+            Some(server_addr)
+        );
+        let started = Instant::now();
+        let result = loop {
+            match r_client.poll_token() {
+                Ok(false) if started.elapsed() < Duration::from_secs(10) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                result => break result,
+            }
+        };
+        assert!(result.is_err());
+        assert_eq!(
+            disconnect_message(DisconnectReason::ConnectionDenied),
+            "The server is full."
+        );
+        assert!(is_timeout(DisconnectReason::ConnectionRequestTimedOut));
+        assert!(is_timeout(DisconnectReason::ConnectionTimedOut));
+        assert!(!is_timeout(DisconnectReason::ConnectionDenied));
+        assert!(!is_timeout(DisconnectReason::DisconnectedByServer));
+    }
 }
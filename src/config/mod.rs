@@ -1,3 +1,4 @@
+use bevy::log::debug;
 use dirs::{config_local_dir, home_dir};
 use std::ffi::OsString;
 use std::fs::DirBuilder;
@@ -10,9 +11,11 @@ use std::path::Path;
 use std::path::PathBuf;
 
 use crate::input_n_state::AppParams;
+use crate::server::settings::ServerSettings;
 
 const SHOOTER_DIR: &str = "shooter";
 const SHOOTER_CONFIG: &str = "config.toml";
+const SERVER_CONFIG: &str = "server.toml";
 
 pub fn do_read_config(config_path: Option<String>) -> Result<AppParams, String> {
     let mut update_param_alternate_path = false;
@@ -53,6 +56,38 @@ pub fn do_write_config(app_params: &AppParams) -> Result<(), String> {
     }
 }
 
+//The dedicated server settings from server.toml in the shooter config directory or from
+//config_path. Without a server.toml the defaults are used.
+pub fn do_read_server_config(config_path: Option<String>) -> Result<ServerSettings, String> {
+    let os_path: OsString = match &config_path {
+        Some(path) => path.into(),
+        None => get_shooter_file_path(SERVER_CONFIG)?,
+    };
+    match std::fs::read_to_string(&os_path) {
+        Ok(contents) => {
+            let settings: ServerSettings = toml::from_str(&contents).map_err(|e| {
+                format!(
+                    "Invalid server config {}. {}",
+                    Path::new(&os_path).display(),
+                    e
+                )
+            })?;
+            Ok(ServerSettings {
+                config_path: Some(os_path),
+                ..settings
+            })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && config_path.is_none() => {
+            Ok(ServerSettings::default())
+        }
+        Err(e) => Err(format!(
+            "Failed to read server config {}. {}",
+            Path::new(&os_path).display(),
+            e
+        )),
+    }
+}
+
 fn read_config(config_file_path_str: &OsString) -> Result<AppParams, String> {
     let config: File = match get_file(config_file_path_str, true) {
         Ok(file) => file,
@@ -66,7 +101,7 @@ fn read_config(config_file_path_str: &OsString) -> Result<AppParams, String> {
         Ok(_) => (),
         Err(e) => return Err(format!("Could not read file. {}", e)),
     };
-    println!("file contents: {}", contents);
+    debug!("file contents: {}", contents);
 
     match toml::from_str(&contents) {
         Ok(params) => Ok(params),
@@ -84,7 +119,7 @@ fn write_config(config_file_path_str: &OsString, app_params: &AppParams) -> Resu
     let mut writer = BufWriter::new(config);
     match writer.write(&toml.into_bytes()) {
         Err(e) => return Err(format!("Error writing. {}", e)),
-        Ok(size) => println!("Wrote {} bytes to config.", size),
+        Ok(size) => debug!("Wrote {} bytes to config.", size),
     }
     match writer.flush() {
         Ok(_) => (),
@@ -98,6 +133,11 @@ fn write_config(config_file_path_str: &OsString, app_params: &AppParams) -> Resu
 }
 
 pub fn get_config_file_path() -> Result<OsString, String> {
+    get_shooter_file_path(SHOOTER_CONFIG)
+}
+
+//Path of a file in the shooter config directory.
+pub fn get_shooter_file_path(file_name: &str) -> Result<OsString, String> {
     let config_dir = match config_local_dir() {
         Some(config) => config,
         None => match home_dir() {
@@ -105,7 +145,7 @@ pub fn get_config_file_path() -> Result<OsString, String> {
             None => return Err("No config dir for this system?".to_string()),
         },
     };
-    let file_path: PathBuf = [SHOOTER_DIR, SHOOTER_CONFIG].iter().collect();
+    let file_path: PathBuf = [SHOOTER_DIR, file_name].iter().collect();
     let config_file_buf: PathBuf = config_dir.join(file_path);
     Ok(config_file_buf.into_os_string())
 }
@@ -203,6 +243,11 @@ fn test_get_file() {
         last_server_index: 0,
         config_file: None,
         changed: false,
+        interpolation_delay_ms: 100,
+        extrapolation_limit_ms: 250,
+        log: crate::logging::LogSettings::default(),
+        master_servers: vec!["192.0.2.1:5100".to_string()],
+        reconnect_secs: 60,
     };
     match write_config(&tmp_dir_str, &app_params) {
         Ok(_) => {}
@@ -214,4 +259,5 @@ fn test_get_file() {
     };
 
     assert!(params.player_name == app_params.player_name);
+    assert_eq!(params.master_servers, app_params.master_servers);
 }
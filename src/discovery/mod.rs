@@ -0,0 +1,588 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, Cursor, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{WireFormat, PROTOCOL_VERSION};
+use crate::server::game_mode::Match;
+use crate::server::ServerInfo;
+use crate::ClientId;
+
+//Servers answer discovery queries on this port (udp), next to the game and token ports.
+pub const DISCOVERY_PORT: u16 = 5002;
+//Replies to a scan are collected for this long.
+const SCAN_DURATION: Duration = Duration::from_secs(1);
+//The saved servers are queried this often while the server window is open.
+const STATUS_REFRESH: Duration = Duration::from_secs(5);
+//A server is offline when a status query is not answered in time.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+//The discovery packets are not netcode packets, anyone may ask. The game socket of the server
+//answers the same query for the status of a saved server. They start with a magic:
+//  query: [QUERY_MAGIC][nonce u64]
+//  reply: [REPLY_MAGIC][nonce u64][protocol version u16][DiscoveryReply]
+//The version is read before the rest so a server of another version is still listed.
+const QUERY_MAGIC: &[u8; 8] = b"SHOOTER?";
+const REPLY_MAGIC: &[u8; 8] = b"SHOOTER!";
+const QUERY_BYTES: usize = 16;
+const MAX_REPLY_BYTES: usize = 512;
+
+//A server as described by its discovery reply.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiscoveryReply {
+    pub version: u16,
+    //The game port, the reply comes from the discovery port.
+    pub port: u16,
+    pub name: String,
+    pub map: String,
+    pub players: u16,
+    pub max_players: u16,
+}
+
+pub fn query_packet(nonce: u64) -> Vec<u8> {
+    let mut buf = QUERY_MAGIC.to_vec();
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    buf
+}
+
+//The nonce of a query, None when the packet is not a query. Netcode packets are never this short.
+pub fn read_query(packet: &[u8]) -> Option<u64> {
+    if packet.len() != QUERY_BYTES || !packet.starts_with(QUERY_MAGIC) {
+        return None;
+    }
+    Some(u64::from_le_bytes(packet[8..].try_into().ok()?))
+}
+
+impl DiscoveryReply {
+    //The reply of a running server.
+    pub fn new(
+        info: &ServerInfo,
+        game: &Match,
+        port: u16,
+        players: usize,
+        max_players: u16,
+    ) -> Self {
+        DiscoveryReply {
+            version: PROTOCOL_VERSION,
+            port,
+            name: info.name.clone(),
+            map: game.map().to_string(),
+            players: players as u16,
+            max_players,
+        }
+    }
+
+    pub fn packet(&self, nonce: u64) -> Result<Vec<u8>, Error> {
+        let mut buf = REPLY_MAGIC.to_vec();
+        nonce.write_to(&mut buf)?;
+        self.write_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    //The nonce and the reply. A server of another version only has its version filled in.
+    pub fn read(packet: &[u8]) -> Result<(u64, DiscoveryReply), Error> {
+        if !packet.starts_with(REPLY_MAGIC) {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a discovery reply"));
+        }
+        let cursor = &mut Cursor::new(&packet[REPLY_MAGIC.len()..]);
+        let nonce = u64::read_from(cursor)?;
+        let start = cursor.position();
+        let version = u16::read_from(cursor)?;
+        if version != PROTOCOL_VERSION {
+            return Ok((
+                nonce,
+                DiscoveryReply {
+                    version,
+                    port: 0,
+                    name: String::new(),
+                    map: String::new(),
+                    players: 0,
+                    max_players: 0,
+                },
+            ));
+        }
+        cursor.set_position(start);
+        let reply = DiscoveryReply::read_from(cursor)?;
+        if cursor.position() as usize != cursor.get_ref().len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Trailing bytes after the discovery reply",
+            ));
+        }
+        Ok((nonce, reply))
+    }
+}
+
+impl WireFormat for DiscoveryReply {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        self.version.write_to(buf)?;
+        self.port.write_to(buf)?;
+        self.name.write_to(buf)?;
+        self.map.write_to(buf)?;
+        self.players.write_to(buf)?;
+        self.max_players.write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        Ok(DiscoveryReply {
+            version: u16::read_from(cursor)?,
+            port: u16::read_from(cursor)?,
+            name: String::read_from(cursor)?,
+            map: String::read_from(cursor)?,
+            players: u16::read_from(cursor)?,
+            max_players: u16::read_from(cursor)?,
+        })
+    }
+}
+
+//The server side, answering the queries on DISCOVERY_PORT.
+#[derive(Resource)]
+pub struct DiscoveryResponder {
+    socket: UdpSocket,
+    port: u16,
+    max_players: u16,
+}
+
+impl DiscoveryResponder {
+    //Broadcast queries only reach a server bound to an unspecified address.
+    pub fn bind(ip: IpAddr, port: u16, max_players: usize) -> io::Result<DiscoveryResponder> {
+        let socket = UdpSocket::bind(SocketAddr::new(ip, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(DiscoveryResponder {
+            socket,
+            port,
+            max_players: max_players as u16,
+        })
+    }
+}
+
+pub fn answer_discovery(
+    responder: Res<DiscoveryResponder>,
+    info: Res<ServerInfo>,
+    game: Res<Match>,
+    players: Query<&ClientId>,
+) {
+    let mut buf = [0u8; QUERY_BYTES + 1];
+    loop {
+        let (len, addr) = match responder.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => {
+                warn!("Discovery socket error. {}", e);
+                break;
+            }
+        };
+        let Some(nonce) = read_query(&buf[..len]) else {
+            debug!("Ignored a discovery packet from {}.", addr);
+            continue;
+        };
+        let reply = DiscoveryReply::new(
+            &info,
+            &game,
+            responder.port,
+            players.iter().count(),
+            responder.max_players,
+        );
+        match reply.packet(nonce) {
+            Ok(packet) => {
+                if let Err(e) = responder.socket.send_to(&packet, addr) {
+                    debug!("Discovery reply to {} failed. {}", addr, e);
+                }
+            }
+            Err(e) => warn!("Could not encode the discovery reply. {}", e),
+        }
+    }
+}
+
+//A server found by a LAN scan or listed by a master server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FoundServer {
+    //The game server address.
+    pub addr: SocketAddr,
+    pub reply: DiscoveryReply,
+}
+
+impl WireFormat for FoundServer {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        self.addr.to_string().write_to(buf)?;
+        self.reply.write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let addr = String::read_from(cursor)?;
+        Ok(FoundServer {
+            addr: addr.parse().map_err(|_| {
+                Error::new(ErrorKind::InvalidData, format!("Invalid address {}", addr))
+            })?,
+            reply: DiscoveryReply::read_from(cursor)?,
+        })
+    }
+}
+
+//The client side, a broadcast query and the replies to it.
+#[derive(Resource, Default)]
+pub struct LanScan {
+    socket: Option<UdpSocket>,
+    nonce: u64,
+    started: Option<Instant>,
+    pub servers: Vec<FoundServer>,
+}
+
+impl LanScan {
+    //Broadcast a query, and ask the local host for servers bound to the loopback address.
+    pub fn start(&mut self) -> Result<(), String> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| {
+                socket.set_broadcast(true)?;
+                socket.set_nonblocking(true)?;
+                Ok(socket)
+            })
+            .map_err(|e| format!("Could not open the scan socket. {}", e))?;
+        //Replies to an earlier scan are ignored.
+        self.nonce = u64::from_le_bytes(renetcode::generate_random_bytes());
+        let query = query_packet(self.nonce);
+        let sent = [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST]
+            .iter()
+            .filter(|ip| match socket.send_to(&query, (**ip, DISCOVERY_PORT)) {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!("Scan query to {} failed. {}", ip, e);
+                    false
+                }
+            })
+            .count();
+        if sent == 0 {
+            return Err("Could not send the scan query.".to_string());
+        }
+        self.servers.clear();
+        self.socket = Some(socket);
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn is_scanning(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    //Collect the replies, the scan ends after SCAN_DURATION.
+    pub fn poll(&mut self) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        let mut buf = [0u8; MAX_REPLY_BYTES];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) => match DiscoveryReply::read(&buf[..len]) {
+                    Ok((nonce, reply)) if nonce == self.nonce => {
+                        let addr = SocketAddr::new(from.ip(), reply.port);
+                        //A server on all addresses of this host answers both queries, the
+                        //LAN address is kept.
+                        let duplicate = self.servers.iter_mut().find(|server| {
+                            server.addr == addr
+                                || server.reply == reply
+                                    && (server.addr.ip().is_loopback() || addr.ip().is_loopback())
+                        });
+                        match duplicate {
+                            Some(server) if server.addr.ip().is_loopback() => server.addr = addr,
+                            Some(_) => {}
+                            None => {
+                                info!("Found {} on {}.", reply.name, addr);
+                                self.servers.push(FoundServer { addr, reply });
+                            }
+                        }
+                    }
+                    Ok(_) => debug!("Discovery reply from {} to another scan.", from),
+                    Err(e) => debug!("Invalid discovery reply from {}. {}", from, e),
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Scan socket error. {}", e);
+                    break;
+                }
+            }
+        }
+        if self
+            .started
+            .is_some_and(|started| started.elapsed() >= SCAN_DURATION)
+        {
+            self.socket = None;
+            self.started = None;
+        }
+    }
+}
+
+//What the server browser knows of a saved server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerStatus {
+    //Not answered yet.
+    Querying,
+    Online { reply: DiscoveryReply, ping_ms: u32 },
+    Offline,
+    //The url is not an address with a port.
+    InvalidAddress,
+}
+
+//The client side of the status queries to the saved servers. A thread sends the queries and
+//times the replies so the ping does not depend on the menu frame rate.
+#[derive(Resource)]
+pub struct StatusPoller {
+    urls: Sender<Vec<String>>,
+    results: Mutex<Receiver<(String, ServerStatus)>>,
+    last_refresh: Option<Instant>,
+    statuses: HashMap<String, ServerStatus>,
+}
+
+impl StatusPoller {
+    pub fn spawn() -> StatusPoller {
+        let (urls, requests) = mpsc::channel::<Vec<String>>();
+        let (sender, results) = mpsc::channel();
+        thread::spawn(move || {
+            let mut sockets = vec![];
+            for ip in [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ] {
+                match UdpSocket::bind((ip, 0)).and_then(|socket| {
+                    socket.set_nonblocking(true)?;
+                    Ok(socket)
+                }) {
+                    Ok(socket) => sockets.push(socket),
+                    Err(e) => warn!("Could not open a status socket on {}. {}", ip, e),
+                }
+            }
+            let mut nonce = u64::from_le_bytes(renetcode::generate_random_bytes());
+            for urls in requests {
+                if !query_statuses(&sockets, &mut nonce, urls, &sender) {
+                    break;
+                }
+            }
+        });
+        StatusPoller {
+            urls,
+            results: Mutex::new(results),
+            last_refresh: None,
+            statuses: HashMap::new(),
+        }
+    }
+
+    pub fn status(&self, url: &str) -> &ServerStatus {
+        self.statuses.get(url).unwrap_or(&ServerStatus::Querying)
+    }
+
+    //Query the servers every STATUS_REFRESH.
+    pub fn refresh<'a>(&mut self, urls: impl Iterator<Item = &'a str>) {
+        if self
+            .last_refresh
+            .is_some_and(|last| last.elapsed() < STATUS_REFRESH)
+        {
+            return;
+        }
+        self.last_refresh = Some(Instant::now());
+        if self.urls.send(urls.map(str::to_string).collect()).is_err() {
+            warn!("The status thread has stopped.");
+        }
+    }
+
+    //Take the statuses found since the last poll, never blocking.
+    pub fn poll(&mut self) {
+        let Ok(results) = self.results.lock() else {
+            return;
+        };
+        for (url, status) in results.try_iter() {
+            self.statuses.insert(url, status);
+        }
+    }
+}
+
+//Query the servers and send each status as it is known. False when the poller is gone.
+fn query_statuses(
+    sockets: &[UdpSocket],
+    next_nonce: &mut u64,
+    urls: Vec<String>,
+    results: &Sender<(String, ServerStatus)>,
+) -> bool {
+    //Unanswered queries by nonce: the url and the time sent.
+    let mut pending = HashMap::new();
+    let mut statuses = vec![];
+    for url in urls {
+        let Ok(addr) = url.parse::<SocketAddr>() else {
+            statuses.push((url, ServerStatus::InvalidAddress));
+            continue;
+        };
+        let Some(socket) = sockets.iter().find(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|local| local.is_ipv6() == addr.is_ipv6())
+        }) else {
+            statuses.push((url, ServerStatus::Offline));
+            continue;
+        };
+        let nonce = *next_nonce;
+        *next_nonce = next_nonce.wrapping_add(1);
+        match socket.send_to(&query_packet(nonce), addr) {
+            Ok(_) => {
+                pending.insert(nonce, (url, Instant::now()));
+            }
+            Err(e) => {
+                debug!("Status query to {} failed. {}", url, e);
+                statuses.push((url, ServerStatus::Offline));
+            }
+        }
+    }
+    let started = Instant::now();
+    let mut buf = [0u8; MAX_REPLY_BYTES];
+    loop {
+        for status in statuses.drain(..) {
+            if results.send(status).is_err() {
+                return false;
+            }
+        }
+        if pending.is_empty() || started.elapsed() >= STATUS_TIMEOUT {
+            break;
+        }
+        let mut received = false;
+        for socket in sockets {
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) => {
+                    received = true;
+                    match DiscoveryReply::read(&buf[..len]) {
+                        Ok((nonce, reply)) => match pending.remove(&nonce) {
+                            Some((url, sent)) => {
+                                let ping_ms = sent.elapsed().as_millis() as u32;
+                                statuses.push((url, ServerStatus::Online { reply, ping_ms }));
+                            }
+                            None => debug!("Late or unknown status reply from {}.", from),
+                        },
+                        Err(e) => debug!("Invalid status reply from {}. {}", from, e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                //Like an ICMP port unreachable from an offline server, the query times out.
+                Err(e) => debug!("Status socket error. {}", e),
+            }
+        }
+        if !received {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    pending
+        .into_values()
+        .all(|(url, _)| results.send((url, ServerStatus::Offline)).is_ok())
+}
+
+#[test]
+fn test_discovery_packets() {
+    let query = query_packet(42);
+    assert_eq!(read_query(&query), Some(42));
+    assert_eq!(read_query(&query[..QUERY_BYTES - 1]), None);
+    assert_eq!(read_query(b"SHOOTER!12345678"), None);
+
+    let reply = DiscoveryReply {
+        version: PROTOCOL_VERSION,
+        port: 5000,
+        name: "Arena é".to_string(),
+        map: "arena".to_string(),
+        players: 3,
+        max_players: 16,
+    };
+    let packet = reply.packet(42).unwrap();
+    assert!(packet.len() <= MAX_REPLY_BYTES);
+    assert_eq!(DiscoveryReply::read(&packet).unwrap(), (42, reply.clone()));
+    assert!(DiscoveryReply::read(&packet[..packet.len() - 1]).is_err());
+    assert!(DiscoveryReply::read(&query).is_err());
+
+    //Only the version of another protocol is read.
+    let mut other = reply.packet(7).unwrap();
+    other[16..18].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    other.truncate(18);
+    let (nonce, other) = DiscoveryReply::read(&other).unwrap();
+    assert_eq!(nonce, 7);
+    assert_eq!(other.version, PROTOCOL_VERSION + 1);
+}
+
+#[test]
+fn test_discovery_exchange() {
+    //A responder socket on an ephemeral port standing in for DISCOVERY_PORT.
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    client
+        .send_to(&query_packet(9), server.local_addr().unwrap())
+        .unwrap();
+    let mut buf = [0u8; QUERY_BYTES + 1];
+    let (len, from) = server.recv_from(&mut buf).unwrap();
+    let nonce = read_query(&buf[..len]).unwrap();
+    let reply = DiscoveryReply {
+        version: PROTOCOL_VERSION,
+        port: 5000,
+        name: "Shooter Server".to_string(),
+        map: "arena".to_string(),
+        players: 0,
+        max_players: 16,
+    };
+    server.send_to(&reply.packet(nonce).unwrap(), from).unwrap();
+
+    client.set_nonblocking(true).unwrap();
+    let mut scan = LanScan {
+        socket: Some(client),
+        nonce: 9,
+        started: Some(Instant::now()),
+        servers: vec![],
+    };
+    let deadline = Instant::now() + SCAN_DURATION;
+    while scan.servers.is_empty() && Instant::now() < deadline {
+        scan.poll();
+    }
+    assert_eq!(
+        scan.servers,
+        vec![FoundServer {
+            addr: "127.0.0.1:5000".parse().unwrap(),
+            reply,
+        }]
+    );
+}
+
+#[test]
+fn test_status_poller() {
+    //A game socket answering one status query.
+    let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let url = server.local_addr().unwrap().to_string();
+    let reply = DiscoveryReply {
+        version: PROTOCOL_VERSION,
+        port: server.local_addr().unwrap().port(),
+        name: "Shooter Server".to_string(),
+        map: "arena".to_string(),
+        players: 2,
+        max_players: 16,
+    };
+    let answer = reply.clone();
+    server.set_read_timeout(Some(STATUS_TIMEOUT)).unwrap();
+    let responder = thread::spawn(move || {
+        let mut buf = [0u8; QUERY_BYTES + 1];
+        let (len, from) = server.recv_from(&mut buf).unwrap();
+        let nonce = read_query(&buf[..len]).unwrap();
+        server
+            .send_to(&answer.packet(nonce).unwrap(), from)
+            .unwrap();
+    });
+
+    let mut poller = StatusPoller::spawn();
+    poller.refresh([url.as_str(), "shooter.example"].into_iter());
+    let deadline = Instant::now() + STATUS_TIMEOUT;
+    while matches!(poller.status(&url), ServerStatus::Querying) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+        poller.poll();
+    }
+    responder.join().unwrap();
+    match poller.status(&url) {
+        ServerStatus::Online { reply: online, .. } => assert_eq!(*online, reply),
+        status => panic!("Unexpected status {:?}", status),
+    }
+    assert_eq!(
+        poller.status("shooter.example"),
+        &ServerStatus::InvalidAddress
+    );
+}
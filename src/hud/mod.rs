@@ -0,0 +1,397 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use crate::input_n_state::MultiplayerState;
+use crate::players::team::PlayerTeam;
+use crate::players::{look_rotation, MouseRotation};
+use crate::protocol::ScoreboardEntry;
+use crate::server::game_mode::{GameMode, Score, Team};
+use crate::server::{MAX_HEALTH, START_ARMOR};
+use crate::{ClientId, FirstPerson};
+
+//Height and radius of the death camera orbit around the death location.
+const DEATH_CAMERA_HEIGHT: f32 = 6.0;
+const DEATH_CAMERA_RADIUS: f32 = 8.0;
+//Radians per second.
+const DEATH_CAMERA_SPEED: f32 = 0.5;
+
+//The local player was hit.
+#[derive(Event)]
+pub struct DamageEvent {
+    pub attacker_id: u64,
+    pub amount: u32,
+    pub health: u32,
+    pub armor: u32,
+}
+
+//The local player was killed at location.
+#[derive(Event)]
+pub struct DiedEvent {
+    pub attacker_id: u64,
+    pub respawn_secs: f32,
+    pub location: Vec3,
+}
+
+#[derive(Event)]
+pub struct RespawnedEvent {
+    pub health: u32,
+    pub armor: u32,
+}
+
+#[derive(Event)]
+pub struct MatchStateEvent {
+    pub mode: GameMode,
+    pub playing: bool,
+    pub remaining_secs: f32,
+    pub frag_limit: u32,
+    pub winner_id: Option<u64>,
+    pub winning_team: Option<Team>,
+}
+
+#[derive(Event)]
+pub struct ScoreEvent {
+    pub client_id: u64,
+    pub frags: i32,
+    pub deaths: u32,
+}
+
+#[derive(Event)]
+pub struct TeamScoreEvent {
+    pub team: Team,
+    pub frags: i32,
+}
+
+#[derive(Event)]
+pub struct ScoreboardEvent(pub Vec<ScoreboardEntry>);
+
+struct Death {
+    killer: String,
+    respawn_time: f64,
+    location: Vec3,
+}
+
+//Health of the local player from the server messages.
+#[derive(Resource)]
+pub struct PlayerStatus {
+    pub health: u32,
+    pub armor: u32,
+    death: Option<Death>,
+}
+
+impl Default for PlayerStatus {
+    fn default() -> PlayerStatus {
+        PlayerStatus {
+            health: MAX_HEALTH,
+            armor: START_ARMOR,
+            death: None,
+        }
+    }
+}
+
+//The match state from the server messages.
+#[derive(Resource)]
+pub struct MatchInfo {
+    pub mode: Option<GameMode>,
+    pub playing: bool,
+    //Time of the end of the match or the intermission.
+    end_time: f64,
+    frag_limit: u32,
+    winner: Option<String>,
+    winning_team: Option<Team>,
+    pub scores: HashMap<u64, Score>,
+    pub team_frags: [i32; 2],
+}
+
+impl Default for MatchInfo {
+    fn default() -> MatchInfo {
+        MatchInfo {
+            mode: None,
+            playing: true,
+            end_time: 0.,
+            frag_limit: 0,
+            winner: None,
+            winning_team: None,
+            scores: HashMap::new(),
+            team_frags: [0; 2],
+        }
+    }
+}
+
+//The players from the last server Scoreboard, by team then frags.
+#[derive(Resource, Default)]
+pub struct Scoreboard {
+    pub entries: Vec<ScoreboardEntry>,
+}
+
+//Run condition for the player input systems, the player is alive and the match is on.
+pub fn player_active(status: Res<PlayerStatus>, info: Res<MatchInfo>) -> bool {
+    status.death.is_none() && info.playing
+}
+
+#[derive(Component)]
+enum HudText {
+    Health,
+    Death,
+    Match,
+}
+
+pub struct HudPlugin;
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageEvent>();
+        app.add_event::<DiedEvent>();
+        app.add_event::<RespawnedEvent>();
+        app.add_event::<MatchStateEvent>();
+        app.add_event::<ScoreEvent>();
+        app.add_event::<TeamScoreEvent>();
+        app.add_event::<ScoreboardEvent>();
+        app.init_resource::<PlayerStatus>();
+        app.init_resource::<MatchInfo>();
+        app.init_resource::<Scoreboard>();
+        app.add_systems(Startup, spawn_hud);
+        app.add_systems(OnEnter(MultiplayerState::Connected), reset_status);
+        app.add_systems(
+            Update,
+            (update_status, update_match_info, death_camera, update_hud)
+                .chain()
+                .after(crate::players::update_world_from_server_messages),
+        );
+    }
+}
+
+fn reset_status(
+    mut status: ResMut<PlayerStatus>,
+    mut info: ResMut<MatchInfo>,
+    mut scoreboard: ResMut<Scoreboard>,
+) {
+    *status = PlayerStatus::default();
+    *info = MatchInfo::default();
+    *scoreboard = Scoreboard::default();
+}
+
+fn update_status(
+    time: Res<Time<Real>>,
+    mut status: ResMut<PlayerStatus>,
+    mut damage: EventReader<DamageEvent>,
+    mut died: EventReader<DiedEvent>,
+    mut respawned: EventReader<RespawnedEvent>,
+    names: Query<(&ClientId, &Name)>,
+    (mut first_person, mouse_rotation): (
+        Query<&mut Transform, With<FirstPerson>>,
+        Res<MouseRotation>,
+    ),
+) {
+    let name_of = |client_id: u64| {
+        names
+            .iter()
+            .find(|(cid, _)| cid.id == client_id)
+            .map_or(client_id.to_string(), |(_, name)| name.to_string())
+    };
+    for event in damage.read() {
+        info!(
+            "Hit by {} for {} damage.",
+            name_of(event.attacker_id),
+            event.amount
+        );
+        status.health = event.health;
+        status.armor = event.armor;
+    }
+    for event in died.read() {
+        status.health = 0;
+        status.death = Some(Death {
+            killer: name_of(event.attacker_id),
+            respawn_time: time.elapsed_secs_f64() + event.respawn_secs as f64,
+            location: event.location,
+        });
+    }
+    for event in respawned.read() {
+        status.health = event.health;
+        status.armor = event.armor;
+        status.death = None;
+        //Back to the first person view, the location is set by the server message.
+        if let Ok(mut transform) = first_person.get_single_mut() {
+            transform.rotation = look_rotation(mouse_rotation.0);
+        }
+    }
+}
+
+fn update_match_info(
+    time: Res<Time<Real>>,
+    mut info: ResMut<MatchInfo>,
+    mut match_state: EventReader<MatchStateEvent>,
+    mut scores: EventReader<ScoreEvent>,
+    mut team_scores: EventReader<TeamScoreEvent>,
+    (mut scoreboard, mut scoreboard_events): (ResMut<Scoreboard>, EventReader<ScoreboardEvent>),
+    names: Query<(&ClientId, &Name)>,
+) {
+    for event in match_state.read() {
+        info.mode = Some(event.mode);
+        info.playing = event.playing;
+        info.end_time = time.elapsed_secs_f64() + event.remaining_secs as f64;
+        info.frag_limit = event.frag_limit;
+        info.winner = event.winner_id.map(|winner_id| {
+            names
+                .iter()
+                .find(|(cid, _)| cid.id == winner_id)
+                .map_or(winner_id.to_string(), |(_, name)| name.to_string())
+        });
+        info.winning_team = event.winning_team;
+    }
+    for event in scores.read() {
+        info.scores.insert(
+            event.client_id,
+            Score {
+                frags: event.frags,
+                deaths: event.deaths,
+            },
+        );
+    }
+    for event in team_scores.read() {
+        info.team_frags[event.team.index()] = event.frags;
+    }
+    for ScoreboardEvent(entries) in scoreboard_events.read() {
+        let mut entries = entries.clone();
+        entries.sort_by(|a, b| {
+            a.team
+                .map(|team| team.index())
+                .cmp(&b.team.map(|team| team.index()))
+                .then(b.frags.cmp(&a.frags))
+                .then(a.deaths.cmp(&b.deaths))
+        });
+        scoreboard.entries = entries;
+    }
+}
+
+//Orbit the first person camera around the death location until the respawn.
+fn death_camera(
+    time: Res<Time<Real>>,
+    status: Res<PlayerStatus>,
+    mut first_person: Query<&mut Transform, With<FirstPerson>>,
+) {
+    let (Some(death), Ok(mut transform)) = (&status.death, first_person.get_single_mut()) else {
+        return;
+    };
+    let angle = time.elapsed_secs() * DEATH_CAMERA_SPEED;
+    transform.translation = death.location
+        + Vec3::new(
+            angle.cos() * DEATH_CAMERA_RADIUS,
+            DEATH_CAMERA_HEIGHT,
+            angle.sin() * DEATH_CAMERA_RADIUS,
+        );
+    transform.look_at(death.location, Vec3::Y);
+}
+
+fn update_hud(
+    time: Res<Time<Real>>,
+    (status, info): (Res<PlayerStatus>, Res<MatchInfo>),
+    multiplayer_state: Res<State<MultiplayerState>>,
+    mut hud_text: Query<(&HudText, &mut Text, &mut Visibility)>,
+    first_person: Query<(&ClientId, Option<&PlayerTeam>), With<FirstPerson>>,
+) {
+    let connected = *multiplayer_state.get() == MultiplayerState::Connected;
+    for (hud_text, mut text, mut visibility) in hud_text.iter_mut() {
+        match (hud_text, &status.death) {
+            (HudText::Match, _) if connected && info.mode.is_some() => {
+                let remaining = (info.end_time - time.elapsed_secs_f64()).max(0.).ceil() as u32;
+                let mode = info.mode.map_or("", |mode| mode.name());
+                let has_teams = info.mode.is_some_and(|mode| mode.has_teams());
+                let own = first_person.get_single().ok();
+                text.0 = if info.playing {
+                    let frags = own
+                        .and_then(|(cid, _)| info.scores.get(&cid.id))
+                        .map_or(0, |score| score.frags);
+                    let limit = if info.frag_limit > 0 {
+                        format!("/{}", info.frag_limit)
+                    } else {
+                        String::new()
+                    };
+                    let score = match own.and_then(|(_, team)| team) {
+                        Some(PlayerTeam(team)) if has_teams => format!(
+                            "{} team  Red {}  Blue {}{}  Frags {}",
+                            team.name(),
+                            info.team_frags[Team::Red.index()],
+                            info.team_frags[Team::Blue.index()],
+                            limit,
+                            frags
+                        ),
+                        _ => format!("Frags {}{}", frags, limit),
+                    };
+                    format!(
+                        "{}  {}:{:02}  {}",
+                        mode,
+                        remaining / 60,
+                        remaining % 60,
+                        score
+                    )
+                } else {
+                    let winner = if has_teams {
+                        info.winning_team.map_or("Draw".to_string(), |team| {
+                            format!("{} team wins", team.name())
+                        })
+                    } else {
+                        info.winner
+                            .as_ref()
+                            .map_or("Draw".to_string(), |name| format!("{} wins", name))
+                    };
+                    format!("{} over, {}\nNext match in {}", mode, winner, remaining)
+                };
+                *visibility = Visibility::Visible;
+            }
+            (HudText::Health, _) if connected => {
+                text.0 = format!("Health {}  Armor {}", status.health, status.armor);
+                *visibility = Visibility::Visible;
+            }
+            (HudText::Death, Some(death)) if connected => {
+                let remaining = (death.respawn_time - time.elapsed_secs_f64()).max(0.);
+                text.0 = format!(
+                    "Killed by {}\nRespawn in {}",
+                    death.killer,
+                    remaining.ceil() as u32
+                );
+                *visibility = Visibility::Visible;
+            }
+            _ => *visibility = Visibility::Hidden,
+        }
+    }
+}
+
+fn spawn_hud(mut commands: Commands) {
+    commands.spawn((
+        Text::new(""),
+        Node {
+            position_type: PositionType::Absolute,
+            left: Val::Px(12.),
+            bottom: Val::Px(12.),
+            ..default()
+        },
+        Visibility::Hidden,
+        HudText::Health,
+    ));
+    commands.spawn((
+        Text::new(""),
+        TextColor(Color::srgb(1.0, 0.3, 0.3)),
+        TextLayout::new_with_justify(JustifyText::Center),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Percent(30.),
+            width: Val::Percent(100.),
+            justify_content: JustifyContent::Center,
+            ..default()
+        },
+        Visibility::Hidden,
+        HudText::Death,
+    ));
+    commands.spawn((
+        Text::new(""),
+        TextLayout::new_with_justify(JustifyText::Center),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(12.),
+            width: Val::Percent(100.),
+            justify_content: JustifyContent::Center,
+            ..default()
+        },
+        Visibility::Hidden,
+        HudText::Match,
+    ));
+}
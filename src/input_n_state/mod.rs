@@ -1,7 +1,11 @@
+use crate::chat::ChatInput;
 use crate::config::do_read_config;
 use crate::config::get_file;
+use crate::logging::LogSettings;
+use crate::players::team::TeamChangeEvent;
 use crate::players::*;
 use crate::server::Server;
+use crate::weapon::PlayerFireEvent;
 use crate::KeyboardInput;
 use bevy::prelude::*;
 use bevy_input::{mouse::AccumulatedMouseMotion, ButtonState};
@@ -53,6 +57,32 @@ pub struct AppParams {
     pub config_file: Option<String>,
     // AppParams changed an not persisted.
     pub changed: bool,
+    // Remote players are rendered this far behind the server snapshots.
+    #[serde(default = "default_interpolation_delay_ms")]
+    pub interpolation_delay_ms: u32,
+    // Maximum time remote player movement is extrapolated when snapshots are late.
+    #[serde(default = "default_extrapolation_limit_ms")]
+    pub extrapolation_limit_ms: u32,
+    #[serde(default)]
+    pub log: LogSettings,
+    // Master servers the server browser fetches the server lists from.
+    #[serde(default)]
+    pub master_servers: Vec<String>,
+    // A lost connection is retried for this long, 0 turns reconnecting off.
+    #[serde(default = "default_reconnect_secs")]
+    pub reconnect_secs: u32,
+}
+
+fn default_interpolation_delay_ms() -> u32 {
+    100
+}
+
+fn default_extrapolation_limit_ms() -> u32 {
+    250
+}
+
+fn default_reconnect_secs() -> u32 {
+    60
 }
 
 impl AppParams {
@@ -64,6 +94,11 @@ impl AppParams {
             last_server_index: self.last_server_index,
             config_file: self.config_file.clone(),
             changed: self.changed,
+            interpolation_delay_ms: self.interpolation_delay_ms,
+            extrapolation_limit_ms: self.extrapolation_limit_ms,
+            log: self.log.clone(),
+            master_servers: self.master_servers.clone(),
+            reconnect_secs: self.reconnect_secs,
         }
     }
     pub fn default() -> AppParams {
@@ -77,6 +112,11 @@ impl AppParams {
             last_server_index: 0,
             config_file: None,
             changed: true,
+            interpolation_delay_ms: default_interpolation_delay_ms(),
+            extrapolation_limit_ms: default_extrapolation_limit_ms(),
+            log: LogSettings::default(),
+            master_servers: vec![],
+            reconnect_secs: default_reconnect_secs(),
         }
     }
     pub fn set_last_server_index(&mut self, index: i8) {
@@ -146,6 +186,18 @@ impl Plugin for InputNStatePlugin {
     }
 }
 
+//The client config file of the --config argument.
+pub fn config_file_arg() -> Result<Option<String>, String> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 2 && args[1] == "--config" {
+        //Check the file exists.
+        get_file(&args[2].clone().into(), false)?;
+        Ok(Some(args[2].clone()))
+    } else {
+        Ok(None)
+    }
+}
+
 pub fn initialise_app(
     mut commands: Commands,
     mut windows: Query<&mut Window>,
@@ -153,37 +205,16 @@ pub fn initialise_app(
     mut next_state: ResMut<NextState<AppState>>,
 ) {
     //Check for commandline config.
-    let args: Vec<String> = std::env::args().collect();
-    let config_file_path: Option<String> = {
-        if args.len() > 2 {
-            let parameter = &args[1];
-            match parameter.as_str() {
-                "--config" => {
-                    //Check the file exists.
-                    let file_result = get_file(&args[2].clone().into(), false);
-                    match file_result {
-                        Ok(_) => Some(args[2].clone().into()),
-                        Err(e) => {
-                            println!("--config path arg error: {}", e);
-                            None
-                        }
-                    }
-                }
-                _ => None,
-            }
-        } else {
-            None
-        }
-    };
+    let config_file_path = config_file_arg().unwrap_or_else(|e| {
+        error!("--config path arg error: {}", e);
+        None
+    });
 
     //config file is Some for the command line config option.
     let params = match do_read_config(config_file_path.clone()) {
         Ok(param) => param,
         Err(e) => {
-            println!(
-                "Failed to read configuration, using defaults. (input_n_state) {}",
-                e
-            );
+            warn!("Failed to read configuration, using defaults. {}", e);
             next_state.set(AppState::MainMenu);
             next_item.set(MenuItem::Config);
             let mut apps = AppParams::default();
@@ -200,18 +231,24 @@ pub fn initialise_app(
                 .set(params.window_size.x, params.window_size.y);
         }
         Err(e) => {
-            println!("Window error: {}", e);
+            error!("Window error: {}", e);
         }
     }
     commands.insert_resource(params);
 }
 
-fn keyboard_event_system(
+//Keys are ignored while the chat line is open.
+pub fn keyboard_event_system(
     mut keyboard_input_events: EventReader<KeyboardInput>,
     mut next_state: ResMut<NextState<AppState>>,
     mut player_movement: EventWriter<PlayerMovementEvent>,
+    mut team_change: EventWriter<TeamChangeEvent>,
+    chat_input: Res<ChatInput>,
 ) {
     for event in keyboard_input_events.read() {
+        if chat_input.open {
+            continue;
+        }
         match event {
             KeyboardInput {
                 key_code,
@@ -235,6 +272,9 @@ fn keyboard_event_system(
                 KeyCode::Escape | KeyCode::KeyX => next_state.set(AppState::GameOver),
                 KeyCode::KeyM => next_state.set(AppState::MainMenu),
                 KeyCode::KeyG => next_state.set(AppState::Game),
+                KeyCode::KeyT => {
+                    team_change.send(TeamChangeEvent);
+                }
                 _ => {}
             },
             _ => {}
@@ -245,11 +285,16 @@ fn keyboard_event_system(
 
 fn mouse_event_system(
     accumulated_mouse_motion: Res<AccumulatedMouseMotion>,
+    mouse_buttons: Res<ButtonInput<MouseButton>>,
     mut player_rotate: EventWriter<PlayerRotateEvent>,
+    mut player_fire: EventWriter<PlayerFireEvent>,
 ) {
     if accumulated_mouse_motion.delta != Vec2::ZERO {
         player_rotate.send(PlayerRotateEvent(accumulated_mouse_motion.delta));
     }
+    if mouse_buttons.just_pressed(MouseButton::Left) {
+        player_fire.send(PlayerFireEvent);
+    }
 }
 
 fn app_exit(
@@ -262,7 +307,7 @@ fn app_exit(
     } else if multiplayer_state.get().eq(&MultiplayerState::Disconnected) {
         app_exit_event_writer.send(AppExit::Success);
     } else {
-        println!("waiting for disconnect {:?} ... ", multiplayer_state.get());
+        debug!("waiting for disconnect {:?} ... ", multiplayer_state.get());
     }
 }
 
@@ -0,0 +1,386 @@
+use bevy::log::tracing_subscriber::layer::{Context, Layer};
+use bevy::log::{BoxedLayer, Level, LogPlugin, DEFAULT_FILTER};
+use bevy::prelude::*;
+use bevy::utils::tracing::field::{Field, Visit};
+use bevy::utils::tracing::{Event, Subscriber};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::get_shooter_file_path;
+
+//Log files are kept in this directory of the shooter config directory.
+const LOG_DIR: &str = "logs";
+
+//Filter names for groups of modules, other filter names are modules of the game.
+const MODULE_GROUPS: [(&str, &[&str]); 4] = [
+    (
+        "network",
+        &[
+            "shooter::client",
+            "shooter::server",
+            "shooter::protocol",
+            "shooter::token",
+        ],
+    ),
+    ("players", &["shooter::players"]),
+    ("menu", &["shooter::menu"]),
+    ("config", &["shooter::config", "shooter::input_n_state"]),
+];
+
+//The [log] settings of config.toml and server.toml.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Reflect)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    //error, warn, info, debug or trace.
+    pub level: String,
+    //Levels by module group or game module, ie network = "debug". off silences a module.
+    pub filters: BTreeMap<String, String>,
+    //Write the log file as JSON lines.
+    pub json: bool,
+    //Also log to a file in the logs directory.
+    pub file: bool,
+    //A log file over this size is rotated.
+    pub max_file_bytes: u64,
+    //The number of rotated files kept.
+    pub max_files: u32,
+}
+
+impl Default for LogSettings {
+    fn default() -> LogSettings {
+        LogSettings {
+            level: "info".to_string(),
+            filters: BTreeMap::new(),
+            json: false,
+            file: false,
+            max_file_bytes: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Result<Level, String> {
+    level.parse::<Level>().map_err(|_| {
+        format!(
+            "Invalid log level {}, use error, warn, info, debug or trace.",
+            level
+        )
+    })
+}
+
+impl LogSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        parse_level(&self.level)?;
+        for (name, level) in &self.filters {
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("Invalid log filter module {}.", name));
+            }
+            if level != "off" {
+                parse_level(level)?;
+            }
+        }
+        if self.file && self.max_file_bytes == 0 {
+            return Err("max_file_bytes must be above 0.".to_string());
+        }
+        Ok(())
+    }
+
+    //The EnvFilter directives of the filters.
+    pub fn filter(&self) -> String {
+        let mut filter = DEFAULT_FILTER.to_string();
+        for (name, level) in &self.filters {
+            match MODULE_GROUPS.iter().find(|(group, _)| group == name) {
+                Some((_, modules)) => {
+                    for module in modules.iter() {
+                        write!(filter, ",{}={}", module, level).unwrap();
+                    }
+                }
+                None => write!(filter, ",shooter::{}={}", name, level).unwrap(),
+            }
+        }
+        filter
+    }
+}
+
+//The log file settings for file_layer, taken from the app when the LogPlugin is built.
+#[derive(Resource)]
+struct LogOutput {
+    settings: LogSettings,
+    file_name: String,
+}
+
+//The LogPlugin for the settings, logging to file_name in the logs directory when the file
+//is on. RUST_LOG overrides the levels.
+pub fn log_plugin(app: &mut App, settings: &LogSettings, file_name: &str) -> LogPlugin {
+    //The logger is not running yet.
+    if let Err(e) = settings.validate() {
+        eprintln!("Log settings ignored. {}", e);
+        return LogPlugin::default();
+    }
+    if settings.file {
+        app.insert_resource(LogOutput {
+            settings: settings.clone(),
+            file_name: file_name.to_string(),
+        });
+    }
+    LogPlugin {
+        filter: settings.filter(),
+        level: parse_level(&settings.level).unwrap_or(Level::INFO),
+        custom_layer: file_layer,
+    }
+}
+
+//Add the logging to an app without the DefaultPlugins.
+pub fn add_logging(app: &mut App, settings: &LogSettings, file_name: &str) {
+    let plugin = log_plugin(app, settings, file_name);
+    app.add_plugins(plugin);
+}
+
+fn file_layer(app: &mut App) -> Option<BoxedLayer> {
+    let LogOutput {
+        settings,
+        file_name,
+    } = app.world_mut().remove_resource::<LogOutput>()?;
+    let path = match get_shooter_file_path(LOG_DIR) {
+        Ok(dir) => PathBuf::from(dir).join(file_name),
+        Err(e) => {
+            eprintln!("No log file. {}", e);
+            return None;
+        }
+    };
+    match RotatingFile::open(path.clone(), settings.max_file_bytes, settings.max_files) {
+        Ok(file) => Some(Box::new(FileLayer {
+            file: Mutex::new(file),
+            json: settings.json,
+        })),
+        Err(e) => {
+            eprintln!("Could not open the log file {}. {}", path.display(), e);
+            None
+        }
+    }
+}
+
+//A log file renamed to name.1, name.2 ... as it fills.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, max_files: u32) -> io::Result<RotatingFile> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            len,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn numbered(&self, num: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", num));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files > 0 {
+            //Windows does not rename over a file.
+            let _ = fs::remove_file(self.numbered(self.max_files));
+            for num in (1..self.max_files).rev() {
+                match fs::rename(self.numbered(num), self.numbered(num + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.numbered(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+//The tracing layer writing the log file.
+struct FileLayer {
+    file: Mutex<RotatingFile>,
+    json: bool,
+}
+
+impl<S: Subscriber> Layer<S> for FileLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut fields = EventFields(vec![]);
+        event.record(&mut fields);
+        let metadata = event.metadata();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let line = format_line(
+            time,
+            metadata.level(),
+            metadata.target(),
+            &fields.0,
+            self.json,
+        );
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(e) = file.write_line(&line) {
+                eprintln!("Could not write the log file. {}", e);
+            }
+        }
+    }
+}
+
+struct EventFields(Vec<(&'static str, String)>);
+
+impl Visit for EventFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+fn format_line(
+    time: f64,
+    level: &Level,
+    target: &str,
+    fields: &[(&'static str, String)],
+    json: bool,
+) -> String {
+    let mut line = String::new();
+    if json {
+        write!(
+            line,
+            "{{\"time\":{:.3},\"level\":\"{}\",\"target\":{}",
+            time,
+            level,
+            json_string(target)
+        )
+        .unwrap();
+        for (name, value) in fields {
+            write!(line, ",{}:{}", json_string(name), json_string(value)).unwrap();
+        }
+        line.push('}');
+    } else {
+        write!(line, "{:.3} {:>5} {}:", time, level, target).unwrap();
+        for (name, value) in fields {
+            if *name == "message" {
+                write!(line, " {}", value).unwrap();
+            } else {
+                write!(line, " {}={}", name, value).unwrap();
+            }
+        }
+    }
+    line.push('\n');
+    line
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = String::with_capacity(text.len() + 2);
+    json.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[test]
+fn test_log_settings() {
+    let mut settings = LogSettings::default();
+    assert!(settings.validate().is_ok());
+    assert_eq!(settings.filter(), DEFAULT_FILTER);
+    settings
+        .filters
+        .insert("network".to_string(), "debug".to_string());
+    settings
+        .filters
+        .insert("hud".to_string(), "off".to_string());
+    assert_eq!(
+        settings.filter(),
+        format!(
+            "{},shooter::hud=off,shooter::client=debug,shooter::server=debug,\
+            shooter::protocol=debug,shooter::token=debug",
+            DEFAULT_FILTER
+        )
+    );
+    assert!(settings.validate().is_ok());
+    settings
+        .filters
+        .insert("menu".to_string(), "loud".to_string());
+    assert!(settings.validate().is_err());
+    let settings: LogSettings = toml::from_str("level = \"warn\"\njson = true\n").unwrap();
+    assert_eq!(settings.level, "warn");
+    assert!(settings.json);
+    assert!(toml::from_str::<LogSettings>("levle = \"warn\"\n").is_err());
+}
+
+#[test]
+fn test_format_line() {
+    let fields = [
+        ("message", "Client \"ikky\"\tjoined.".to_string()),
+        ("client_id", "7".to_string()),
+    ];
+    assert_eq!(
+        format_line(12.5, &Level::INFO, "shooter::server", &fields, false),
+        "12.500  INFO shooter::server: Client \"ikky\"\tjoined. client_id=7\n"
+    );
+    assert_eq!(
+        format_line(12.5, &Level::WARN, "shooter::server", &fields, true),
+        "{\"time\":12.500,\"level\":\"WARN\",\"target\":\"shooter::server\",\
+        \"message\":\"Client \\\"ikky\\\"\\tjoined.\",\"client_id\":\"7\"}\n"
+    );
+}
+
+#[test]
+fn test_rotating_file() {
+    let dir = std::env::temp_dir().join("shooter_log_test");
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("test.log");
+    let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+    for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+        file.write_line(line).unwrap();
+    }
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+    assert_eq!(fs::read_to_string(file.numbered(1)).unwrap(), "third\n");
+    assert_eq!(fs::read_to_string(file.numbered(2)).unwrap(), "second\n");
+    assert!(!file.numbered(3).exists());
+    //Appends to an existing file.
+    let mut file = RotatingFile::open(path.clone(), 100, 2).unwrap();
+    file.write_line("fifth\n").unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\nfifth\n");
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -6,18 +6,37 @@ use bevy::render::mesh::VertexAttributeValues;
 use bevy_egui::EguiPlugin;
 use std::f32::consts::PI;
 
+use crate::chat::ChatPlugin;
 use crate::client::ClientPlugin;
-use crate::input_n_state::{AppParams, InputNStatePlugin};
+use crate::config::{do_read_config, do_read_server_config};
+use crate::hud::HudPlugin;
+use crate::input_n_state::{config_file_arg, AppParams, InputNStatePlugin};
+use crate::logging::log_plugin;
+use crate::master::{master_main, MASTER_PORT};
 use crate::menu::MenuPlugin;
 use crate::players::PlayersPlugin;
+use crate::server::game_mode::{DEFAULT_FRAG_LIMIT, DEFAULT_TIME_LIMIT_SECS};
+use crate::server::lag_compensation::{DEFAULT_MAX_REWIND_MS, MAX_REWIND_LIMIT_MS};
 use crate::server::server_main;
+use crate::server::settings::ServerOptions;
+use crate::server::tick::{DEFAULT_TICK_RATE, MAX_TICK_RATE, MIN_TICK_RATE};
+use crate::token::token_server_main;
+use crate::weapon::WeaponPlugin;
 
+mod chat;
 mod client;
 mod config;
+mod discovery;
+mod hud;
 mod input_n_state;
+mod logging;
+mod master;
 mod menu;
 mod players;
+mod protocol;
 mod server;
+mod token;
+mod weapon;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
@@ -26,25 +45,72 @@ fn main() {
         match exec_type.as_str() {
             "--server" => {
                 println!("Starting server...");
+                match server_args(&args[2..]) {
+                    Ok(options) => server_main(options),
+                    Err(e) => eprintln!("{}", e),
+                }
+                return;
+            }
+            "--token-server" => {
+                println!("Starting token server...");
                 if args.len() > 2 {
-                    let connection = &args[2];
-                    server_main(Some(connection));
+                    token_server_main(Some(&args[2]));
                 } else {
-                    server_main(None);
+                    token_server_main(None);
                 }
                 return;
             }
+            "--master" => {
+                println!("Starting master server...");
+                master_main(args.get(2).map(String::as_str));
+                return;
+            }
             "--help" => {
                 println!("\nRun client with an alternate configuration:");
                 println!("    --config [alternate filename]");
                 println!(
                     "\nStart server on custom connection ie\n\
-                    + ipv4 192.168.1.4\n\
-                    + ipv6 2001:8003:e95b:100:9007:deff:fe70:1840"
+                    + ipv4 192.168.1.4:5000\n\
+                    + ipv6 [2001:8003:e95b:100:9007:deff:fe70:1840]:5000"
                 );
                 println!("    --server [connection]");
                 println!("\nStart server on local loopback");
                 println!("    --server");
+                println!(
+                    "\nServer settings file (default server.toml in the shooter config directory),\n\
+                    the other server arguments override its settings"
+                );
+                println!("    --server [connection] --config [filename]");
+                println!(
+                    "\nServer simulation rate in ticks per second ({}-{}, default {})",
+                    MIN_TICK_RATE, MAX_TICK_RATE, DEFAULT_TICK_RATE
+                );
+                println!("    --server [connection] --tick-rate [rate]");
+                println!(
+                    "\nMaximum lag compensation rewind for shots in milliseconds (0-{}, default {})",
+                    MAX_REWIND_LIMIT_MS, DEFAULT_MAX_REWIND_MS
+                );
+                println!("    --server [connection] --max-rewind-ms [ms]");
+                println!("\nGame mode (deathmatch or teamdeathmatch, default deathmatch)");
+                println!("    --server [connection] --mode [mode]");
+                println!(
+                    "\nFrag and time limits of a match (0 for no frag limit, default {} frags {} s)",
+                    DEFAULT_FRAG_LIMIT, DEFAULT_TIME_LIMIT_SECS
+                );
+                println!("    --server [connection] --frag-limit [frags] --time-limit [secs]");
+                println!("\nTeam mates can damage each other (on or off, default off)");
+                println!("    --server [connection] --friendly-fire [on|off]");
+                println!(
+                    "\nStart only the connect token service for the server connection\n\
+                    (the server private key is read from $SHOOTER_PRIVATE_KEY or the key file)"
+                );
+                println!("    --token-server [connection]");
+                println!(
+                    "\nStart a master server listing the servers that send it heartbeats\n\
+                    (default 127.0.0.1:{}, servers set masters in server.toml)",
+                    MASTER_PORT
+                );
+                println!("    --master [connection]");
                 return;
             }
 
@@ -52,17 +118,88 @@ fn main() {
         }
     }
     let mut app: App = App::new();
-    app.add_plugins(DefaultPlugins);
+    //The logging starts before the config is loaded by initialise_app.
+    let log_settings = config_file_arg()
+        .ok()
+        .and_then(|config_path| do_read_config(config_path).ok())
+        .map(|params| params.log)
+        .unwrap_or_default();
+    let log_plugin = log_plugin(&mut app, &log_settings, "client.log");
+    app.add_plugins(DefaultPlugins.set(log_plugin));
     app.add_plugins(EguiPlugin);
     app.add_plugins(InputNStatePlugin);
     app.add_plugins(MenuPlugin);
     app.add_plugins(ClientPlugin);
     app.add_plugins(PlayersPlugin);
+    app.add_plugins(WeaponPlugin);
+    app.add_plugins(HudPlugin);
+    app.add_plugins(ChatPlugin);
     app.add_systems(Startup, setup);
     app.add_systems(Update, (move_cube, rotate_on_timer));
     app.run();
 }
 
+//Parse the arguments following --server: [connection] [--config path] [--tick-rate rate]
+//[--max-rewind-ms ms] [--mode mode] [--frag-limit frags] [--time-limit secs]
+//[--friendly-fire on|off]. The arguments override the server.toml settings.
+fn server_args(args: &[String]) -> Result<ServerOptions, String> {
+    let config_path = match args.iter().position(|arg| arg == "--config") {
+        Some(index) => Some(
+            args.get(index + 1)
+                .ok_or("--config requires a file")?
+                .clone(),
+        ),
+        None => None,
+    };
+    let mut settings = do_read_server_config(config_path)?;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--config" => {
+                iter.next();
+            }
+            "--tick-rate" => {
+                let rate = iter.next().ok_or("--tick-rate requires a rate")?;
+                settings.tick_rate = rate
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid tick rate {}.", rate))?;
+            }
+            "--max-rewind-ms" => {
+                let ms = iter.next().ok_or("--max-rewind-ms requires a time")?;
+                settings.max_rewind_ms = ms
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid maximum rewind {}.", ms))?;
+            }
+            "--mode" => {
+                settings.mode = iter.next().ok_or("--mode requires a game mode")?.clone();
+            }
+            "--frag-limit" => {
+                let frags = iter.next().ok_or("--frag-limit requires a frag count")?;
+                settings.frag_limit = frags
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid frag limit {}.", frags))?;
+            }
+            "--time-limit" => {
+                let secs = iter.next().ok_or("--time-limit requires a time")?;
+                settings.time_limit_secs = secs
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid time limit {}.", secs))?;
+            }
+            "--friendly-fire" => {
+                settings.friendly_fire = match iter.next().map(|s| s.as_str()) {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => return Err("--friendly-fire requires on or off".to_string()),
+                };
+            }
+            //The connection, a mistyped option is not taken for an address.
+            _ if !arg.starts_with("--") => settings.bind = arg.clone(),
+            _ => return Err(format!("Unknown option {}.", arg)),
+        }
+    }
+    settings.validate()
+}
+
 #[derive(Component)]
 struct Cube;
 
@@ -0,0 +1,335 @@
+use bevy::log::{debug, error, info, warn};
+use bevy::prelude::{App, Resource};
+use std::collections::HashMap;
+use std::io::{Cursor, Error, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::do_read_server_config;
+use crate::discovery::{DiscoveryReply, FoundServer};
+use crate::logging::add_logging;
+use crate::protocol::{WireFormat, PROTOCOL_VERSION};
+
+//The master server takes heartbeats on this port (udp) and lists the servers on it (tcp).
+pub const MASTER_PORT: u16 = 5100;
+//Dedicated servers send a heartbeat this often to each of their masters.
+pub const HEARTBEAT_SECS: f32 = 10.0;
+//A server is dropped from the list after this long without a heartbeat.
+const SERVER_EXPIRY: Duration = Duration::from_secs(35);
+//Heartbeats of more servers are ignored.
+const MAX_SERVERS: usize = 256;
+const LIST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_LIST_BYTES: u64 = 256 * 1024;
+
+//Like the discovery packets the master packets start with a magic:
+//  heartbeat (udp): [HEARTBEAT_MAGIC][DiscoveryReply]
+//  list (tcp):      [LIST_MAGIC][Vec<FoundServer>]
+//A server is listed at the heartbeat source address with the game port of the reply.
+const HEARTBEAT_MAGIC: &[u8; 8] = b"SHOOTER+";
+const LIST_MAGIC: &[u8; 8] = b"SHOOTER=";
+const MAX_HEARTBEAT_BYTES: usize = 512;
+
+pub fn heartbeat_packet(reply: &DiscoveryReply) -> Result<Vec<u8>, Error> {
+    let mut buf = HEARTBEAT_MAGIC.to_vec();
+    reply.write_to(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_heartbeat(packet: &[u8]) -> Result<DiscoveryReply, Error> {
+    if !packet.starts_with(HEARTBEAT_MAGIC) {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a heartbeat"));
+    }
+    let cursor = &mut Cursor::new(&packet[HEARTBEAT_MAGIC.len()..]);
+    let reply = DiscoveryReply::read_from(cursor)?;
+    if reply.version != PROTOCOL_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Protocol version {}", reply.version),
+        ));
+    }
+    if cursor.position() as usize != cursor.get_ref().len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Trailing bytes after the heartbeat",
+        ));
+    }
+    Ok(reply)
+}
+
+//The servers known to the master and the time of their last heartbeat.
+#[derive(Default)]
+struct Registry {
+    servers: HashMap<SocketAddr, (DiscoveryReply, Instant)>,
+}
+
+impl Registry {
+    fn heartbeat(&mut self, from: SocketAddr, packet: &[u8], now: Instant) -> Result<(), String> {
+        let reply = read_heartbeat(packet)
+            .map_err(|e| format!("Invalid heartbeat from {}. {}", from, e))?;
+        let addr = SocketAddr::new(from.ip(), reply.port);
+        self.expire(now);
+        if !self.servers.contains_key(&addr) {
+            if self.servers.len() >= MAX_SERVERS {
+                return Err(format!("Server list full, {} not listed.", addr));
+            }
+            info!("Listed {} on {}.", reply.name, addr);
+        }
+        self.servers.insert(addr, (reply, now));
+        Ok(())
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.servers.retain(|addr, (reply, last_seen)| {
+            let alive = now.duration_since(*last_seen) < SERVER_EXPIRY;
+            if !alive {
+                info!("Dropped {} on {}, no heartbeat.", reply.name, addr);
+            }
+            alive
+        });
+    }
+
+    //The servers by name.
+    fn list(&mut self, now: Instant) -> Vec<FoundServer> {
+        self.expire(now);
+        let mut servers: Vec<FoundServer> = self
+            .servers
+            .iter()
+            .map(|(addr, (reply, _))| FoundServer {
+                addr: *addr,
+                reply: reply.clone(),
+            })
+            .collect();
+        servers.sort_by(|a, b| a.reply.name.cmp(&b.reply.name).then(a.addr.cmp(&b.addr)));
+        servers
+    }
+}
+
+fn list_packet(servers: &Vec<FoundServer>) -> Result<Vec<u8>, Error> {
+    let mut buf = LIST_MAGIC.to_vec();
+    servers.write_to(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_list(packet: &[u8]) -> Result<Vec<FoundServer>, Error> {
+    if !packet.starts_with(LIST_MAGIC) {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a server list"));
+    }
+    Vec::<FoundServer>::read_from(&mut Cursor::new(&packet[LIST_MAGIC.len()..]))
+}
+
+//Send the list to each client connecting to the listener.
+fn spawn_list_service(
+    listener: TcpListener,
+    registry: Arc<Mutex<Registry>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| {
+                stream.set_write_timeout(Some(LIST_TIMEOUT))?;
+                let servers = match registry.lock() {
+                    Ok(mut registry) => registry.list(Instant::now()),
+                    Err(_) => return Err(Error::other("Registry poisoned")),
+                };
+                stream.write_all(&list_packet(&servers)?)
+            });
+            if let Err(e) = result {
+                warn!("Server list request failed. {}", e);
+            }
+        }
+    })
+}
+
+//Standalone master server on connection.
+pub fn master_main(connection: Option<&str>) {
+    //Logs with the server.toml log settings like the token service.
+    let log_settings = do_read_server_config(None)
+        .map(|settings| settings.log)
+        .unwrap_or_default();
+    add_logging(&mut App::new(), &log_settings, "master.log");
+    let addr: SocketAddr = match connection {
+        Some(con) => match con.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                error!("Not a valid connection: {}", con);
+                return;
+            }
+        },
+        None => SocketAddr::from(([127, 0, 0, 1], MASTER_PORT)),
+    };
+    let (socket, listener) = match UdpSocket::bind(addr).and_then(|socket| {
+        let listener = TcpListener::bind(addr)?;
+        Ok((socket, listener))
+    }) {
+        Ok(bound) => bound,
+        Err(e) => {
+            error!("Could not bind the master server {}. {}", addr, e);
+            return;
+        }
+    };
+    info!("Master server listening on {}", addr);
+    let registry = Arc::new(Mutex::new(Registry::default()));
+    spawn_list_service(listener, registry.clone());
+    let mut buf = [0u8; MAX_HEARTBEAT_BYTES];
+    loop {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                let result = match registry.lock() {
+                    Ok(mut registry) => registry.heartbeat(from, &buf[..len], Instant::now()),
+                    Err(_) => {
+                        error!("Server registry poisoned.");
+                        return;
+                    }
+                };
+                if let Err(e) = result {
+                    debug!("{}", e);
+                }
+            }
+            Err(e) => warn!("Master socket error. {}", e),
+        }
+    }
+}
+
+//Ask a master server for its list.
+pub fn request_server_list(master: SocketAddr) -> Result<Vec<FoundServer>, String> {
+    let stream = TcpStream::connect_timeout(&master, LIST_TIMEOUT)
+        .map_err(|e| format!("Could not reach master server {}. {}", master, e))?;
+    stream
+        .set_read_timeout(Some(LIST_TIMEOUT))
+        .map_err(|e| format!("Master server error. {}", e))?;
+    let mut reply: Vec<u8> = vec![];
+    stream
+        .take(MAX_LIST_BYTES)
+        .read_to_end(&mut reply)
+        .map_err(|e| format!("Could not read the server list of {}. {}", master, e))?;
+    read_list(&reply).map_err(|e| format!("Invalid server list from {}. {}", master, e))
+}
+
+//The client side, the lists of the configured master servers fetched on a thread.
+#[derive(Resource, Default)]
+pub struct MasterQuery {
+    receiver: Option<Mutex<Receiver<Vec<FoundServer>>>>,
+    pub servers: Vec<FoundServer>,
+}
+
+impl MasterQuery {
+    pub fn start(&mut self, masters: &[String]) {
+        let masters = masters.to_vec();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut servers: Vec<FoundServer> = vec![];
+            for master in masters {
+                let listed = master
+                    .parse()
+                    .map_err(|_| format!("Master server {} is not an address with a port.", master))
+                    .and_then(request_server_list);
+                match listed {
+                    Ok(listed) => {
+                        for server in listed {
+                            if !servers.iter().any(|found| found.addr == server.addr) {
+                                servers.push(server);
+                            }
+                        }
+                    }
+                    Err(e) => warn!("{}", e),
+                }
+            }
+            let _ = sender.send(servers);
+        });
+        self.receiver = Some(Mutex::new(receiver));
+    }
+
+    pub fn is_querying(&self) -> bool {
+        self.receiver.is_some()
+    }
+
+    pub fn poll(&mut self) {
+        let Some(receiver) = &self.receiver else {
+            return;
+        };
+        let result = match receiver.lock() {
+            Ok(receiver) => receiver.try_recv(),
+            Err(_) => Err(mpsc::TryRecvError::Disconnected),
+        };
+        match result {
+            Ok(servers) => {
+                self.servers = servers;
+                self.receiver = None;
+            }
+            Err(mpsc::TryRecvError::Empty) => {}
+            Err(mpsc::TryRecvError::Disconnected) => self.receiver = None,
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_reply(name: &str, port: u16) -> DiscoveryReply {
+    DiscoveryReply {
+        version: PROTOCOL_VERSION,
+        port,
+        name: name.to_string(),
+        map: "arena".to_string(),
+        players: 1,
+        max_players: 16,
+    }
+}
+
+#[test]
+fn test_registry() {
+    let mut registry = Registry::default();
+    let start = Instant::now();
+    let from: SocketAddr = "192.0.2.7:40000".parse().unwrap();
+    let packet = heartbeat_packet(&test_reply("Beta", 5000)).unwrap();
+    registry.heartbeat(from, &packet, start).unwrap();
+    let packet = heartbeat_packet(&test_reply("Alpha", 5003)).unwrap();
+    registry.heartbeat(from, &packet, start).unwrap();
+    //Listed at the source address with the game port.
+    let list = registry.list(start);
+    assert_eq!(list.len(), 2);
+    assert_eq!(list[0].addr, "192.0.2.7:5003".parse().unwrap());
+    assert_eq!(list[1].reply, test_reply("Beta", 5000));
+
+    let mut other = test_reply("Old", 5006);
+    other.version = PROTOCOL_VERSION - 1;
+    let packet = heartbeat_packet(&other).unwrap();
+    assert!(registry.heartbeat(from, &packet, start).is_err());
+    assert!(registry.heartbeat(from, b"SHOOTER+", start).is_err());
+
+    //Alpha keeps sending heartbeats, Beta expires.
+    let later = start + SERVER_EXPIRY;
+    let packet = heartbeat_packet(&test_reply("Alpha", 5003)).unwrap();
+    registry
+        .heartbeat(from, &packet, start + SERVER_EXPIRY / 2)
+        .unwrap();
+    let list = registry.list(later);
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].reply.name, "Alpha");
+
+    let packet = list_packet(&list).unwrap();
+    assert_eq!(read_list(&packet).unwrap(), list);
+    assert!(read_list(&packet[..packet.len() - 1]).is_err());
+}
+
+#[test]
+fn test_request_server_list() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let master = listener.local_addr().unwrap();
+    let registry = Arc::new(Mutex::new(Registry::default()));
+    let packet = heartbeat_packet(&test_reply("Arena", 5000)).unwrap();
+    registry
+        .lock()
+        .unwrap()
+        .heartbeat("127.0.0.1:40000".parse().unwrap(), &packet, Instant::now())
+        .unwrap();
+    spawn_list_service(listener, registry);
+    let list = request_server_list(master).unwrap();
+    assert_eq!(
+        list,
+        vec![FoundServer {
+            addr: "127.0.0.1:5000".parse().unwrap(),
+            reply: test_reply("Arena", 5000),
+        }]
+    );
+}
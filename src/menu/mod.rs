@@ -1,15 +1,20 @@
+use bevy::input::common_conditions::input_pressed;
 use bevy::prelude::*;
 use bevy::winit::{UpdateMode, WinitSettings};
 use bevy_egui::{egui::menu, EguiContexts, EguiSet, EguiStartupSet};
 use egui::containers::panel::TopBottomPanel;
-use egui::{pos2, Color32, Visuals};
+use egui::{pos2, Align2, Color32, Grid, Ui, Visuals};
 use regex::Regex;
-use std::sync::Mutex;
 use std::time::Duration;
 
 use crate::client::*;
 use crate::config::*;
+use crate::discovery::{FoundServer, LanScan, ServerStatus, StatusPoller};
+use crate::hud::{MatchInfo, Scoreboard};
 use crate::input_n_state::*;
+use crate::master::MasterQuery;
+use crate::protocol::PROTOCOL_VERSION;
+use crate::server::game_mode::Team;
 use crate::server::Server;
 use crate::ActiveCamera;
 
@@ -17,6 +22,9 @@ pub struct MenuPlugin;
 
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<LanScan>();
+        app.init_resource::<MasterQuery>();
+        app.insert_resource(StatusPoller::spawn());
         app.add_systems(Startup, setup_menu);
         app.add_systems(Startup, low_fps);
         app.add_systems(
@@ -26,6 +34,9 @@ impl Plugin for MenuPlugin {
                 spawn_config_window.run_if(in_state(MenuItem::Config)),
                 spawn_player_window.run_if(in_state(MenuItem::Players)),
                 spawn_server_window.run_if(in_state(MenuItem::Servers)),
+                spawn_scoreboard_window
+                    .run_if(in_state(MultiplayerState::Connected).and(input_pressed(KeyCode::Tab))),
+                spawn_reconnect_window.run_if(is_reconnecting),
             )
                 .after(EguiStartupSet::InitContexts)
                 .after(EguiSet::InitContexts),
@@ -34,6 +45,10 @@ impl Plugin for MenuPlugin {
         app.add_systems(OnEnter(AppState::Game), high_fps);
         app.add_systems(OnExit(AppState::Game), low_fps);
         app.add_systems(OnEnter(MenuItem::ActivateCamera), activate_camera);
+        app.add_systems(
+            OnEnter(MultiplayerState::Connected),
+            close_server_window.run_if(in_state(MenuItem::Servers)),
+        );
 
         app.add_systems(
             OnEnter(MenuItem::Config),
@@ -62,11 +77,11 @@ pub fn spawn_main_menu(
                     MultiplayerState::Connected => {
                         next_item.set(MenuItem::Players);
                     }
-                    MultiplayerState::Disconnected => {
+                    MultiplayerState::Disconnected | MultiplayerState::Connecting => {
                         next_item.set(MenuItem::Servers);
                     }
                     _ => {
-                        println!("{:?}", multiplayer_state.get())
+                        debug!("{:?}", multiplayer_state.get())
                     }
                 }
                 ui.close_menu();
@@ -101,17 +116,17 @@ fn only_numbers_mask(s: &mut String) {
 
 fn validate_ok(app_params: &mut AppParamsInput) -> bool {
     if app_params.player_name.len() < 4 {
-        println!("Player name must use more than 4 characters");
+        warn!("Player name must use more than 4 characters");
         return false;
     }
     only_numbers_mask(&mut app_params.window_size_x);
     only_numbers_mask(&mut app_params.window_size_y);
     if app_params.window_size_x.len() < 3 {
-        println!("Screen with minimum 100.");
+        warn!("Screen with minimum 100.");
         return false;
     }
     if app_params.window_size_y.len() < 3 {
-        println!("Screen with minimum 100.");
+        warn!("Screen with minimum 100.");
         return false;
     }
     true
@@ -123,7 +138,7 @@ pub fn save_config(app_params: &AppParams) -> Result<(), String> {
     match do_write_config(&copy) {
         Ok(_) => Ok(()),
         Err(e) => {
-            println!("Failed to write config file. {}", e);
+            error!("Failed to write config file. {}", e);
             Err(e)
         }
     }
@@ -181,10 +196,10 @@ pub fn spawn_config_window(
                                 app_params.changed = false;
                                 ui.close_menu();
                             }
-                            Err(e) => println!("Failed to write config file. {}", e),
+                            Err(e) => error!("Failed to write config file. {}", e),
                         }
                     } else {
-                        println!("Validation failed.");
+                        warn!("Validation failed.");
                     }
                 }
 
@@ -194,7 +209,7 @@ pub fn spawn_config_window(
                             next_state.set(AppState::Game);
                             ui.close_menu();
                         } else {
-                            println!("Player name is a minumum of 4 characters.");
+                            warn!("Player name is a minumum of 4 characters.");
                         }
                     }
                 }
@@ -202,24 +217,52 @@ pub fn spawn_config_window(
         });
 }
 
+//The connection state shown in the server window.
+type ConnectionParams<'w> = (
+    ResMut<'w, DisconnectReason>,
+    ResMut<'w, Reconnect>,
+    Res<'w, State<MultiplayerState>>,
+    Option<ResMut<'w, RenetClient>>,
+);
+
 pub fn spawn_server_window(
     mut contexts: EguiContexts,
     mut next_multiplayer: ResMut<NextState<MultiplayerState>>,
-    mut next_menu_item: ResMut<NextState<MenuItem>>,
     mut app_params: ResMut<AppParams>,
     mut commands: Commands,
+    (mut disconnect_reason, mut reconnect, multiplayer_state, mut r_client): ConnectionParams,
+    (mut lan_scan, mut status_poller, mut master_query): (
+        ResMut<LanScan>,
+        ResMut<StatusPoller>,
+        ResMut<MasterQuery>,
+    ),
 ) {
+    lan_scan.poll();
+    master_query.poll();
+    status_poller.refresh(
+        app_params
+            .server_list
+            .iter()
+            .map(|server| server.url.as_str()),
+    );
+    status_poller.poll();
     bevy_egui::egui::Window::new("Game Servers")
         .collapsible(false)
         .default_pos(pos2(30.0, 50.0))
         .show(contexts.ctx_mut(), |ui| {
+            if let Some(reason) = &disconnect_reason.0 {
+                ui.colored_label(Color32::LIGHT_RED, reason);
+                ui.separator();
+            }
             ui.horizontal(|ui| {
                 ui.label("Server Selection:");
             });
             let visuals = ui.visuals_mut();
             visuals.dark_mode = true;
             use egui_extras::{Column, TableBuilder};
-            let mut table = TableBuilder::new(ui).columns(Column::remainder(), 2);
+            let mut table = TableBuilder::new(ui)
+                .columns(Column::remainder(), 2)
+                .columns(Column::auto(), 4);
             table = table.sense(egui::Sense::click());
             table
                 .header(20.0, |mut header| {
@@ -229,6 +272,11 @@ pub fn spawn_server_window(
                     header.col(|ui| {
                         ui.strong("Server Connection");
                     });
+                    for title in ["Status", "Ping", "Players", "Map"] {
+                        header.col(|ui| {
+                            ui.strong(title);
+                        });
+                    }
                 })
                 .body(|mut body| {
                     let row_height = 18.0;
@@ -249,9 +297,10 @@ pub fn spawn_server_window(
                                 row.col(|ui| {
                                     ui.label(&game_server.url);
                                 });
+                                status_columns(&mut row, status_poller.status(&game_server.url));
                                 if row.response().clicked() {
                                     idx = row_index;
-                                    println!("selected");
+                                    debug!("selected");
                                 }
                             });
                         }
@@ -323,26 +372,51 @@ pub fn spawn_server_window(
                 });
             }
             ui.separator();
+            lan_servers(ui, &mut lan_scan, &mut app_params);
+            master_servers(ui, &mut master_query, &mut app_params);
 
-            if app_params.player_name.len() > 3 && !app_params.last_server_index < 0 {
+            if multiplayer_state.get() == &MultiplayerState::Connecting {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    match r_client.as_ref().and_then(|client| client.connecting_to()) {
+                        Some((addr, elapsed)) => ui.label(format!(
+                            "Connecting to {}… {}/{} s",
+                            addr,
+                            elapsed.as_secs(),
+                            CONNECT_TIMEOUT.as_secs()
+                        )),
+                        None => ui.label("Connecting…"),
+                    };
+                    if let Some(r_client) = r_client.as_mut() {
+                        if ui.button("Cancel").clicked() {
+                            reconnect.cancel();
+                            cancel_connecting(r_client, &mut commands, &mut next_multiplayer);
+                        }
+                    }
+                });
+            } else if app_params.player_name.len() > 3 && !app_params.last_server_index < 0 {
                 if ui.button("Connect").clicked() {
                     let mut r_client = RenetClient::new();
                     let connection = app_params
                         .server_list
                         .get(app_params.last_server_index as usize)
                         .expect("Server connection deleted???");
-                    let (sender, rx) = r_client.connect(&app_params.player_name, &connection.url);
-                    commands.insert_resource(r_client);
-                    commands.insert_resource(MultiplayerMessageSender { sender });
-                    let receiver = Mutex::new(rx);
-                    commands.insert_resource(MultiplayerMessageReceiver { receiver });
-                    next_multiplayer.set(MultiplayerState::Connecting);
-                    next_menu_item.set(MenuItem::None);
-                    ui.close_menu();
+                    match r_client.connect(&app_params.player_name, &connection.url) {
+                        Ok(channels) => {
+                            disconnect_reason.0 = None;
+                            reconnect.remember(&app_params.player_name, &connection.url);
+                            insert_connection(&mut commands, r_client, channels);
+                            next_multiplayer.set(MultiplayerState::Connecting);
+                        }
+                        Err(e) => {
+                            warn!("Connection failed. {}", e);
+                            disconnect_reason.0 = Some(e);
+                        }
+                    }
                 };
             } else {
                 if app_params.player_name.len() < 4 {
-                    println!(
+                    warn!(
                         "Configuration of 'Player Name' (minimum of 3 characters)\
                     required before connecting."
                     );
@@ -351,12 +425,140 @@ pub fn spawn_server_window(
         });
 }
 
+//The Status, Ping, Players and Map of a saved server.
+fn status_columns(row: &mut egui_extras::TableRow, status: &ServerStatus) {
+    let (status_text, color) = match status {
+        ServerStatus::Querying => ("...".to_string(), Color32::GRAY),
+        ServerStatus::Online { reply, .. } if reply.version != PROTOCOL_VERSION => {
+            (format!("Version {}", reply.version), Color32::LIGHT_RED)
+        }
+        ServerStatus::Online { reply, .. } if reply.players >= reply.max_players => {
+            ("Full".to_string(), Color32::YELLOW)
+        }
+        ServerStatus::Online { .. } => ("Online".to_string(), Color32::LIGHT_GREEN),
+        ServerStatus::Offline => ("Offline".to_string(), Color32::GRAY),
+        ServerStatus::InvalidAddress => ("Invalid address".to_string(), Color32::LIGHT_RED),
+    };
+    row.col(|ui| {
+        ui.colored_label(color, status_text);
+    });
+    let (ping, players, map) = match status {
+        ServerStatus::Online { reply, ping_ms } if reply.version == PROTOCOL_VERSION => (
+            format!("{} ms", ping_ms),
+            format!("{}/{}", reply.players, reply.max_players),
+            reply.map.clone(),
+        ),
+        ServerStatus::Online { ping_ms, .. } => {
+            (format!("{} ms", ping_ms), String::new(), String::new())
+        }
+        _ => (String::new(), String::new(), String::new()),
+    };
+    for text in [ping, players, map] {
+        row.col(|ui| {
+            ui.label(text);
+        });
+    }
+}
+
+//The servers found by a LAN scan, with a button to save them to the server list.
+fn lan_servers(ui: &mut Ui, lan_scan: &mut LanScan, app_params: &mut AppParams) {
+    ui.horizontal(|ui| {
+        let scan = ui.add_enabled(!lan_scan.is_scanning(), egui::Button::new("Scan LAN"));
+        if scan.clicked() {
+            if let Err(e) = lan_scan.start() {
+                warn!("LAN scan failed. {}", e);
+            }
+        }
+        if lan_scan.is_scanning() {
+            ui.spinner();
+        }
+    });
+    found_servers(ui, "LAN Server", &lan_scan.servers, app_params);
+}
+
+fn master_servers(ui: &mut Ui, master_query: &mut MasterQuery, app_params: &mut AppParams) {
+    if app_params.master_servers.is_empty() {
+        return;
+    }
+    ui.horizontal(|ui| {
+        let query = ui.add_enabled(
+            !master_query.is_querying(),
+            egui::Button::new("Master Servers"),
+        );
+        if query.clicked() {
+            master_query.start(&app_params.master_servers);
+        }
+        if master_query.is_querying() {
+            ui.spinner();
+        }
+    });
+    found_servers(ui, "Listed Server", &master_query.servers, app_params);
+}
+
+//The servers of a LAN scan or the master servers, with a button to save them to the list.
+fn found_servers(ui: &mut Ui, title: &str, servers: &[FoundServer], app_params: &mut AppParams) {
+    if servers.is_empty() {
+        return;
+    }
+    let mut save = None;
+    Grid::new(title).striped(true).show(ui, |ui| {
+        ui.strong(title);
+        ui.strong("Map");
+        ui.strong("Players");
+        ui.strong("Server Connection");
+        ui.end_row();
+        for server in servers.iter() {
+            let reply = &server.reply;
+            let url = server.addr.to_string();
+            if reply.version != PROTOCOL_VERSION {
+                ui.label("?");
+                ui.label("");
+                ui.label("");
+                ui.label(&url);
+                ui.colored_label(
+                    Color32::LIGHT_RED,
+                    format!("Protocol version {}", reply.version),
+                );
+            } else {
+                ui.label(&reply.name);
+                ui.label(&reply.map);
+                ui.label(format!("{}/{}", reply.players, reply.max_players));
+                ui.label(&url);
+                if app_params.server_list.iter().any(|saved| saved.url == url) {
+                    ui.label("Saved");
+                } else if ui
+                    .add_enabled(
+                        app_params.last_server_index >= 0,
+                        egui::Button::new("Save to list"),
+                    )
+                    .clicked()
+                {
+                    save = Some(Server {
+                        name: reply.name.clone(),
+                        url,
+                    });
+                }
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(server) = save {
+        app_params.server_list.push(server);
+        app_params.changed = true;
+        if save_config(app_params).is_ok() {
+            app_params.changed = false;
+        }
+    }
+    ui.separator();
+}
+
 pub fn spawn_player_window(
     mut contexts: EguiContexts,
     mut next_multiplayer: ResMut<NextState<MultiplayerState>>,
     mut next_menu_item: ResMut<NextState<MenuItem>>,
     app_params: Res<AppParams>,
     mut r_client: ResMut<RenetClient>,
+    (scoreboard, match_info): (Res<Scoreboard>, Res<MatchInfo>),
 ) {
     bevy_egui::egui::Window::new("Players Ingame")
         .collapsible(false)
@@ -366,6 +568,8 @@ pub fn spawn_player_window(
             ui.horizontal(|ui| {
                 ui.label("Players:");
             });
+            scoreboard_grid(ui, &scoreboard, &match_info);
+            ui.separator();
             if app_params.player_name.len() > 3 {
                 if !r_client.is_disconnected() {
                     if ui.button("Disconnect").clicked() {
@@ -375,11 +579,106 @@ pub fn spawn_player_window(
                     };
                 }
             } else {
-                println!("Configuration of 'Player Name' required before connecting.");
+                warn!("Configuration of 'Player Name' required before connecting.");
+            }
+        });
+}
+
+//The window closes once the player is in the game.
+fn close_server_window(mut next_menu_item: ResMut<NextState<MenuItem>>) {
+    next_menu_item.set(MenuItem::None);
+}
+
+//Shown over the game and the menus while reconnecting to a lost server.
+pub fn spawn_reconnect_window(
+    mut contexts: EguiContexts,
+    mut reconnect: ResMut<Reconnect>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    mut commands: Commands,
+    mut next_multiplayer: ResMut<NextState<MultiplayerState>>,
+    mut r_client: Option<ResMut<RenetClient>>,
+) {
+    bevy_egui::egui::Window::new("Connection Lost")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(Align2::CENTER_TOP, [0.0, 60.0])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(format!(
+                    "Reconnecting… ({}/{})",
+                    reconnect.attempt.max(1),
+                    RECONNECT_ATTEMPTS
+                ));
+            });
+            if ui.button("Cancel").clicked() {
+                //Forget the server and drop an attempt in progress, a pending token request
+                //is dropped with its receiver.
+                reconnect.cancel();
+                disconnect_reason.0 = Some("Connection lost.".to_string());
+                if let Some(r_client) = r_client.as_mut() {
+                    cancel_connecting(r_client, &mut commands, &mut next_multiplayer);
+                }
             }
         });
 }
 
+//Shown while Tab is held in game.
+pub fn spawn_scoreboard_window(
+    mut contexts: EguiContexts,
+    scoreboard: Res<Scoreboard>,
+    match_info: Res<MatchInfo>,
+) {
+    bevy_egui::egui::Window::new("Scoreboard")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(Align2::CENTER_TOP, [0.0, 60.0])
+        .show(contexts.ctx_mut(), |ui| {
+            scoreboard_grid(ui, &scoreboard, &match_info);
+        });
+}
+
+//The players from the server Scoreboard, with the team scores in a team mode.
+fn scoreboard_grid(ui: &mut Ui, scoreboard: &Scoreboard, match_info: &MatchInfo) {
+    if match_info.mode.is_some_and(|mode| mode.has_teams()) {
+        ui.horizontal(|ui| {
+            for team in Team::ALL {
+                ui.colored_label(
+                    team_color32(team),
+                    format!("{} {}", team.name(), match_info.team_frags[team.index()]),
+                );
+            }
+        });
+    }
+    Grid::new("scoreboard")
+        .striped(true)
+        .min_col_width(50.0)
+        .show(ui, |ui| {
+            for heading in ["Name", "Team", "Frags", "Deaths", "Ping"] {
+                ui.strong(heading);
+            }
+            ui.end_row();
+            for entry in scoreboard.entries.iter() {
+                ui.label(&entry.name);
+                match entry.team {
+                    Some(team) => ui.colored_label(team_color32(team), team.name()),
+                    None => ui.label(""),
+                };
+                ui.label(entry.frags.to_string());
+                ui.label(entry.deaths.to_string());
+                ui.label(format!("{} ms", entry.ping_ms));
+                ui.end_row();
+            }
+        });
+}
+
+fn team_color32(team: Team) -> Color32 {
+    match team {
+        Team::Red => Color32::from_rgb(200, 30, 20),
+        Team::Blue => Color32::from_rgb(20, 60, 200),
+    }
+}
+
 pub fn activate_camera(
     mut next_menu_item: ResMut<NextState<MenuItem>>,
     mut cameras: Query<(Entity, &mut Camera, Option<&ActiveCamera>)>,
@@ -0,0 +1,129 @@
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+use crate::input_n_state::AppParams;
+
+//Snapshots kept for each remote player.
+const MAX_SNAPSHOTS: usize = 32;
+
+//A server state of a remote player, time is the local receive time in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    pub time: f64,
+    pub tick: u32,
+    pub location: Vec3,
+    pub rotation: Quat,
+}
+
+//Remote players are rendered from their buffered server snapshots, delayed by the
+//interpolation delay so there is usually a snapshot either side of the render time.
+#[derive(Component, Default)]
+pub struct SnapshotBuffer {
+    pub snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    pub fn new(snapshot: Snapshot) -> SnapshotBuffer {
+        SnapshotBuffer {
+            snapshots: VecDeque::from([snapshot]),
+        }
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        //Drop out of order snapshots.
+        if let Some(last) = self.snapshots.back() {
+            if snapshot.time < last.time {
+                return;
+            }
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    //The location and rotation at render_time. Beyond the newest snapshot the movement is
+    //extrapolated for at most extrapolation_limit seconds.
+    pub fn sample(&mut self, render_time: f64, extrapolation_limit: f64) -> Option<(Vec3, Quat)> {
+        //Keep one snapshot older than the render time.
+        while self.snapshots.len() > 2 && self.snapshots[1].time <= render_time {
+            self.snapshots.pop_front();
+        }
+        let first = *self.snapshots.front()?;
+        if render_time <= first.time || self.snapshots.len() == 1 {
+            return Some((first.location, first.rotation));
+        }
+        let second = self.snapshots[1];
+        let span = second.time - first.time;
+        if span <= 0.0 {
+            return Some((second.location, second.rotation));
+        }
+        let latest_time = second.time + extrapolation_limit;
+        let t = ((render_time.min(latest_time) - first.time) / span) as f32;
+        if t <= 1.0 {
+            Some((
+                first.location.lerp(second.location, t),
+                first.rotation.slerp(second.rotation, t),
+            ))
+        } else {
+            //Late packets, continue the last movement.
+            Some((
+                first.location + (second.location - first.location) * t,
+                second.rotation,
+            ))
+        }
+    }
+}
+
+pub fn interpolate_remote_players(
+    time: Res<Time<Real>>,
+    app_params: Res<AppParams>,
+    mut players: Query<(&mut Transform, &mut SnapshotBuffer)>,
+) {
+    let render_time = time.elapsed_secs_f64() - app_params.interpolation_delay_ms as f64 / 1000.0;
+    let extrapolation_limit = app_params.extrapolation_limit_ms as f64 / 1000.0;
+    for (mut transform, mut buffer) in players.iter_mut() {
+        if let Some((location, rotation)) = buffer.sample(render_time, extrapolation_limit) {
+            transform.translation = location;
+            transform.rotation = rotation;
+        }
+    }
+}
+
+#[cfg(test)]
+fn snapshot(time: f64, x: f32) -> Snapshot {
+    Snapshot {
+        time,
+        tick: 0,
+        location: Vec3::new(x, 0., 0.),
+        rotation: Quat::IDENTITY,
+    }
+}
+
+#[test]
+fn test_snapshot_interpolation() {
+    let mut buffer = SnapshotBuffer::new(snapshot(1.0, 0.));
+    assert_eq!(buffer.sample(0.5, 0.1).unwrap().0.x, 0.);
+    buffer.push(snapshot(1.1, 1.));
+    buffer.push(snapshot(1.2, 2.));
+    //Out of order snapshot is ignored.
+    buffer.push(snapshot(1.15, 5.));
+    assert_eq!(buffer.snapshots.len(), 3);
+    let (location, _) = buffer.sample(1.05, 0.1).unwrap();
+    assert!((location.x - 0.5).abs() < 0.0001);
+    let (location, _) = buffer.sample(1.15, 0.1).unwrap();
+    assert!((location.x - 1.5).abs() < 0.0001);
+    //The snapshot older than the render time is dropped.
+    assert_eq!(buffer.snapshots.len(), 2);
+}
+
+#[test]
+fn test_snapshot_extrapolation() {
+    let mut buffer = SnapshotBuffer::new(snapshot(1.0, 0.));
+    buffer.push(snapshot(1.1, 1.));
+    let (location, _) = buffer.sample(1.15, 0.1).unwrap();
+    assert!((location.x - 1.5).abs() < 0.0001);
+    //Extrapolation stops at the limit.
+    let (location, _) = buffer.sample(2.0, 0.1).unwrap();
+    assert!((location.x - 2.0).abs() < 0.0001);
+}
@@ -1,6 +1,20 @@
+use crate::chat::ChatEvent;
 use crate::client::*;
+use crate::hud::{
+    player_active, DamageEvent, DiedEvent, MatchStateEvent, RespawnedEvent, ScoreEvent,
+    ScoreboardEvent, TeamScoreEvent,
+};
 use crate::input_n_state::*;
+use crate::protocol::{self, MultiplayerMessage};
+use crate::weapon::ShotEvent;
 use crate::*;
+use bevy::ecs::system::SystemParam;
+use interpolation::{interpolate_remote_players, Snapshot, SnapshotBuffer};
+use std::collections::VecDeque;
+use team::{request_team_change, tint_team_models, PlayerTeam, TeamChangeEvent};
+
+pub mod interpolation;
+pub mod team;
 
 #[derive(Event)]
 pub struct PlayerMovementEvent(pub Movement);
@@ -11,22 +25,101 @@ pub struct PlayerRotateEvent(pub Vec2);
 #[derive(serde::Deserialize, serde::Serialize, Resource)]
 pub struct MouseRotation(pub Vec2);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
 pub enum Movement {
-    Forward,
-    Back,
-    Left,
-    Right,
+    Forward = 1,
+    Back = 2,
+    Left = 3,
+    Right = 4,
+}
+impl Movement {
+    pub fn from_u8(value: u8) -> Option<Movement> {
+        match value {
+            1 => Some(Movement::Forward),
+            2 => Some(Movement::Back),
+            3 => Some(Movement::Left),
+            4 => Some(Movement::Right),
+            _ => None,
+        }
+    }
+}
+
+//Sequence number of the last movement input sent to the server.
+#[derive(Resource, Default)]
+pub struct InputSequence(pub u32);
+
+//Movement inputs applied locally and not yet acknowledged by the server.
+#[derive(Resource, Default)]
+pub struct PendingInputs(pub VecDeque<(u32, Movement, Vec2)>);
+
+impl PendingInputs {
+    pub fn push(&mut self, sequence: u32, movement: Movement, rotation: Vec2) {
+        if self.0.len() >= MAX_PENDING_INPUTS {
+            self.0.pop_front();
+        }
+        self.0.push_back((sequence, movement, rotation));
+    }
+
+    //Drop the inputs acknowledged by the server and replay the remainder from the
+    //authoritative server location. Returns the predicted location.
+    pub fn reconcile(&mut self, acknowledged: u32, server_location: Vec3) -> Vec3 {
+        self.0
+            .retain(|(sequence, _, _)| is_sequence_newer(*sequence, acknowledged));
+        self.0
+            .iter()
+            .fold(server_location, |location, (_, movement, rotation)| {
+                simulate_move(location, *rotation, *movement)
+            })
+    }
+}
+
+//Sequence numbers wrap, a is newer than b if it is less than half the range ahead.
+pub fn is_sequence_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
 }
 
 const MOUSE_SENSITIVITY: f32 = 0.001;
+//Minimum interval between MultiplayerMessage::Rotate messages.
+const ROTATE_SEND_INTERVAL_SECS: f64 = 0.05;
+//Distance travelled for one movement input.
+pub const MOVE_STEP: f32 = 1.0;
+//Size of the unacknowledged input ring buffer.
+const MAX_PENDING_INPUTS: usize = 64;
+
+//Camera rotation from the accumulated mouse rotation (yaw, pitch).
+pub fn look_rotation(rotation: Vec2) -> Quat {
+    let x_quat = Quat::from_axis_angle(Vec3::new(0., 1., 0.), rotation.x);
+    let y_quat = Quat::from_axis_angle(Vec3::new(1., 0., 0.), rotation.y);
+    x_quat * y_quat
+}
+
+//Remote player models only turn with the yaw, the pitch is the aim.
+pub fn body_rotation(rotation: Vec2) -> Quat {
+    Quat::from_rotation_y(rotation.x)
+}
+
+//The movement simulation shared by the client and the server.
+pub fn simulate_move(location: Vec3, rotation: Vec2, movement: Movement) -> Vec3 {
+    let transform = Transform::from_translation(location).with_rotation(look_rotation(rotation));
+    let dir = match movement {
+        Movement::Forward => transform.forward(),
+        Movement::Back => transform.back(),
+        Movement::Left => transform.left(),
+        Movement::Right => transform.right(),
+    };
+    location + *dir * MOVE_STEP
+}
 
 pub struct PlayersPlugin;
 impl Plugin for PlayersPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<PlayerMovementEvent>();
         app.add_event::<PlayerRotateEvent>();
+        app.add_event::<TeamChangeEvent>();
         app.insert_resource(MouseRotation(Vec2::ZERO));
+        app.init_resource::<InputSequence>();
+        app.init_resource::<PendingInputs>();
         app.add_systems(
             Update,
             update_world_from_server_messages.run_if(
@@ -37,9 +130,27 @@ impl Plugin for PlayersPlugin {
         );
         app.add_systems(
             Update,
-            keyboard_move_cmd.run_if(in_state(MultiplayerState::Connected)),
+            keyboard_move_cmd.run_if(in_state(MultiplayerState::Connected).and(player_active)),
+        );
+        app.add_systems(Update, mouse_move_cmd.run_if(player_active));
+        app.add_systems(
+            Update,
+            send_rotation
+                .after(mouse_move_cmd)
+                .run_if(in_state(MultiplayerState::Connected).and(player_active)),
+        );
+        app.add_systems(
+            Update,
+            interpolate_remote_players.after(update_world_from_server_messages),
+        );
+        app.add_systems(
+            Update,
+            request_team_change.run_if(in_state(MultiplayerState::Connected)),
+        );
+        app.add_systems(
+            Update,
+            tint_team_models.after(update_world_from_server_messages),
         );
-        app.add_systems(Update, mouse_move_cmd);
         app.add_systems(
             OnEnter(MultiplayerState::Connected),
             connect_first_person.run_if(resource_exists::<MultiplayerMessageReceiver>),
@@ -62,36 +173,59 @@ fn mouse_move_cmd(
     if total_mouse != Vec2::ZERO {
         mouse_rotation.0.x -= total_mouse.x * MOUSE_SENSITIVITY;
         mouse_rotation.0.y -= total_mouse.y * MOUSE_SENSITIVITY;
-        let x_quat = Quat::from_axis_angle(Vec3::new(0., 1., 0.), mouse_rotation.0.x);
-        let y_quat = Quat::from_axis_angle(Vec3::new(1., 0., 0.), mouse_rotation.0.y);
-        transform.rotation = x_quat * y_quat;
+        transform.rotation = look_rotation(mouse_rotation.0);
+    }
+}
+
+//Send the look rotation to the server when it changes, limited to one message per
+//ROTATE_SEND_INTERVAL_SECS.
+fn send_rotation(
+    time: Res<Time<Real>>,
+    mouse_rotation: Res<MouseRotation>,
+    mut last_sent: Local<(Vec2, f64)>,
+    sender: Res<MultiplayerMessageSender>,
+    r_client: Res<RenetClient>,
+) {
+    let now = time.elapsed_secs_f64();
+    if mouse_rotation.0 == last_sent.0 || now - last_sent.1 < ROTATE_SEND_INTERVAL_SECS {
+        return;
+    }
+    *last_sent = (mouse_rotation.0, now);
+    if let Err(e) = sender.sender.send(MultiplayerMessage::Rotate {
+        client_id: r_client.get_client_id(),
+        rotation: mouse_rotation.0,
+    }) {
+        warn!("Could not send MultiplayerMessage::Rotate. {}", e);
     }
 }
 
+//Movement is predicted locally and the input is sent to the server which owns the player
+//location. The input is kept until the server acknowledges it in MultiplayerMessage::Move.
 fn keyboard_move_cmd(
     mut player_movement: EventReader<PlayerMovementEvent>,
-    mut camera: Query<&mut Transform, With<ActiveCamera>>,
+    mut first_person: Query<&mut Transform, With<FirstPerson>>,
+    mouse_rotation: Res<MouseRotation>,
+    mut input_sequence: ResMut<InputSequence>,
+    mut pending_inputs: ResMut<PendingInputs>,
     sender: ResMut<MultiplayerMessageSender>,
     r_client: ResMut<RenetClient>,
 ) {
-    let mut transform = camera.get_single_mut().unwrap();
+    let mut transform = first_person.get_single_mut().unwrap();
 
     for mv in player_movement.read() {
-        println!("{:?}", mv.0);
-        let dir = match mv {
-            PlayerMovementEvent(Movement::Forward) => transform.forward(),
-            PlayerMovementEvent(Movement::Back) => transform.back(),
-            PlayerMovementEvent(Movement::Left) => transform.left(),
-            PlayerMovementEvent(Movement::Right) => transform.right(),
-        };
-        transform.translation += *dir;
+        trace!("{:?}", mv.0);
+        transform.translation = simulate_move(transform.translation, mouse_rotation.0, mv.0);
+        input_sequence.0 = input_sequence.0.wrapping_add(1);
+        pending_inputs.push(input_sequence.0, mv.0, mouse_rotation.0);
         sender
             .sender
-            .send(MultiplayerMessage::Move {
+            .send(MultiplayerMessage::Input {
                 client_id: r_client.get_client_id(),
-                location: transform.translation,
+                sequence: input_sequence.0,
+                movement: mv.0,
+                rotation: mouse_rotation.0,
             })
-            .expect("Could not send MultiplayerMessage::Move from keyboard");
+            .expect("Could not send MultiplayerMessage::Input from keyboard");
     }
 }
 
@@ -99,69 +233,124 @@ pub fn connect_first_person(
     mut commands: Commands,
     fp_entity_query: Query<Entity, With<FirstPerson>>,
     r_client: ResMut<RenetClient>,
+    mut input_sequence: ResMut<InputSequence>,
+    mut pending_inputs: ResMut<PendingInputs>,
+    mouse_rotation: Res<MouseRotation>,
+    sender: Res<MultiplayerMessageSender>,
 ) {
+    input_sequence.0 = 0;
+    pending_inputs.0.clear();
     let cid = r_client.get_client_id();
+    //The server starts with the current look rotation.
+    if let Err(e) = sender.sender.send(MultiplayerMessage::Rotate {
+        client_id: cid,
+        rotation: mouse_rotation.0,
+    }) {
+        warn!("Could not send MultiplayerMessage::Rotate. {}", e);
+    }
     match fp_entity_query.get_single() {
         Ok(entity_id) => {
             commands.entity(entity_id).insert(ClientId { id: cid });
-            println!("Added clientid to FirstPerson: {}", cid);
+            debug!("Added clientid to FirstPerson: {}", cid);
         }
         Err(e) => {
-            eprintln!("Error adding clientid to FirstPerson: {}", e);
+            error!("Error adding clientid to FirstPerson: {}", e);
         }
     }
 }
 
+//Events for the other plugins from the server messages.
+#[derive(SystemParam)]
+pub struct ServerMessageEvents<'w> {
+    shots: EventWriter<'w, ShotEvent>,
+    damage: EventWriter<'w, DamageEvent>,
+    died: EventWriter<'w, DiedEvent>,
+    respawned: EventWriter<'w, RespawnedEvent>,
+    match_state: EventWriter<'w, MatchStateEvent>,
+    scores: EventWriter<'w, ScoreEvent>,
+    team_scores: EventWriter<'w, TeamScoreEvent>,
+    pings: EventWriter<'w, PingEvent>,
+    scoreboard: EventWriter<'w, ScoreboardEvent>,
+    chat: EventWriter<'w, ChatEvent>,
+    disconnect_reason: ResMut<'w, DisconnectReason>,
+}
+
+type PlayerData = (
+    Entity,
+    &'static ClientId,
+    &'static mut Transform,
+    Option<&'static FirstPerson>,
+    Option<&'static mut SnapshotBuffer>,
+);
+
 pub fn update_world_from_server_messages(
     receiver: ResMut<MultiplayerMessageReceiver>,
+    mut pending_inputs: ResMut<PendingInputs>,
     mut commands: Commands,
     asset_server: Res<AssetServer>,
-    mut players: Query<(Entity, &ClientId, &mut Transform, Option<&FirstPerson>)>,
+    time: Res<Time<Real>>,
+    mut players: Query<PlayerData>,
+    mut events: ServerMessageEvents,
 ) {
     for message in receiver.receiver.lock().expect("").try_iter() {
         match message {
             MultiplayerMessage::Connect {
                 client_id,
                 location,
-                direction: _,
+                rotation,
                 name,
+                team,
             } => {
                 let mut is_spawned = false;
-                players.iter_mut().for_each(|(_entity, cid, mut transform, first_person)| {
+                players.iter_mut().for_each(|(entity, cid, mut transform, first_person, _)| {
                       if client_id == cid.id  {
                           is_spawned = true;
                           match first_person {
                               Some(_) => {
                                   transform.translation = location;
-                                  println!("Littleman connected and positioned.");
+                                  match team {
+                                      Some(team) => commands.entity(entity).insert(PlayerTeam(team)),
+                                      None => commands.entity(entity).remove::<PlayerTeam>(),
+                                  };
+                                  debug!("Littleman connected and positioned.");
                               }
                               None => {
-                                  eprintln!("Player is already spawned? //
+                                  error!("Player is already spawned? //
                                   Should only get a new player and it's id should not exist as an entity??.");
                               }
                           }
                       }
                   });
-                println!("received connect message for {}", name);
+                debug!("received connect message for {}", name);
                 if is_spawned == false {
-                    println!("spawn player {}", name);
-                    commands.spawn((
+                    info!("spawn player {}", name);
+                    let mut player = commands.spawn((
                         Name::new(name),
-                        Transform::from_translation(location),
+                        Transform::from_translation(location)
+                            .with_rotation(body_rotation(rotation)),
                         ClientId { id: client_id },
+                        SnapshotBuffer::new(Snapshot {
+                            time: time.elapsed_secs_f64(),
+                            tick: 0,
+                            location,
+                            rotation: body_rotation(rotation),
+                        }),
                         SceneRoot(
                             asset_server
                                 .load(GltfAssetLabel::Scene(0).from_asset("littleman1.glb")),
                         ),
                     ));
+                    if let Some(team) = team {
+                        player.insert(PlayerTeam(team));
+                    }
                 }
             }
             MultiplayerMessage::Disconnect { client_id } => {
-                players.iter_mut().for_each(|(entity, cid, _, first_person)| {
+                players.iter_mut().for_each(|(entity, cid, _, first_person, _)| {
                     if client_id == cid.id  {
                         match first_person {
                             Some(_) => {
-                                println!("Client disconnect initiated by the server, a timeout?  cid:{}",
+                                info!("Client disconnect initiated by the server, a timeout?  cid:{}",
                                     client_id);
                                 let finish_disconnect_cmd = commands.
                                     register_system(client::do_finish_disconnect);
@@ -169,7 +358,7 @@ pub fn update_world_from_server_messages(
                             }
                             None => {
                                 //remove the disconnected player.
-                                println!("disconnect player cid:{}", client_id);
+                                info!("disconnect player cid:{}", client_id);
                                 commands.entity(entity).despawn_recursive();
                             }
                         }
@@ -178,25 +367,261 @@ pub fn update_world_from_server_messages(
             }
             MultiplayerMessage::Move {
                 client_id,
+                tick,
+                sequence,
                 location,
+                rotation,
             } => {
-                players.iter_mut().for_each(|(_entity, cid, mut transform, first_person)| {
-                    if client_id == cid.id  {
-                        match first_person {
-                            Some(_) => {
-                                eprintln!("Disconnect is not propagated by the payload layer?? cid:{}", client_id);
+                players.iter_mut().for_each(
+                    |(_entity, cid, mut transform, first_person, snapshot_buffer)| {
+                        if client_id == cid.id {
+                            match (first_person, snapshot_buffer) {
+                                (Some(_), _) => {
+                                    //Rewind to the server location and replay the pending inputs.
+                                    transform.translation =
+                                        pending_inputs.reconcile(sequence, location);
+                                }
+                                (None, Some(mut buffer)) => {
+                                    //Rendered by interpolate_remote_players.
+                                    buffer.push(Snapshot {
+                                        time: time.elapsed_secs_f64(),
+                                        tick,
+                                        location,
+                                        rotation: body_rotation(rotation),
+                                    });
+                                }
+                                (None, None) => {
+                                    //move the player.
+                                    transform.translation = location;
+                                    transform.rotation = body_rotation(rotation);
+                                }
                             }
-                            None => {
-                                //move the player.
-                                transform.translation = location;
+                        }
+                    },
+                );
+            }
+            MultiplayerMessage::Input { client_id, .. } => {
+                warn!(
+                    "Received MultiplayerMessage::Input from the server? cid:{}",
+                    client_id
+                );
+            }
+            MultiplayerMessage::Hit {
+                shooter_id,
+                origin,
+                point,
+                target_id,
+            } => {
+                let own_shot = players.iter().any(|(_, cid, _, first_person, _)| {
+                    first_person.is_some() && cid.id == shooter_id
+                });
+                if !own_shot {
+                    events.shots.send(ShotEvent {
+                        origin,
+                        point,
+                        hit_confirmed: false,
+                    });
+                } else if target_id.is_some() {
+                    events.shots.send(ShotEvent {
+                        origin,
+                        point,
+                        hit_confirmed: true,
+                    });
+                }
+            }
+            MultiplayerMessage::Damage {
+                client_id: _,
+                attacker_id,
+                amount,
+                health,
+                armor,
+            } => {
+                events.damage.send(DamageEvent {
+                    attacker_id,
+                    amount,
+                    health,
+                    armor,
+                });
+            }
+            MultiplayerMessage::Died {
+                client_id,
+                attacker_id,
+                respawn_secs,
+            } => {
+                for (entity, cid, transform, first_person, _) in players.iter() {
+                    if client_id != cid.id {
+                        continue;
+                    }
+                    match first_person {
+                        Some(_) => {
+                            pending_inputs.0.clear();
+                            events.died.send(DiedEvent {
+                                attacker_id,
+                                respawn_secs,
+                                location: transform.translation,
+                            });
+                        }
+                        None => {
+                            commands.entity(entity).insert(Visibility::Hidden);
+                        }
+                    }
+                }
+            }
+            MultiplayerMessage::Respawned {
+                client_id,
+                location,
+                health,
+                armor,
+            } => {
+                for (entity, cid, mut transform, first_person, snapshot_buffer) in
+                    players.iter_mut()
+                {
+                    if client_id != cid.id {
+                        continue;
+                    }
+                    transform.translation = location;
+                    match (first_person, snapshot_buffer) {
+                        (Some(_), _) => {
+                            pending_inputs.0.clear();
+                            events.respawned.send(RespawnedEvent { health, armor });
+                        }
+                        (None, snapshot_buffer) => {
+                            //Do not interpolate from the death location.
+                            if let Some(mut buffer) = snapshot_buffer {
+                                *buffer = SnapshotBuffer::new(Snapshot {
+                                    time: time.elapsed_secs_f64(),
+                                    tick: 0,
+                                    location,
+                                    rotation: transform.rotation,
+                                });
                             }
+                            commands.entity(entity).insert(Visibility::Visible);
                         }
                     }
+                }
+            }
+            MultiplayerMessage::Fire { client_id, .. } => {
+                warn!(
+                    "Received MultiplayerMessage::Fire from the server? cid:{}",
+                    client_id
+                );
+            }
+            MultiplayerMessage::Rotate { client_id, .. } => {
+                warn!(
+                    "Received MultiplayerMessage::Rotate from the server? cid:{}",
+                    client_id
+                );
+            }
+            MultiplayerMessage::Hello {} => {
+                warn!("Received MultiplayerMessage::Hello from the server?");
+            }
+            MultiplayerMessage::MatchState {
+                mode,
+                playing,
+                remaining_secs,
+                frag_limit,
+                winner_id,
+                winning_team,
+            } => {
+                events.match_state.send(MatchStateEvent {
+                    mode,
+                    playing,
+                    remaining_secs,
+                    frag_limit,
+                    winner_id,
+                    winning_team,
+                });
+            }
+            MultiplayerMessage::Score {
+                client_id,
+                frags,
+                deaths,
+            } => {
+                events.scores.send(ScoreEvent {
+                    client_id,
+                    frags,
+                    deaths,
+                });
+            }
+            MultiplayerMessage::TeamAssigned { client_id, team } => {
+                for (entity, cid, ..) in players.iter() {
+                    if cid.id == client_id {
+                        commands.entity(entity).insert(PlayerTeam(team));
+                    }
+                }
+            }
+            MultiplayerMessage::TeamScore { team, frags } => {
+                events.team_scores.send(TeamScoreEvent { team, frags });
+            }
+            MultiplayerMessage::Ping { sequence } => {
+                events.pings.send(PingEvent(sequence));
+            }
+            MultiplayerMessage::Scoreboard { entries } => {
+                events.scoreboard.send(ScoreboardEvent(entries));
+            }
+            MultiplayerMessage::Chat {
+                client_id,
+                team_only,
+                text,
+            } => {
+                events.chat.send(ChatEvent {
+                    client_id,
+                    team_only,
+                    text,
                 });
             }
-            MultiplayerMessage::None => {
-                println!("Received Multiplayer::None?");
+            MultiplayerMessage::Pong { client_id, .. } => {
+                warn!(
+                    "Received MultiplayerMessage::Pong from the server? cid:{}",
+                    client_id
+                );
+            }
+            MultiplayerMessage::ChangeTeam { client_id, .. } => {
+                warn!(
+                    "Received MultiplayerMessage::ChangeTeam from the server? cid:{}",
+                    client_id
+                );
+            }
+            MultiplayerMessage::Kicked { reason } => {
+                //The server disconnects the client after this message.
+                warn!("Disconnected by the server. {}", reason);
+                events.disconnect_reason.0 = Some(reason);
+            }
+            MultiplayerMessage::VersionMismatch { server_version } => {
+                //The server disconnects the client after this message.
+                let reason = format!(
+                    "Connection refused, the server protocol version is {} and this client is {}. \
+                    Update the game to play on this server.",
+                    server_version,
+                    protocol::PROTOCOL_VERSION
+                );
+                warn!("{}", reason);
+                events.disconnect_reason.0 = Some(reason);
             }
         }
     }
 }
+
+#[test]
+fn test_pending_inputs_reconcile() {
+    let mut pending_inputs = PendingInputs::default();
+    for sequence in 1..=4 {
+        pending_inputs.push(sequence, Movement::Right, Vec2::ZERO);
+    }
+    //The server processed 2 inputs, 2 are replayed from the server location.
+    let location = pending_inputs.reconcile(2, Vec3::new(2., 0., 0.));
+    assert_eq!(location, Vec3::new(4., 0., 0.));
+    assert_eq!(pending_inputs.0.len(), 2);
+    //All inputs acknowledged.
+    let location = pending_inputs.reconcile(4, Vec3::new(5., 0., 0.));
+    assert_eq!(location, Vec3::new(5., 0., 0.));
+    assert!(pending_inputs.0.is_empty());
+}
+
+#[test]
+fn test_is_sequence_newer() {
+    assert!(is_sequence_newer(2, 1));
+    assert!(!is_sequence_newer(1, 1));
+    assert!(!is_sequence_newer(1, 2));
+    assert!(is_sequence_newer(1, u32::MAX));
+}
@@ -0,0 +1,90 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use crate::client::ServerConnection;
+use crate::protocol::MultiplayerMessage;
+use crate::server::game_mode::Team;
+use crate::FirstPerson;
+
+//Ask the server to move the local player to the other team.
+#[derive(Event)]
+pub struct TeamChangeEvent;
+
+#[derive(Component, Clone, Copy, PartialEq)]
+pub struct PlayerTeam(pub Team);
+
+//A model mesh tinted with the team color, keeping the model material for a team change.
+#[derive(Component)]
+pub struct TeamTint {
+    team: Team,
+    original: Handle<StandardMaterial>,
+}
+
+//Team colored copies of the model materials.
+type TintedMaterials = HashMap<(AssetId<StandardMaterial>, Team), Handle<StandardMaterial>>;
+
+pub fn team_color(team: Team) -> Color {
+    match team {
+        Team::Red => Color::srgb(0.9, 0.15, 0.1),
+        Team::Blue => Color::srgb(0.1, 0.3, 0.9),
+    }
+}
+
+pub fn request_team_change(
+    mut team_change: EventReader<TeamChangeEvent>,
+    first_person: Query<&PlayerTeam, With<FirstPerson>>,
+    connection: ServerConnection,
+) {
+    for TeamChangeEvent in team_change.read() {
+        //No team without a team mode.
+        let Ok(PlayerTeam(team)) = first_person.get_single() else {
+            continue;
+        };
+        connection.send(MultiplayerMessage::ChangeTeam {
+            client_id: connection.client_id(),
+            team: team.other(),
+        });
+    }
+}
+
+//Replace the materials of the littleman1.glb meshes of a player with team colored copies. The
+//scene meshes appear after the model loads, so the players are checked every frame.
+pub fn tint_team_models(
+    mut commands: Commands,
+    players: Query<(Entity, &PlayerTeam)>,
+    children: Query<&Children>,
+    mut meshes: Query<(&mut MeshMaterial3d<StandardMaterial>, Option<&TeamTint>)>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut tinted: Local<TintedMaterials>,
+) {
+    for (entity, PlayerTeam(team)) in players.iter() {
+        for descendant in children.iter_descendants(entity) {
+            let Ok((mut material, tint)) = meshes.get_mut(descendant) else {
+                continue;
+            };
+            let original = match tint {
+                Some(tint) if tint.team == *team => continue,
+                Some(tint) => tint.original.clone(),
+                None => material.0.clone(),
+            };
+            let key = (original.id(), *team);
+            let handle = match tinted.get(&key) {
+                Some(handle) => handle.clone(),
+                None => {
+                    let Some(mut team_material) = materials.get(&original).cloned() else {
+                        continue;
+                    };
+                    team_material.base_color = team_color(*team);
+                    let handle = materials.add(team_material);
+                    tinted.insert(key, handle.clone());
+                    handle
+                }
+            };
+            material.0 = handle;
+            commands.entity(descendant).insert(TeamTint {
+                team: *team,
+                original,
+            });
+        }
+    }
+}
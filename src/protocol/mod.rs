@@ -0,0 +1,618 @@
+use bevy::prelude::*;
+use std::io::{Cursor, Error, ErrorKind, Read};
+
+use crate::players::Movement;
+use crate::server::game_mode::{GameMode, Team};
+
+//Incremented whenever the encoding of a message changes.
+pub const PROTOCOL_VERSION: u16 = 10;
+//version: u16, message id: u8, payload length: u16
+pub const HEADER_BYTES: usize = 5;
+//Encoded messages must fit a netcode payload.
+pub const MAX_MESSAGE_BYTES: usize = 1200;
+
+//Every message is framed by a header:
+//  [protocol version u16][message id u8][payload length u16][payload]
+//All values are little endian. Strings and vectors are prefixed by a u16 length.
+pub struct Header {
+    pub version: u16,
+    pub id: u8,
+    pub len: u16,
+}
+
+impl Header {
+    pub fn read(buf: &[u8]) -> Result<Header, Error> {
+        let cursor = &mut Cursor::new(buf);
+        Ok(Header {
+            version: u16::read_from(cursor)?,
+            id: u8::read_from(cursor)?,
+            len: u16::read_from(cursor)?,
+        })
+    }
+}
+
+//Encoding of a message field.
+pub trait WireFormat: Sized {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error>;
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error>;
+}
+
+#[inline]
+pub fn read_bytes<const N: usize>(src: &mut impl std::io::Read) -> Result<[u8; N], Error> {
+    let mut data = [0u8; N];
+    src.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+macro_rules! wire_format_number {
+    ($($ty:ty),*) => {
+        $(
+            impl WireFormat for $ty {
+                fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+                    buf.extend_from_slice(&self.to_le_bytes());
+                    Ok(())
+                }
+
+                fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+                    Ok(<$ty>::from_le_bytes(read_bytes::<{ size_of::<$ty>() }>(cursor)?))
+                }
+            }
+        )*
+    };
+}
+
+wire_format_number!(u8, u16, u32, u64, i32);
+
+//NaN and infinite values are refused, they would spread through the simulation.
+impl WireFormat for f32 {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        buf.extend_from_slice(&self.to_le_bytes());
+        Ok(())
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let value = f32::from_le_bytes(read_bytes::<4>(cursor)?);
+        if !value.is_finite() {
+            return Err(invalid_data(format!("Invalid number {}", value)));
+        }
+        Ok(value)
+    }
+}
+
+impl WireFormat for bool {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        (*self as u8).write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        match u8::read_from(cursor)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(invalid_data(format!("Invalid bool {}", value))),
+        }
+    }
+}
+
+impl WireFormat for Vec2 {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        self.x.write_to(buf)?;
+        self.y.write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        Ok(Vec2::new(f32::read_from(cursor)?, f32::read_from(cursor)?))
+    }
+}
+
+impl WireFormat for Vec3 {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        self.x.write_to(buf)?;
+        self.y.write_to(buf)?;
+        self.z.write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        Ok(Vec3::new(
+            f32::read_from(cursor)?,
+            f32::read_from(cursor)?,
+            f32::read_from(cursor)?,
+        ))
+    }
+}
+
+fn write_len(len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let len: u16 = len
+        .try_into()
+        .map_err(|_| invalid_data(format!("Length {} is too long to encode", len)))?;
+    len.write_to(buf)
+}
+
+//Lengths are checked against the remaining bytes before allocating.
+fn read_len(cursor: &mut Cursor<&[u8]>) -> Result<usize, Error> {
+    let len = u16::read_from(cursor)? as usize;
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    if len > remaining {
+        return Err(invalid_data(format!(
+            "Length {} exceeds the remaining {} bytes",
+            len, remaining
+        )));
+    }
+    Ok(len)
+}
+
+impl WireFormat for String {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        write_len(self.len(), buf)?;
+        buf.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let mut bytes = vec![0u8; read_len(cursor)?];
+        cursor.read_exact(&mut bytes)?;
+        String::from_utf8(bytes).map_err(|e| invalid_data(format!("Invalid string. {}", e)))
+    }
+}
+
+impl<T: WireFormat> WireFormat for Vec<T> {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        write_len(self.len(), buf)?;
+        for item in self.iter() {
+            item.write_to(buf)?;
+        }
+        Ok(())
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let len = read_len(cursor)?;
+        let mut items = Vec::with_capacity(len);
+        for _ in 0..len {
+            items.push(T::read_from(cursor)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: WireFormat> WireFormat for Option<T> {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        match self {
+            Some(value) => {
+                true.write_to(buf)?;
+                value.write_to(buf)
+            }
+            None => false.write_to(buf),
+        }
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        if bool::read_from(cursor)? {
+            Ok(Some(T::read_from(cursor)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl WireFormat for Movement {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        (*self as u8).write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let value = u8::read_from(cursor)?;
+        Movement::from_u8(value).ok_or(invalid_data(format!("Invalid movement {}", value)))
+    }
+}
+
+impl WireFormat for GameMode {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        (*self as u8).write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let value = u8::read_from(cursor)?;
+        GameMode::from_u8(value).ok_or(invalid_data(format!("Invalid game mode {}", value)))
+    }
+}
+
+impl WireFormat for Team {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        (*self as u8).write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        let value = u8::read_from(cursor)?;
+        Team::from_u8(value).ok_or(invalid_data(format!("Invalid team {}", value)))
+    }
+}
+
+//A player row of the Scoreboard message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScoreboardEntry {
+    pub client_id: u64,
+    pub name: String,
+    pub team: Option<Team>,
+    pub frags: i32,
+    pub deaths: u32,
+    pub ping_ms: u16,
+}
+
+impl WireFormat for ScoreboardEntry {
+    fn write_to(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+        self.client_id.write_to(buf)?;
+        self.name.write_to(buf)?;
+        self.team.write_to(buf)?;
+        self.frags.write_to(buf)?;
+        self.deaths.write_to(buf)?;
+        self.ping_ms.write_to(buf)
+    }
+
+    fn read_from(cursor: &mut Cursor<&[u8]>) -> Result<Self, Error> {
+        Ok(ScoreboardEntry {
+            client_id: u64::read_from(cursor)?,
+            name: String::read_from(cursor)?,
+            team: Option::<Team>::read_from(cursor)?,
+            frags: i32::read_from(cursor)?,
+            deaths: u32::read_from(cursor)?,
+            ping_ms: u16::read_from(cursor)?,
+        })
+    }
+}
+
+//Declares MultiplayerMessage with the id of each variant and generates the codec, the fields
+//are encoded in the declared order. Adding a message only requires a new entry here.
+macro_rules! multiplayer_messages {
+    ($($(#[$meta:meta])* $variant:ident = $id:literal { $($field:ident: $ty:ty),* $(,)? }),* $(,)?) => {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum MultiplayerMessage {
+            $($(#[$meta])* $variant { $($field: $ty),* },)*
+        }
+
+        impl MultiplayerMessage {
+            pub fn get_id(&self) -> u8 {
+                match self {
+                    $(MultiplayerMessage::$variant { .. } => $id,)*
+                }
+            }
+
+            fn write_payload(&self, buf: &mut Vec<u8>) -> Result<(), Error> {
+                match self {
+                    $(MultiplayerMessage::$variant { $($field),* } => {
+                        $($field.write_to(buf)?;)*
+                    })*
+                }
+                Ok(())
+            }
+
+            fn read_payload(id: u8, cursor: &mut Cursor<&[u8]>) -> Result<MultiplayerMessage, Error> {
+                match id {
+                    $($id => Ok(MultiplayerMessage::$variant {
+                        $($field: <$ty as WireFormat>::read_from(cursor)?),*
+                    }),)*
+                    _ => Err(invalid_data(format!("Unknown message id {}", id))),
+                }
+            }
+        }
+    };
+}
+
+multiplayer_messages! {
+    //team is None without a team mode.
+    Connect = 1 {
+        client_id: u64,
+        location: Vec3,
+        rotation: Vec2,
+        name: String,
+        team: Option<Team>,
+    },
+    Disconnect = 2 { client_id: u64 },
+    //The authoritative player location at the server tick, sequence is the last input
+    //processed for the player.
+    Move = 3 {
+        client_id: u64,
+        tick: u32,
+        sequence: u32,
+        location: Vec3,
+        rotation: Vec2,
+    },
+    //Movement input from a client, the server simulates the move.
+    Input = 4 {
+        client_id: u64,
+        sequence: u32,
+        movement: Movement,
+        rotation: Vec2,
+    },
+    //Look rotation (yaw, pitch) from a client, sent when the player looks around.
+    Rotate = 6 {
+        client_id: u64,
+        rotation: Vec2,
+    },
+    //A shot from a client along direction. tick is the newest server tick the client received,
+    //the remote players are rendered interpolation_delay_ms behind it.
+    Fire = 7 {
+        client_id: u64,
+        tick: u32,
+        interpolation_delay_ms: u16,
+        origin: Vec3,
+        direction: Vec3,
+    },
+    //The server result of a shot, target_id is None for a miss or a team mate hit without
+    //friendly fire.
+    Hit = 8 {
+        shooter_id: u64,
+        origin: Vec3,
+        point: Vec3,
+        target_id: Option<u64>,
+    },
+    //Sent to the player hit by a shot, with the remaining health and armor.
+    Damage = 9 {
+        client_id: u64,
+        attacker_id: u64,
+        amount: u32,
+        health: u32,
+        armor: u32,
+    },
+    Died = 10 {
+        client_id: u64,
+        attacker_id: u64,
+        respawn_secs: f32,
+    },
+    Respawned = 11 {
+        client_id: u64,
+        location: Vec3,
+        health: u32,
+        armor: u32,
+    },
+    //The match phase, sent when it changes. remaining_secs is the time left in the match or
+    //the intermission, winner_id and winning_team are the winners during the intermission.
+    MatchState = 12 {
+        mode: GameMode,
+        playing: bool,
+        remaining_secs: f32,
+        frag_limit: u32,
+        winner_id: Option<u64>,
+        winning_team: Option<Team>,
+    },
+    Score = 13 {
+        client_id: u64,
+        frags: i32,
+        deaths: u32,
+    },
+    //The team of a player after joining a team or a team change.
+    TeamAssigned = 14 {
+        client_id: u64,
+        team: Team,
+    },
+    //A team change request from a client.
+    ChangeTeam = 15 {
+        client_id: u64,
+        team: Team,
+    },
+    TeamScore = 16 {
+        team: Team,
+        frags: i32,
+    },
+    //Round trip time measurement, the client answers a Ping with a Pong of the same sequence.
+    Ping = 17 { sequence: u32 },
+    Pong = 18 {
+        client_id: u64,
+        sequence: u32,
+    },
+    //Every connected player, sent periodically.
+    Scoreboard = 19 { entries: Vec<ScoreboardEntry> },
+    //Chat text from a client, relayed by the server with the sender id. team_only chat goes to
+    //the team mates of the sender.
+    Chat = 20 {
+        client_id: u64,
+        team_only: bool,
+        text: String,
+    },
+    //Why the server disconnects a client: a kick, a ban or a shutdown.
+    Kicked = 21 { reason: String },
+    //First message from a client, the server only adds the player after the handshake.
+    Hello = 5 {},
+    //Sent to a client with a different protocol version before it is disconnected. The
+    //encoding of this message must not change between versions.
+    VersionMismatch = 255 { server_version: u16 },
+}
+
+impl MultiplayerMessage {
+    pub fn get_buf(&self) -> Result<Vec<u8>, Error> {
+        let mut buf: Vec<u8> = Vec::with_capacity(64);
+        PROTOCOL_VERSION.write_to(&mut buf)?;
+        self.get_id().write_to(&mut buf)?;
+        //Payload length placeholder.
+        0u16.write_to(&mut buf)?;
+        self.write_payload(&mut buf)?;
+        if buf.len() > MAX_MESSAGE_BYTES {
+            return Err(invalid_data(format!(
+                "Message {} is {} bytes, the maximum is {}",
+                self.get_id(),
+                buf.len(),
+                MAX_MESSAGE_BYTES
+            )));
+        }
+        let len = (buf.len() - HEADER_BYTES) as u16;
+        buf[3..HEADER_BYTES].copy_from_slice(&len.to_le_bytes());
+        Ok(buf)
+    }
+
+    pub fn get(buf: &[u8]) -> Result<MultiplayerMessage, Error> {
+        let header = Header::read(buf)?;
+        let payload_len = buf.len() - HEADER_BYTES;
+        if header.len as usize != payload_len {
+            return Err(invalid_data(format!(
+                "Message length {} does not match the payload length {}",
+                header.len, payload_len
+            )));
+        }
+        let version_mismatch_id =
+            MultiplayerMessage::VersionMismatch { server_version: 0 }.get_id();
+        if header.version != PROTOCOL_VERSION && header.id != version_mismatch_id {
+            return Err(invalid_data(format!(
+                "Protocol version {} does not match {}",
+                header.version, PROTOCOL_VERSION
+            )));
+        }
+        let cursor = &mut Cursor::new(buf);
+        cursor.set_position(HEADER_BYTES as u64);
+        let message = MultiplayerMessage::read_payload(header.id, cursor)?;
+        if cursor.position() as usize != buf.len() {
+            return Err(invalid_data(format!(
+                "Message {} has {} unread bytes",
+                header.id,
+                buf.len() - cursor.position() as usize
+            )));
+        }
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header() {
+        let buf = MultiplayerMessage::Disconnect { client_id: 7 }
+            .get_buf()
+            .unwrap();
+        let header = Header::read(&buf).unwrap();
+        assert_eq!(header.version, PROTOCOL_VERSION);
+        assert_eq!(header.id, 2);
+        assert_eq!(header.len as usize, 8);
+        assert_eq!(buf.len(), HEADER_BYTES + 8);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let messages = vec![
+            MultiplayerMessage::Hello {},
+            MultiplayerMessage::VersionMismatch { server_version: 3 },
+            MultiplayerMessage::Connect {
+                client_id: 1,
+                location: Vec3::new(1., 2., 3.),
+                rotation: Vec2::ZERO,
+                name:
+                    "a much longer player name than one hundred bytes would allow in the old fixed \
+                    size message buffer"
+                        .to_string(),
+                team: Some(Team::Blue),
+            },
+            MultiplayerMessage::TeamScore {
+                team: Team::Red,
+                frags: -2,
+            },
+            MultiplayerMessage::Kicked {
+                reason: "You are banned from the server.".to_string(),
+            },
+            MultiplayerMessage::Scoreboard {
+                entries: vec![
+                    ScoreboardEntry {
+                        client_id: 1,
+                        name: "one".to_string(),
+                        team: None,
+                        frags: -1,
+                        deaths: 3,
+                        ping_ms: 45,
+                    },
+                    ScoreboardEntry {
+                        client_id: 2,
+                        name: "two".to_string(),
+                        team: Some(Team::Blue),
+                        frags: 10,
+                        deaths: 0,
+                        ping_ms: 120,
+                    },
+                ],
+            },
+        ];
+        for message in messages {
+            let buf = message.get_buf().unwrap();
+            assert_eq!(MultiplayerMessage::get(&buf).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn test_invalid_messages() {
+        let buf = MultiplayerMessage::Disconnect { client_id: 7 }
+            .get_buf()
+            .unwrap();
+        //Truncated.
+        assert!(MultiplayerMessage::get(&buf[..buf.len() - 1]).is_err());
+        assert!(MultiplayerMessage::get(&buf[..2]).is_err());
+        //Trailing bytes.
+        let mut long = buf.clone();
+        long.push(0);
+        assert!(MultiplayerMessage::get(&long).is_err());
+        //Unknown message id.
+        let mut unknown = buf.clone();
+        unknown[2] = 200;
+        assert!(MultiplayerMessage::get(&unknown).is_err());
+        //Protocol version mismatch.
+        let mut version = buf.clone();
+        version[0..2].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        assert!(MultiplayerMessage::get(&version).is_err());
+        //Invalid utf8 name.
+        let mut name = MultiplayerMessage::Connect {
+            client_id: 1,
+            location: Vec3::ZERO,
+            rotation: Vec2::ZERO,
+            name: "ab".to_string(),
+            team: None,
+        }
+        .get_buf()
+        .unwrap();
+        let len = name.len();
+        //The name is followed by the team.
+        name[len - 2] = 0xff;
+        assert!(MultiplayerMessage::get(&name).is_err());
+        //Invalid team.
+        let mut team = MultiplayerMessage::TeamScore {
+            team: Team::Red,
+            frags: 0,
+        }
+        .get_buf()
+        .unwrap();
+        team[HEADER_BYTES] = 3;
+        assert!(MultiplayerMessage::get(&team).is_err());
+        //Not a finite number.
+        for value in [f32::NAN, f32::INFINITY] {
+            let rotate = MultiplayerMessage::Rotate {
+                client_id: 1,
+                rotation: Vec2::new(0.0, value),
+            }
+            .get_buf()
+            .unwrap();
+            assert!(MultiplayerMessage::get(&rotate).is_err());
+        }
+    }
+
+    #[test]
+    fn test_version_mismatch_decodes_across_versions() {
+        let mut buf = MultiplayerMessage::VersionMismatch { server_version: 9 }
+            .get_buf()
+            .unwrap();
+        buf[0..2].copy_from_slice(&9u16.to_le_bytes());
+        assert_eq!(
+            MultiplayerMessage::get(&buf).unwrap(),
+            MultiplayerMessage::VersionMismatch { server_version: 9 }
+        );
+    }
+
+    #[test]
+    fn test_message_too_long() {
+        let message = MultiplayerMessage::Connect {
+            client_id: 1,
+            location: Vec3::ZERO,
+            rotation: Vec2::ZERO,
+            name: "x".repeat(MAX_MESSAGE_BYTES),
+            team: None,
+        };
+        assert!(message.get_buf().is_err());
+    }
+}
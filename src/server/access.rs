@@ -0,0 +1,325 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::config::{get_file, get_shooter_file_path};
+
+//The ban and allow lists are kept next to the server config.
+pub const ACCESS_FILE: &str = "access.toml";
+
+//An address or a network like 10.0.0.0/8.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{} is not an address.", text))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or(format!("{} has an invalid prefix length.", text))?,
+            None => max_prefix,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        //IPv4 clients of a dual stack socket have mapped IPv6 addresses.
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => same_prefix(
+                u32::from(net).into(),
+                u32::from(addr).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                same_prefix(net.into(), addr.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let max_prefix = if self.addr.is_ipv4() { 32 } else { 128 };
+        if self.prefix == max_prefix {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+fn access_file_path(server_config: Option<&OsString>) -> Result<OsString, String> {
+    match server_config {
+        Some(config) => Ok(Path::new(config)
+            .with_file_name(ACCESS_FILE)
+            .into_os_string()),
+        None => get_shooter_file_path(ACCESS_FILE),
+    }
+}
+
+fn same_prefix(a: u128, b: u128, bits: u8, prefix: u8) -> bool {
+    prefix == 0 || a >> (bits - prefix) == b >> (bits - prefix)
+}
+
+//A rule matches a client when all of its fields match. Names are not case sensitive. Names
+//are not authenticated, any client can claim one, so only addr rules restrict who connects.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct AccessRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<u64>,
+    //An address or a CIDR network.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+    //Told to a banned client.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AccessRule {
+    //A rule for a console target, an address or network, a client id or else a player name.
+    pub fn from_target(target: &str) -> AccessRule {
+        if let Ok(cidr) = Cidr::parse(target) {
+            AccessRule {
+                addr: Some(cidr.to_string()),
+                ..AccessRule::default()
+            }
+        } else if let Ok(client_id) = target.parse::<u64>() {
+            AccessRule {
+                client_id: Some(client_id),
+                ..AccessRule::default()
+            }
+        } else {
+            AccessRule {
+                name: Some(target.to_string()),
+                ..AccessRule::default()
+            }
+        }
+    }
+
+    pub fn matches(&self, name: &str, client_id: u64, addr: IpAddr) -> bool {
+        if self.name.is_none() && self.client_id.is_none() && self.addr.is_none() {
+            return false;
+        }
+        self.name
+            .as_ref()
+            .is_none_or(|rule_name| rule_name.eq_ignore_ascii_case(name))
+            && self.client_id.is_none_or(|id| id == client_id)
+            && self.addr.as_ref().is_none_or(|rule_addr| {
+                Cidr::parse(rule_addr).is_ok_and(|cidr| cidr.contains(addr))
+            })
+    }
+
+    //The same client selection, the reason is ignored.
+    fn same_target(&self, other: &AccessRule) -> bool {
+        self.name.as_ref().map(|name| name.to_lowercase())
+            == other.name.as_ref().map(|name| name.to_lowercase())
+            && self.client_id == other.client_id
+            && self.addr == other.addr
+    }
+}
+
+impl fmt::Display for AccessRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut fields = vec![];
+        if let Some(name) = &self.name {
+            fields.push(format!("name {}", name));
+        }
+        if let Some(client_id) = self.client_id {
+            fields.push(format!("client {}", client_id));
+        }
+        if let Some(addr) = &self.addr {
+            fields.push(format!("address {}", addr));
+        }
+        if let Some(reason) = &self.reason {
+            fields.push(format!("({})", reason));
+        }
+        write!(f, "{}", fields.join(" "))
+    }
+}
+
+//Who may connect. With allow rules only matching clients may connect, ban rules refuse clients.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct AccessList {
+    #[serde(default)]
+    pub allow: Vec<AccessRule>,
+    #[serde(default)]
+    pub ban: Vec<AccessRule>,
+    //Changes are saved to the file the list was loaded from.
+    #[serde(skip)]
+    path: Option<OsString>,
+}
+
+impl AccessList {
+    //The list in ACCESS_FILE next to the server config, in the shooter config directory
+    //without a server.toml. Empty when there is no file.
+    pub fn load_default(server_config: Option<&OsString>) -> Result<AccessList, String> {
+        AccessList::load(access_file_path(server_config)?)
+    }
+
+    pub fn load(path: OsString) -> Result<AccessList, String> {
+        let mut list = match std::fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str::<AccessList>(&contents)
+                .map_err(|e| format!("Could not read the access list {:?}. {}", path, e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => AccessList::default(),
+            Err(e) => return Err(format!("Could not open the access list {:?}. {}", path, e)),
+        };
+        for rule in list.allow.iter().chain(list.ban.iter()) {
+            if let Some(addr) = &rule.addr {
+                Cidr::parse(addr)?;
+            }
+        }
+        list.path = Some(path);
+        Ok(list)
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let toml = toml::to_string(self).map_err(|e| format!("{}", e))?;
+        let mut file = get_file(path, false)?;
+        file.set_len(0)
+            .and_then(|_| file.write_all(toml.as_bytes()))
+            .map_err(|e| format!("Could not write the access list {:?}. {}", path, e))
+    }
+
+    //Ok when the client may connect, otherwise the reason it may not.
+    pub fn check(&self, name: &str, client_id: u64, addr: IpAddr) -> Result<(), String> {
+        if !self.allow.is_empty()
+            && !self
+                .allow
+                .iter()
+                .any(|rule| rule.matches(name, client_id, addr))
+        {
+            return Err("You are not on the allow list of the server.".to_string());
+        }
+        match self
+            .ban
+            .iter()
+            .find(|rule| rule.matches(name, client_id, addr))
+        {
+            Some(rule) => Err(match &rule.reason {
+                Some(reason) => format!("You are banned from the server: {}", reason),
+                None => "You are banned from the server.".to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    //Add a rule, replacing a rule for the same target.
+    pub fn add(rules: &mut Vec<AccessRule>, rule: AccessRule) {
+        rules.retain(|other| !other.same_target(&rule));
+        rules.push(rule);
+    }
+
+    //Remove the rules for the target, returns false when there were none.
+    pub fn remove(rules: &mut Vec<AccessRule>, target: &str) -> bool {
+        let rule = AccessRule::from_target(target);
+        let len = rules.len();
+        rules.retain(|other| !other.same_target(&rule));
+        rules.len() != len
+    }
+}
+
+#[test]
+fn test_cidr() {
+    let addr = |text: &str| text.parse::<IpAddr>().unwrap();
+    let net = Cidr::parse("10.1.0.0/16").unwrap();
+    assert!(net.contains(addr("10.1.200.3")));
+    assert!(!net.contains(addr("10.2.0.1")));
+    assert!(net.contains(addr("::ffff:10.1.0.9")));
+    assert!(!net.contains(addr("fe80::1")));
+    assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(addr("8.8.8.8")));
+    assert!(Cidr::parse("10.0.0.7").unwrap().contains(addr("10.0.0.7")));
+    assert!(!Cidr::parse("10.0.0.7").unwrap().contains(addr("10.0.0.8")));
+    assert!(Cidr::parse("fd00::/8").unwrap().contains(addr("fd12::1")));
+    assert!(Cidr::parse("10.0.0.0/33").is_err());
+    assert!(Cidr::parse("ikky").is_err());
+    assert_eq!(Cidr::parse("10.0.0.7/32").unwrap().to_string(), "10.0.0.7");
+}
+
+#[test]
+fn test_access_list() {
+    let addr: IpAddr = "10.0.0.7".parse().unwrap();
+    let mut list = AccessList::default();
+    assert!(list.check("ikky", 1, addr).is_ok());
+    AccessList::add(
+        &mut list.ban,
+        AccessRule {
+            reason: Some("cheating".to_string()),
+            ..AccessRule::from_target("Ikky")
+        },
+    );
+    assert_eq!(
+        list.check("ikky", 1, addr),
+        Err("You are banned from the server: cheating".to_string())
+    );
+    AccessList::add(&mut list.ban, AccessRule::from_target("10.9.0.0/16"));
+    assert!(list.check("other", 1, "10.9.3.4".parse().unwrap()).is_err());
+    assert!(list.check("other", 1, addr).is_ok());
+    AccessList::add(&mut list.allow, AccessRule::from_target("10.0.0.0/24"));
+    assert!(list.check("other", 1, "10.0.1.1".parse().unwrap()).is_err());
+    assert!(AccessList::remove(&mut list.ban, "ikky"));
+    assert!(!AccessList::remove(&mut list.ban, "ikky"));
+    assert!(list.check("ikky", 1, addr).is_ok());
+    AccessList::add(&mut list.ban, AccessRule::from_target("42"));
+    assert!(list.check("ikky", 42, addr).is_err());
+}
+
+#[test]
+fn test_access_file() {
+    let path = std::env::temp_dir().join("shooter_access_test.toml");
+    std::fs::write(
+        &path,
+        "[[allow]]\naddr = \"192.168.0.0/16\"\n\n[[ban]]\nname = \"ikky\"\nreason = \"spam\"\n",
+    )
+    .unwrap();
+    let mut list = AccessList::load(path.clone().into_os_string()).unwrap();
+    assert_eq!(list.allow.len(), 1);
+    assert!(list
+        .check("ikky", 1, "192.168.1.2".parse().unwrap())
+        .is_err());
+    AccessList::remove(&mut list.ban, "ikky");
+    list.save().unwrap();
+    let list = AccessList::load(path.clone().into_os_string()).unwrap();
+    assert!(list.ban.is_empty());
+    assert!(list
+        .check("ikky", 1, "192.168.1.2".parse().unwrap())
+        .is_ok());
+    std::fs::write(&path, "[[ban]]\naddr = \"10.0.0.0/99\"\n").unwrap();
+    assert!(AccessList::load(path.into_os_string()).is_err());
+}
+
+#[test]
+fn test_access_file_path() {
+    let config = OsString::from("/srv/shooter/server.toml");
+    assert_eq!(
+        access_file_path(Some(&config)).unwrap(),
+        OsString::from("/srv/shooter/access.toml")
+    );
+    let config = OsString::from("server.toml");
+    assert_eq!(
+        access_file_path(Some(&config)).unwrap(),
+        OsString::from("access.toml")
+    );
+}
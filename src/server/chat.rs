@@ -0,0 +1,153 @@
+use bevy::prelude::*;
+
+use super::tick::TickScheduler;
+use super::{ClientEntities, ClientMessage, Destination, MessagesToDeliver, Player};
+use crate::protocol::MultiplayerMessage;
+use crate::ClientId;
+
+//Longer chat text is cut.
+pub const MAX_CHAT_BYTES: usize = 200;
+//The sender id of chat from the server itself.
+pub const SERVER_CHAT_ID: u64 = 0;
+//A player may send CHAT_BURST messages at once, then one every CHAT_REFILL_SECS.
+const CHAT_BURST: f32 = 4.0;
+const CHAT_REFILL_SECS: f32 = 2.0;
+
+//Chat rate limit of a player.
+#[derive(Component)]
+pub struct ChatLimiter {
+    tokens: f32,
+    last_tick: Option<u32>,
+}
+
+impl Default for ChatLimiter {
+    fn default() -> ChatLimiter {
+        ChatLimiter {
+            tokens: CHAT_BURST,
+            last_tick: None,
+        }
+    }
+}
+
+impl ChatLimiter {
+    pub fn allow(&mut self, tick: u32, tick_rate: u32) -> bool {
+        if let Some(last_tick) = self.last_tick {
+            let elapsed_secs = tick.wrapping_sub(last_tick) as f32 / tick_rate as f32;
+            self.tokens = (self.tokens + elapsed_secs / CHAT_REFILL_SECS).min(CHAT_BURST);
+        }
+        self.last_tick = Some(tick);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+//The text without control characters and surrounding white space, cut to MAX_CHAT_BYTES.
+pub fn clean_text(text: &str) -> String {
+    let text: String = text.chars().filter(|c| !c.is_control()).collect();
+    let text = text.trim();
+    let mut end = text.len().min(MAX_CHAT_BYTES);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text[..end].trim_end().to_string()
+}
+
+pub(super) fn server_notice(client_id: u64, text: &str) -> (Destination, MultiplayerMessage) {
+    (
+        Destination::Player(client_id),
+        MultiplayerMessage::Chat {
+            client_id: SERVER_CHAT_ID,
+            team_only: false,
+            text: text.to_string(),
+        },
+    )
+}
+
+//Relay chat to the other players, or to the team mates of the sender for team chat.
+pub(super) fn relay_chat(
+    scheduler: Res<TickScheduler>,
+    mut messages: EventReader<ClientMessage>,
+    client_entities: Res<ClientEntities>,
+    players: Query<(&ClientId, &Player)>,
+    mut limiters: Query<&mut ChatLimiter>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+) {
+    for ClientMessage { client_id, message } in messages.read() {
+        let MultiplayerMessage::Chat {
+            client_id: _,
+            team_only,
+            text,
+        } = message
+        else {
+            continue;
+        };
+        let Some(entity) = client_entities.0.get(client_id) else {
+            continue;
+        };
+        let (Ok((_, sender)), Ok(mut limiter)) = (players.get(*entity), limiters.get_mut(*entity))
+        else {
+            continue;
+        };
+        let text = clean_text(text);
+        if text.is_empty() {
+            continue;
+        }
+        if !limiter.allow(scheduler.tick(), scheduler.tick_rate()) {
+            messages_to_deliver.0.push(server_notice(
+                *client_id,
+                "You are sending messages too fast.",
+            ));
+            continue;
+        }
+        let chat = MultiplayerMessage::Chat {
+            client_id: *client_id,
+            team_only: *team_only,
+            text,
+        };
+        if !*team_only {
+            messages_to_deliver
+                .0
+                .push((Destination::NotPlayer(*client_id), chat));
+            continue;
+        }
+        let Some(team) = sender.team else {
+            messages_to_deliver
+                .0
+                .push(server_notice(*client_id, "There are no teams."));
+            continue;
+        };
+        for (team_mate, _) in players
+            .iter()
+            .filter(|(cid, player)| cid.id != *client_id && player.team == Some(team))
+        {
+            messages_to_deliver
+                .0
+                .push((Destination::Player(team_mate.id), chat.clone()));
+        }
+    }
+}
+
+#[test]
+fn test_chat_limiter() {
+    let mut limiter = ChatLimiter::default();
+    for _ in 0..CHAT_BURST as u32 {
+        assert!(limiter.allow(100, 20));
+    }
+    assert!(!limiter.allow(100, 20));
+    //One message after CHAT_REFILL_SECS at 20 Hz.
+    assert!(!limiter.allow(120, 20));
+    assert!(limiter.allow(140, 20));
+    assert!(!limiter.allow(140, 20));
+}
+
+#[test]
+fn test_clean_text() {
+    assert_eq!(clean_text("  hello\n\tthere \u{7} "), "hellothere");
+    assert_eq!(clean_text(" \r\n"), "");
+    let long = clean_text(&"é".repeat(MAX_CHAT_BYTES));
+    assert_eq!(long.len(), MAX_CHAT_BYTES);
+}
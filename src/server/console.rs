@@ -0,0 +1,409 @@
+use bevy::prelude::*;
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+
+use super::access::{AccessList, AccessRule};
+use super::chat::{clean_text, SERVER_CHAT_ID};
+use super::game_mode::Match;
+use super::lag_compensation::{LagCompensation, MAX_REWIND_LIMIT_MS};
+use super::tick::TickScheduler;
+use super::{Destination, MessagesToDeliver, NetServer, Player, ServerInfo};
+use crate::protocol::MultiplayerMessage;
+use crate::ClientId;
+
+const HELP: &str = "Commands: status | kick <id> | ban <target> [reason] | unban <target> \
+    | allow <target> | disallow <target> | access | say <text> | map <name> \
+    | set [<cvar> <value>] | quit. A target is an address, a network, a client id or a name.";
+
+#[derive(Debug, PartialEq)]
+pub enum ConsoleCommand {
+    Help,
+    Status,
+    Kick(u64),
+    //The target and the reason told to the client.
+    Ban(String, Option<String>),
+    Unban(String),
+    Allow(String),
+    Disallow(String),
+    //Lists the access rules.
+    Access,
+    Say(String),
+    Map(String),
+    //None lists the cvars.
+    Set(Option<(String, String)>),
+    Quit,
+}
+
+impl ConsoleCommand {
+    pub fn parse(line: &str) -> Result<ConsoleCommand, String> {
+        let line = line.trim();
+        let (command, args) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(command, args)| (command, args.trim()));
+        match (command, args) {
+            ("help", _) => Ok(ConsoleCommand::Help),
+            ("status", "") => Ok(ConsoleCommand::Status),
+            ("kick", id) => id
+                .parse::<u64>()
+                .map(ConsoleCommand::Kick)
+                .map_err(|_| "Usage: kick <id>".to_string()),
+            ("ban", args) if !args.is_empty() => Ok(match args.split_once(char::is_whitespace) {
+                Some((target, reason)) => {
+                    ConsoleCommand::Ban(target.to_string(), Some(reason.trim().to_string()))
+                }
+                None => ConsoleCommand::Ban(args.to_string(), None),
+            }),
+            ("unban", target) if !target.is_empty() => {
+                Ok(ConsoleCommand::Unban(target.to_string()))
+            }
+            ("allow", target) if !target.is_empty() => {
+                Ok(ConsoleCommand::Allow(target.to_string()))
+            }
+            ("disallow", target) if !target.is_empty() => {
+                Ok(ConsoleCommand::Disallow(target.to_string()))
+            }
+            ("access", "") => Ok(ConsoleCommand::Access),
+            ("say", text) if !text.is_empty() => Ok(ConsoleCommand::Say(text.to_string())),
+            ("map", name) if !name.is_empty() => Ok(ConsoleCommand::Map(name.to_string())),
+            ("set", "") => Ok(ConsoleCommand::Set(None)),
+            ("set", args) => match args.split_once(char::is_whitespace) {
+                Some((name, value)) => Ok(ConsoleCommand::Set(Some((
+                    name.to_string(),
+                    value.trim().to_string(),
+                )))),
+                None => Err("Usage: set <cvar> <value>".to_string()),
+            },
+            ("quit", "") => Ok(ConsoleCommand::Quit),
+            _ => Err(format!("Unknown command {}. {}", line, HELP)),
+        }
+    }
+}
+
+//Lines typed on the server stdin.
+#[derive(Resource)]
+pub struct Console {
+    receiver: Mutex<Receiver<String>>,
+}
+
+impl Console {
+    pub fn spawn_stdin() -> Console {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Console {
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+//The server settings that can be changed while running.
+fn cvars(game: &Match, lag_compensation: &LagCompensation) -> Vec<(&'static str, String)> {
+    vec![
+        ("frag_limit", game.frag_limit.to_string()),
+        ("time_limit", game.time_limit_secs.to_string()),
+        (
+            "friendly_fire",
+            if game.friendly_fire { "on" } else { "off" }.to_string(),
+        ),
+        ("max_rewind_ms", lag_compensation.max_rewind_ms.to_string()),
+    ]
+}
+
+//Change a setting. A new time limit applies from the next match.
+fn set_cvar(
+    name: &str,
+    value: &str,
+    game: &mut Match,
+    lag_compensation: &mut LagCompensation,
+) -> Result<(), String> {
+    let number = || {
+        value
+            .parse::<u32>()
+            .map_err(|_| format!("{} is not a number.", value))
+    };
+    match name {
+        "frag_limit" => game.frag_limit = number()?,
+        "time_limit" => match number()? {
+            0 => return Err("The time limit must be at least 1 s.".to_string()),
+            secs => game.time_limit_secs = secs,
+        },
+        "friendly_fire" => {
+            game.friendly_fire = match value {
+                "on" => true,
+                "off" => false,
+                _ => return Err("friendly_fire is on or off.".to_string()),
+            }
+        }
+        "max_rewind_ms" => match number()? {
+            ms if ms <= MAX_REWIND_LIMIT_MS => lag_compensation.max_rewind_ms = ms,
+            _ => return Err(format!("max_rewind_ms is 0-{}.", MAX_REWIND_LIMIT_MS)),
+        },
+        _ => return Err(format!("Unknown cvar {}.", name)),
+    }
+    Ok(())
+}
+
+fn print_status(
+    info: &ServerInfo,
+    net: &NetServer,
+    scheduler: &TickScheduler,
+    game: &Match,
+    players: &Query<(&ClientId, &mut Player)>,
+) {
+    println!(
+        "{}: {} on {} {}, {:.0} s left. Tick {} at {} Hz.",
+        info.name,
+        game.mode.name(),
+        game.map(),
+        if game.is_playing() {
+            "playing"
+        } else {
+            "intermission"
+        },
+        game.remaining_secs(scheduler),
+        scheduler.tick(),
+        scheduler.tick_rate()
+    );
+    println!(
+        "{:>20} {:<24} {:<5} {:>5} {:>6} {:>5} address",
+        "id", "name", "team", "frags", "deaths", "ping"
+    );
+    for (client_id, player) in players.iter() {
+        let score = game.scores.get(&client_id.id).copied().unwrap_or_default();
+        let addr = net
+            .server
+            .client_addr(client_id.id)
+            .map_or("-".to_string(), |addr| addr.to_string());
+        println!(
+            "{:>20} {:<24} {:<5} {:>5} {:>6} {:>5} {}",
+            client_id.id,
+            player.name,
+            player.team.map_or("-", |team| team.name()),
+            score.frags,
+            score.deaths,
+            player.ping_ms,
+            addr
+        );
+    }
+    for (client_id, name) in net.pending.iter() {
+        println!("{:>20} {:<24} waiting for the handshake", client_id, name);
+    }
+}
+
+//Save the changed access lists and kick the connected clients they refuse.
+fn update_access(net: &mut NetServer, players: &Query<(&ClientId, &mut Player)>) {
+    if let Err(e) = net.access.save() {
+        error!("{}", e);
+    }
+    let names = players
+        .iter()
+        .map(|(client_id, player)| (client_id.id, player.name.clone()))
+        .chain(net.pending.clone());
+    let mut refused = vec![];
+    for (client_id, name) in names {
+        let Some(addr) = net.server.client_addr(client_id) else {
+            continue;
+        };
+        if let Err(reason) = net.access.check(&name, client_id, addr.ip()) {
+            info!("Kicking client {}. {}", client_id, reason);
+            refused.push((client_id, reason));
+        }
+    }
+    net.kicks.extend(refused);
+}
+
+//Run the commands typed on the console.
+pub(super) fn run_console(
+    (console, info): (Res<Console>, Res<ServerInfo>),
+    mut net: ResMut<NetServer>,
+    (scheduler, mut game, mut lag_compensation): (
+        Res<TickScheduler>,
+        ResMut<Match>,
+        ResMut<LagCompensation>,
+    ),
+    mut players: Query<(&ClientId, &mut Player)>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+    mut exit: EventWriter<AppExit>,
+    mut commands: Commands,
+) {
+    let lines: Vec<String> = match console.receiver.lock() {
+        Ok(receiver) => receiver.try_iter().collect(),
+        //The server runs on without the console.
+        Err(e) => {
+            error!("The console failed, it is stopped. {}", e);
+            commands.remove_resource::<Console>();
+            return;
+        }
+    };
+    for line in lines.iter().filter(|line| !line.trim().is_empty()) {
+        let command = match ConsoleCommand::parse(line) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("{}", e);
+                continue;
+            }
+        };
+        match command {
+            ConsoleCommand::Help => println!("{}", HELP),
+            ConsoleCommand::Status => print_status(&info, &net, &scheduler, &game, &players),
+            ConsoleCommand::Kick(client_id) => {
+                if net.server.is_client_connected(client_id) {
+                    info!("Kicking client {}.", client_id);
+                    net.kicks
+                        .push((client_id, "You were kicked from the server.".to_string()));
+                } else {
+                    println!("There is no client {}.", client_id);
+                }
+            }
+            ConsoleCommand::Ban(target, reason) => {
+                let rule = AccessRule {
+                    reason,
+                    ..AccessRule::from_target(&target)
+                };
+                info!("Banned {}.", rule);
+                AccessList::add(&mut net.access.ban, rule);
+                update_access(&mut net, &players);
+            }
+            ConsoleCommand::Unban(target) => {
+                if AccessList::remove(&mut net.access.ban, &target) {
+                    info!("Unbanned {}.", target);
+                    update_access(&mut net, &players);
+                } else {
+                    println!("{} is not banned.", target);
+                }
+            }
+            ConsoleCommand::Allow(target) => {
+                let rule = AccessRule::from_target(&target);
+                info!("Allowed {}.", rule);
+                AccessList::add(&mut net.access.allow, rule);
+                update_access(&mut net, &players);
+            }
+            ConsoleCommand::Disallow(target) => {
+                if AccessList::remove(&mut net.access.allow, &target) {
+                    info!("Removed {} from the allow list.", target);
+                    update_access(&mut net, &players);
+                } else {
+                    println!("{} is not on the allow list.", target);
+                }
+            }
+            ConsoleCommand::Access => {
+                if net.access.allow.is_empty() {
+                    println!("Everyone not banned may connect.");
+                }
+                for rule in net.access.allow.iter() {
+                    println!("allow {}", rule);
+                }
+                for rule in net.access.ban.iter() {
+                    println!("ban {}", rule);
+                }
+            }
+            ConsoleCommand::Say(text) => {
+                let text = clean_text(&text);
+                info!("Server: {}", text);
+                messages_to_deliver.0.push((
+                    Destination::All,
+                    MultiplayerMessage::Chat {
+                        client_id: SERVER_CHAT_ID,
+                        team_only: false,
+                        text,
+                    },
+                ));
+            }
+            ConsoleCommand::Map(name) => {
+                if let Err(e) = game.set_map(&name) {
+                    println!("{}", e);
+                    continue;
+                }
+                info!("Restarting the match on {}.", name);
+                for message in game.restart(&scheduler) {
+                    messages_to_deliver.0.push((Destination::All, message));
+                }
+                for (_, mut player) in players.iter_mut() {
+                    player.respawn_tick = Some(scheduler.tick());
+                }
+            }
+            ConsoleCommand::Set(None) => {
+                for (name, value) in cvars(&game, &lag_compensation) {
+                    println!("{} {}", name, value);
+                }
+            }
+            ConsoleCommand::Set(Some((name, value))) => {
+                match set_cvar(&name, &value, &mut game, &mut lag_compensation) {
+                    Ok(()) => info!("{} set to {}.", name, value),
+                    Err(e) => println!("{}", e),
+                }
+            }
+            ConsoleCommand::Quit => {
+                info!("Shutting down, disconnecting the clients.");
+                let client_ids = net.server.clients_id();
+                for client_id in client_ids {
+                    net.kicks
+                        .push((client_id, "The server is shutting down.".to_string()));
+                }
+                exit.send(AppExit::Success);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse_commands() {
+    assert_eq!(
+        ConsoleCommand::parse(" status "),
+        Ok(ConsoleCommand::Status)
+    );
+    assert_eq!(
+        ConsoleCommand::parse("kick 42"),
+        Ok(ConsoleCommand::Kick(42))
+    );
+    assert!(ConsoleCommand::parse("kick ikky").is_err());
+    assert_eq!(
+        ConsoleCommand::parse("say  hello there"),
+        Ok(ConsoleCommand::Say("hello there".to_string()))
+    );
+    assert_eq!(
+        ConsoleCommand::parse("set frag_limit  30"),
+        Ok(ConsoleCommand::Set(Some((
+            "frag_limit".to_string(),
+            "30".to_string()
+        ))))
+    );
+    assert_eq!(ConsoleCommand::parse("set"), Ok(ConsoleCommand::Set(None)));
+    assert!(ConsoleCommand::parse("set frag_limit").is_err());
+    assert!(ConsoleCommand::parse("ban").is_err());
+    assert_eq!(
+        ConsoleCommand::parse("ban 10.0.0.0/8 too many  bots"),
+        Ok(ConsoleCommand::Ban(
+            "10.0.0.0/8".to_string(),
+            Some("too many  bots".to_string())
+        ))
+    );
+    assert_eq!(
+        ConsoleCommand::parse("unban ikky"),
+        Ok(ConsoleCommand::Unban("ikky".to_string()))
+    );
+    assert!(ConsoleCommand::parse("jump").is_err());
+}
+
+#[test]
+fn test_set_cvar() {
+    use super::game_mode::GameMode;
+    let mut game = Match::new(GameMode::Deathmatch, 20, 600, 1000);
+    let mut lag = LagCompensation { max_rewind_ms: 250 };
+    assert!(set_cvar("frag_limit", "5", &mut game, &mut lag).is_ok());
+    assert_eq!(game.frag_limit, 5);
+    assert!(set_cvar("friendly_fire", "on", &mut game, &mut lag).is_ok());
+    assert!(game.friendly_fire);
+    assert!(set_cvar("time_limit", "0", &mut game, &mut lag).is_err());
+    assert!(set_cvar("max_rewind_ms", "5000", &mut game, &mut lag).is_err());
+    assert_eq!(lag.max_rewind_ms, 250);
+    assert!(set_cvar("gravity", "1", &mut game, &mut lag).is_err());
+    assert_eq!(cvars(&game, &lag)[2], ("friendly_fire", "on".to_string()));
+}
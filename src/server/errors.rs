@@ -0,0 +1,246 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+//A client is disconnected after this many errors.
+pub const MAX_CLIENT_ERRORS: u32 = 5;
+
+//Errors of the server loop. None of them stop the server, the errors of a client count
+//against the client.
+#[derive(Debug)]
+pub enum ServerError {
+    //Reading the socket failed.
+    Receive(io::Error),
+    //Sending a packet failed.
+    Send { addr: SocketAddr, error: io::Error },
+    //Netcode could not make a packet for the client.
+    Packet { client_id: u64, error: String },
+    //A message could not be encoded.
+    Encode(io::Error),
+    //A client sent a message that could not be decoded.
+    InvalidMessage { client_id: u64, error: io::Error },
+    //A client sent a message only the server sends.
+    UnexpectedMessage { client_id: u64, id: u8 },
+    //The connect token user data is not a player name.
+    InvalidUsername { client_id: u64 },
+}
+
+impl ServerError {
+    //The client that caused the error.
+    pub fn client_id(&self) -> Option<u64> {
+        match self {
+            ServerError::InvalidMessage { client_id, .. }
+            | ServerError::UnexpectedMessage { client_id, .. }
+            | ServerError::InvalidUsername { client_id } => Some(*client_id),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Receive(error) => write!(f, "Socket receive failed. {}", error),
+            ServerError::Send { addr, error } => write!(f, "Send to {} failed. {}", addr, error),
+            ServerError::Packet { client_id, error } => {
+                write!(f, "No packet for client {}. {}", client_id, error)
+            }
+            ServerError::Encode(error) => {
+                write!(f, "Error buffering MultiplayerMessage. {}", error)
+            }
+            ServerError::InvalidMessage { client_id, error } => {
+                write!(f, "Invalid message from client {}. {}", client_id, error)
+            }
+            ServerError::UnexpectedMessage { client_id, id } => write!(
+                f,
+                "Client {} sent message {}, it is only sent by the server.",
+                client_id, id
+            ),
+            ServerError::InvalidUsername { client_id } => {
+                write!(f, "Client {} has an invalid player name.", client_id)
+            }
+        }
+    }
+}
+
+//Error counts of the connected clients.
+#[derive(Resource, Default)]
+pub struct ClientErrors {
+    counts: HashMap<u64, u32>,
+    //Clients over MAX_CLIENT_ERRORS, disconnected by receive_packets.
+    misbehaving: Vec<u64>,
+}
+
+impl ClientErrors {
+    //Log the error and count it against the client.
+    pub fn report(&mut self, error: ServerError) {
+        let Some(client_id) = error.client_id() else {
+            error!("{}", error);
+            return;
+        };
+        warn!("{}", error);
+        let count = self.counts.entry(client_id).or_default();
+        *count += 1;
+        if *count == MAX_CLIENT_ERRORS {
+            self.misbehaving.push(client_id);
+        }
+    }
+
+    pub fn take_misbehaving(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.misbehaving)
+    }
+
+    pub fn remove(&mut self, client_id: u64) {
+        self.counts.remove(&client_id);
+    }
+}
+
+#[test]
+fn test_client_errors() {
+    let mut errors = ClientErrors::default();
+    errors.report(ServerError::Receive(io::ErrorKind::ConnectionReset.into()));
+    for _ in 0..MAX_CLIENT_ERRORS - 1 {
+        errors.report(ServerError::UnexpectedMessage {
+            client_id: 7,
+            id: 8,
+        });
+    }
+    assert!(errors.take_misbehaving().is_empty());
+    errors.report(ServerError::InvalidUsername { client_id: 7 });
+    assert_eq!(errors.counts[&7], MAX_CLIENT_ERRORS);
+    assert_eq!(errors.take_misbehaving(), vec![7]);
+    //Reported once.
+    errors.report(ServerError::InvalidUsername { client_id: 7 });
+    assert!(errors.take_misbehaving().is_empty());
+    errors.remove(7);
+    assert!(!errors.counts.contains_key(&7));
+}
+
+//Deterministic pseudo random input for the fuzz tests.
+#[cfg(test)]
+struct XorShift(u64);
+
+#[cfg(test)]
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+#[test]
+fn test_fuzz_message_get() {
+    use super::game_mode::{GameMode, Team};
+    use crate::players::Movement;
+    use crate::protocol::{MultiplayerMessage, ScoreboardEntry, PROTOCOL_VERSION};
+    let valid = [
+        MultiplayerMessage::Hello {},
+        MultiplayerMessage::Input {
+            client_id: 3,
+            sequence: 9,
+            movement: Movement::Left,
+            rotation: Vec2::new(0.5, -1.0),
+        },
+        MultiplayerMessage::Chat {
+            client_id: 3,
+            team_only: true,
+            text: "gg é".to_string(),
+        },
+        MultiplayerMessage::MatchState {
+            mode: GameMode::TeamDeathmatch,
+            playing: false,
+            remaining_secs: 4.5,
+            frag_limit: 20,
+            winner_id: Some(3),
+            winning_team: Some(Team::Red),
+        },
+        MultiplayerMessage::Scoreboard {
+            entries: vec![ScoreboardEntry {
+                client_id: 3,
+                name: "ikky".to_string(),
+                team: None,
+                frags: 2,
+                deaths: 1,
+                ping_ms: 40,
+            }],
+        },
+    ];
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    for round in 0..20_000 {
+        let mut buf = match round % 3 {
+            //Random bytes, mostly with a valid header.
+            0 => {
+                let len = rng.below(64);
+                let mut buf = rng.bytes(len);
+                if len >= 5 && rng.below(4) != 0 {
+                    buf[0..2].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+                    buf[2] = rng.below(24) as u8;
+                    buf[3..5].copy_from_slice(&((len - 5) as u16).to_le_bytes());
+                }
+                buf
+            }
+            //Valid messages with flipped bits.
+            1 => {
+                let mut buf = valid[rng.below(valid.len())].get_buf().unwrap();
+                for _ in 0..1 + rng.below(3) {
+                    let i = rng.below(buf.len());
+                    buf[i] ^= 1 << rng.below(8);
+                }
+                buf
+            }
+            //Valid messages cut short or extended.
+            _ => {
+                let mut buf = valid[rng.below(valid.len())].get_buf().unwrap();
+                if rng.below(2) == 0 {
+                    buf.truncate(rng.below(buf.len()));
+                } else {
+                    let len = 1 + rng.below(8);
+                    buf.extend(rng.bytes(len));
+                }
+                buf
+            }
+        };
+        if let Ok(message) = MultiplayerMessage::get(&buf) {
+            //Whatever decodes encodes to the same message.
+            buf = message.get_buf().unwrap();
+            assert_eq!(MultiplayerMessage::get(&buf).unwrap(), message);
+        }
+    }
+}
+
+#[test]
+fn test_fuzz_username() {
+    use super::Username;
+    use renetcode::NETCODE_USER_DATA_BYTES;
+    let mut rng = XorShift(0x9e37_79b9_7f4a_7c15);
+    for round in 0..5_000 {
+        let mut user_data = [0u8; NETCODE_USER_DATA_BYTES];
+        user_data.copy_from_slice(&rng.bytes(NETCODE_USER_DATA_BYTES));
+        if round % 2 == 0 {
+            //A plausible length with random name bytes.
+            let len = rng.below(NETCODE_USER_DATA_BYTES) as u64;
+            user_data[0..8].copy_from_slice(&len.to_le_bytes());
+        }
+        if let Some(username) = Username::from_user_data(&user_data) {
+            assert!(username.0.len() <= NETCODE_USER_DATA_BYTES - 8);
+        }
+    }
+    let name = "ikky é".to_string();
+    let user_data = Username(name.clone()).to_netcode_user_data();
+    assert_eq!(
+        Username::from_user_data(&user_data).map(|u| u.0),
+        Some(name)
+    );
+}
@@ -0,0 +1,558 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use super::tick::TickScheduler;
+use super::{ClientEntities, ClientMessage, Destination, MessagesToDeliver, Player, PlayerKilled};
+use crate::players::is_sequence_newer;
+use crate::protocol::MultiplayerMessage;
+
+pub const DEFAULT_FRAG_LIMIT: u32 = 20;
+pub const DEFAULT_TIME_LIMIT_SECS: u32 = 600;
+pub const INTERMISSION_SECS: f32 = 10.0;
+//The maps a server can run, there is only the arena for now.
+pub const MAPS: [&str; 1] = ["arena"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum GameMode {
+    Deathmatch = 1,
+    TeamDeathmatch = 2,
+}
+
+impl GameMode {
+    pub fn from_u8(value: u8) -> Option<GameMode> {
+        match value {
+            1 => Some(GameMode::Deathmatch),
+            2 => Some(GameMode::TeamDeathmatch),
+            _ => None,
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<GameMode, String> {
+        match name {
+            "deathmatch" | "dm" => Ok(GameMode::Deathmatch),
+            "teamdeathmatch" | "tdm" => Ok(GameMode::TeamDeathmatch),
+            _ => Err(format!(
+                "Unknown game mode {}, use deathmatch or teamdeathmatch.",
+                name
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::Deathmatch => "Deathmatch",
+            GameMode::TeamDeathmatch => "Team Deathmatch",
+        }
+    }
+
+    pub fn has_teams(&self) -> bool {
+        matches!(self, GameMode::TeamDeathmatch)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Team {
+    Red = 1,
+    Blue = 2,
+}
+
+impl Team {
+    pub const ALL: [Team; 2] = [Team::Red, Team::Blue];
+
+    pub fn from_u8(value: u8) -> Option<Team> {
+        match value {
+            1 => Some(Team::Red),
+            2 => Some(Team::Blue),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Team::Red => "Red",
+            Team::Blue => "Blue",
+        }
+    }
+
+    pub fn other(&self) -> Team {
+        match self {
+            Team::Red => Team::Blue,
+            Team::Blue => Team::Red,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        *self as usize - 1
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Score {
+    pub frags: i32,
+    pub deaths: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MatchPhase {
+    Playing {
+        end_tick: u32,
+    },
+    Intermission {
+        restart_tick: u32,
+        winner: Option<u64>,
+        winning_team: Option<Team>,
+    },
+}
+
+//The game rules of the running match.
+#[derive(Resource)]
+pub struct Match {
+    pub mode: GameMode,
+    pub frag_limit: u32,
+    pub time_limit_secs: u32,
+    pub friendly_fire: bool,
+    //The map rotation, a match on each map in turn.
+    pub maps: Vec<String>,
+    map_index: usize,
+    pub phase: MatchPhase,
+    pub scores: HashMap<u64, Score>,
+    //Frags of the Red and Blue teams in a team mode.
+    team_frags: [i32; 2],
+    //Counts the restarts, a score is only restored to a returning player in the same match.
+    pub number: u32,
+}
+
+impl Match {
+    pub fn new(mode: GameMode, frag_limit: u32, time_limit_secs: u32, end_tick: u32) -> Match {
+        Match {
+            mode,
+            frag_limit,
+            time_limit_secs,
+            friendly_fire: false,
+            maps: vec![MAPS[0].to_string()],
+            map_index: 0,
+            phase: MatchPhase::Playing { end_tick },
+            scores: HashMap::new(),
+            team_frags: [0; 2],
+            number: 0,
+        }
+    }
+
+    pub fn map(&self) -> &str {
+        &self.maps[self.map_index]
+    }
+
+    //Play the next map of the rotation from the next restart.
+    pub fn next_map(&mut self) {
+        self.map_index = (self.map_index + 1) % self.maps.len();
+    }
+
+    pub fn set_map(&mut self, name: &str) -> Result<(), String> {
+        match self.maps.iter().position(|map| map == name) {
+            Some(index) => {
+                self.map_index = index;
+                Ok(())
+            }
+            None => Err(format!(
+                "{} is not in the map rotation {}.",
+                name,
+                self.maps.join(", ")
+            )),
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        matches!(self.phase, MatchPhase::Playing { .. })
+    }
+
+    //Count the kill, a suicide or killing a team mate loses a frag.
+    pub fn record_kill(&mut self, kill: &PlayerKilled) {
+        self.scores.entry(kill.victim).or_default().deaths += 1;
+        let own_goal = kill.victim == kill.attacker
+            || (kill.attacker_team.is_some() && kill.attacker_team == kill.victim_team);
+        let frags = if own_goal { -1 } else { 1 };
+        self.scores.entry(kill.attacker).or_default().frags += frags;
+        if let Some(team) = kill.attacker_team {
+            self.team_frags[team.index()] += frags;
+        }
+    }
+
+    pub fn team_frags(&self, team: Team) -> i32 {
+        self.team_frags[team.index()]
+    }
+
+    //The team for a joining player, the smaller team or the team behind on frags. sizes are
+    //the players in each team.
+    pub fn auto_team(&self, sizes: [usize; 2]) -> Option<Team> {
+        if !self.mode.has_teams() {
+            return None;
+        }
+        Some(if sizes[0] != sizes[1] {
+            if sizes[0] < sizes[1] {
+                Team::Red
+            } else {
+                Team::Blue
+            }
+        } else if self.team_frags(Team::Blue) < self.team_frags(Team::Red) {
+            Team::Blue
+        } else {
+            Team::Red
+        })
+    }
+
+    //A player may change to a team with fewer players than the team they leave.
+    pub fn check_team_change(
+        &self,
+        from: Option<Team>,
+        to: Team,
+        sizes: [usize; 2],
+    ) -> Result<(), String> {
+        if !self.mode.has_teams() {
+            return Err(format!("{} has no teams.", self.mode.name()));
+        }
+        match from {
+            Some(from) if from == to => Err(format!("Already in the {} team.", to.name())),
+            Some(from) if sizes[to.index()] >= sizes[from.index()] => Err(format!(
+                "The {} team has {} players and the {} team {}.",
+                to.name(),
+                sizes[to.index()],
+                from.name(),
+                sizes[from.index()]
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    //The team with the most frags, None for a tie.
+    pub fn leading_team(&self) -> Option<Team> {
+        let (red, blue) = (self.team_frags(Team::Red), self.team_frags(Team::Blue));
+        match red.cmp(&blue) {
+            std::cmp::Ordering::Greater => Some(Team::Red),
+            std::cmp::Ordering::Less => Some(Team::Blue),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    //The player with the most frags, None for a tie.
+    pub fn leader(&self) -> Option<u64> {
+        let mut best: Option<(u64, i32)> = None;
+        let mut tie = false;
+        for (client_id, score) in self.scores.iter() {
+            match best {
+                Some((_, frags)) if score.frags == frags => tie = true,
+                Some((_, frags)) if score.frags < frags => {}
+                _ => {
+                    best = Some((*client_id, score.frags));
+                    tie = false;
+                }
+            }
+        }
+        if tie {
+            None
+        } else {
+            best.map(|(client_id, _)| client_id)
+        }
+    }
+
+    //True when the match should end at tick.
+    pub fn is_over(&self, tick: u32) -> bool {
+        match self.phase {
+            MatchPhase::Playing { end_tick } => {
+                let limit = self.frag_limit as i32;
+                let frag_limit_reached = if self.mode.has_teams() {
+                    self.team_frags.iter().any(|frags| *frags >= limit)
+                } else {
+                    self.scores.values().any(|score| score.frags >= limit)
+                };
+                !is_sequence_newer(end_tick, tick) || (self.frag_limit > 0 && frag_limit_reached)
+            }
+            MatchPhase::Intermission { .. } => false,
+        }
+    }
+
+    //The time left in the match or the intermission.
+    pub fn remaining_secs(&self, scheduler: &TickScheduler) -> f32 {
+        let deadline = match self.phase {
+            MatchPhase::Playing { end_tick } => end_tick,
+            MatchPhase::Intermission { restart_tick, .. } => restart_tick,
+        };
+        let remaining_ticks = (deadline.wrapping_sub(scheduler.tick()) as i32).max(0);
+        remaining_ticks as f32 / scheduler.tick_rate() as f32
+    }
+
+    //Start a new match with the scores reset, returns the messages for all clients. The
+    //players are respawned by the caller.
+    pub fn restart(&mut self, scheduler: &TickScheduler) -> Vec<MultiplayerMessage> {
+        self.phase = MatchPhase::Playing {
+            end_tick: scheduler
+                .tick()
+                .wrapping_add(scheduler.ticks_in(self.time_limit_secs as f32)),
+        };
+        self.number = self.number.wrapping_add(1);
+        let mut messages = vec![];
+        for score in self.scores.values_mut() {
+            *score = Score::default();
+        }
+        for client_id in self.scores.keys() {
+            messages.push(self.score_message(*client_id));
+        }
+        self.team_frags = [0; 2];
+        messages.extend(self.team_score_messages());
+        messages.push(self.state_message(scheduler));
+        messages
+    }
+
+    pub fn state_message(&self, scheduler: &TickScheduler) -> MultiplayerMessage {
+        let (winner_id, winning_team) = match self.phase {
+            MatchPhase::Playing { .. } => (None, None),
+            MatchPhase::Intermission {
+                winner,
+                winning_team,
+                ..
+            } => (winner, winning_team),
+        };
+        MultiplayerMessage::MatchState {
+            mode: self.mode,
+            playing: self.is_playing(),
+            remaining_secs: self.remaining_secs(scheduler),
+            frag_limit: self.frag_limit,
+            winner_id,
+            winning_team,
+        }
+    }
+
+    //Messages with the scores of both teams, none without teams.
+    pub fn team_score_messages(&self) -> Vec<MultiplayerMessage> {
+        if !self.mode.has_teams() {
+            return Vec::new();
+        }
+        Team::ALL
+            .iter()
+            .map(|team| MultiplayerMessage::TeamScore {
+                team: *team,
+                frags: self.team_frags(*team),
+            })
+            .collect()
+    }
+
+    pub fn score_message(&self, client_id: u64) -> MultiplayerMessage {
+        let score = self.scores.get(&client_id).copied().unwrap_or_default();
+        MultiplayerMessage::Score {
+            client_id,
+            frags: score.frags,
+            deaths: score.deaths,
+        }
+    }
+}
+
+//The number of players in each team.
+pub(super) fn team_sizes<'a>(players: impl Iterator<Item = &'a Player>) -> [usize; 2] {
+    let mut sizes = [0; 2];
+    for team in players.filter_map(|player| player.team) {
+        sizes[team.index()] += 1;
+    }
+    sizes
+}
+
+//Move the players asking for a team change, they respawn with the new team.
+pub(super) fn change_teams(
+    scheduler: Res<TickScheduler>,
+    game: Res<Match>,
+    mut messages: EventReader<ClientMessage>,
+    client_entities: Res<ClientEntities>,
+    mut players: Query<&mut Player>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+) {
+    for ClientMessage { client_id, message } in messages.read() {
+        let MultiplayerMessage::ChangeTeam { client_id: _, team } = message else {
+            continue;
+        };
+        let sizes = team_sizes(players.iter());
+        let Some(Ok(mut player)) = client_entities
+            .0
+            .get(client_id)
+            .map(|entity| players.get_mut(*entity))
+        else {
+            continue;
+        };
+        if let Err(e) = game.check_team_change(player.team, *team, sizes) {
+            debug!("Team change of client {} rejected. {}", client_id, e);
+            continue;
+        }
+        info!("{} joined the {} team.", player.name, team.name());
+        player.team = Some(*team);
+        if player.is_alive() {
+            player.respawn_tick = Some(scheduler.tick());
+        }
+        messages_to_deliver.0.push((
+            Destination::All,
+            MultiplayerMessage::TeamAssigned {
+                client_id: *client_id,
+                team: *team,
+            },
+        ));
+    }
+}
+
+//Count the kills, end the match at the frag or time limit and restart it after the
+//intermission.
+pub(super) fn update_match(
+    scheduler: Res<TickScheduler>,
+    mut game: ResMut<Match>,
+    mut kills: EventReader<PlayerKilled>,
+    mut players: Query<&mut Player>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+) {
+    for kill in kills.read() {
+        if game.is_playing() {
+            game.record_kill(kill);
+            for client_id in [kill.victim, kill.attacker] {
+                let message = game.score_message(client_id);
+                messages_to_deliver.0.push((Destination::All, message));
+            }
+            for message in game.team_score_messages() {
+                messages_to_deliver.0.push((Destination::All, message));
+            }
+        }
+    }
+    let tick = scheduler.tick();
+    match game.phase {
+        MatchPhase::Playing { .. } if game.is_over(tick) => {
+            let winner = game.leader();
+            let winning_team = if game.mode.has_teams() {
+                game.leading_team()
+            } else {
+                None
+            };
+            info!("Match over, winner {:?} {:?}.", winner, winning_team);
+            game.phase = MatchPhase::Intermission {
+                restart_tick: tick.wrapping_add(scheduler.ticks_in(INTERMISSION_SECS)),
+                winner,
+                winning_team,
+            };
+            let message = game.state_message(&scheduler);
+            messages_to_deliver.0.push((Destination::All, message));
+        }
+        MatchPhase::Intermission { restart_tick, .. } if !is_sequence_newer(restart_tick, tick) => {
+            game.next_map();
+            info!("Match restart on {}.", game.map());
+            for message in game.restart(&scheduler) {
+                messages_to_deliver.0.push((Destination::All, message));
+            }
+            //Everyone respawns for the new match.
+            for mut player in players.iter_mut() {
+                player.respawn_tick = Some(tick);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+fn kill(victim: u64, attacker: u64, teams: Option<(Team, Team)>) -> PlayerKilled {
+    PlayerKilled {
+        victim,
+        attacker,
+        victim_team: teams.map(|(victim_team, _)| victim_team),
+        attacker_team: teams.map(|(_, attacker_team)| attacker_team),
+    }
+}
+
+#[test]
+fn test_match_scores() {
+    let mut game = Match::new(GameMode::Deathmatch, 2, 600, 1000);
+    assert_eq!(game.leader(), None);
+    game.record_kill(&kill(1, 2, None));
+    assert_eq!(
+        game.scores[&2],
+        Score {
+            frags: 1,
+            deaths: 0
+        }
+    );
+    assert_eq!(
+        game.scores[&1],
+        Score {
+            frags: 0,
+            deaths: 1
+        }
+    );
+    assert_eq!(game.leader(), Some(2));
+    //Suicide.
+    game.record_kill(&kill(2, 2, None));
+    assert_eq!(
+        game.scores[&2],
+        Score {
+            frags: 0,
+            deaths: 1
+        }
+    );
+    assert_eq!(game.leader(), None);
+    assert!(!game.is_over(10));
+    //Time limit.
+    assert!(game.is_over(1000));
+    //Frag limit.
+    game.record_kill(&kill(2, 1, None));
+    game.record_kill(&kill(2, 1, None));
+    assert!(game.is_over(10));
+    assert_eq!(game.leader(), Some(1));
+    assert!(game.team_score_messages().is_empty());
+}
+
+#[test]
+fn test_team_scores() {
+    let mut game = Match::new(GameMode::TeamDeathmatch, 2, 600, 1000);
+    assert_eq!(game.auto_team([0, 0]), Some(Team::Red));
+    assert_eq!(game.auto_team([1, 0]), Some(Team::Blue));
+    assert_eq!(game.auto_team([2, 1]), Some(Team::Blue));
+    game.record_kill(&kill(1, 2, Some((Team::Red, Team::Blue))));
+    assert_eq!(game.team_frags(Team::Blue), 1);
+    assert_eq!(game.leading_team(), Some(Team::Blue));
+    //Ties go to the team behind.
+    assert_eq!(game.auto_team([1, 1]), Some(Team::Red));
+    //Team kill.
+    game.record_kill(&kill(3, 2, Some((Team::Blue, Team::Blue))));
+    assert_eq!(game.team_frags(Team::Blue), 0);
+    assert_eq!(game.scores[&2].frags, 0);
+    assert_eq!(game.leading_team(), None);
+    //The team frag limit ends the match.
+    game.record_kill(&kill(2, 1, Some((Team::Blue, Team::Red))));
+    assert!(!game.is_over(10));
+    game.record_kill(&kill(3, 1, Some((Team::Blue, Team::Red))));
+    assert!(game.is_over(10));
+    assert_eq!(
+        Match::new(GameMode::Deathmatch, 2, 600, 1000).auto_team([0, 1]),
+        None
+    );
+}
+
+#[test]
+fn test_team_change() {
+    let game = Match::new(GameMode::TeamDeathmatch, 0, 600, 1000);
+    assert!(game.check_team_change(None, Team::Blue, [1, 1]).is_ok());
+    assert!(game
+        .check_team_change(Some(Team::Red), Team::Blue, [3, 2])
+        .is_ok());
+    //Would unbalance the teams.
+    assert!(game
+        .check_team_change(Some(Team::Red), Team::Blue, [2, 2])
+        .is_err());
+    assert!(game
+        .check_team_change(Some(Team::Red), Team::Red, [1, 0])
+        .is_err());
+    let game = Match::new(GameMode::Deathmatch, 0, 600, 1000);
+    assert!(game.check_team_change(None, Team::Red, [0, 0]).is_err());
+}
+
+#[test]
+fn test_game_mode_parse() {
+    assert_eq!(GameMode::parse("deathmatch"), Ok(GameMode::Deathmatch));
+    assert_eq!(GameMode::parse("tdm"), Ok(GameMode::TeamDeathmatch));
+    assert!(GameMode::parse("capture").is_err());
+    assert_eq!(
+        GameMode::from_u8(GameMode::Deathmatch as u8),
+        Some(GameMode::Deathmatch)
+    );
+}
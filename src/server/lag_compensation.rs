@@ -0,0 +1,102 @@
+use bevy::prelude::*;
+use std::collections::VecDeque;
+
+//Player locations are kept for this long.
+pub const HISTORY_SECS: f32 = 1.0;
+pub const DEFAULT_MAX_REWIND_MS: u32 = 250;
+pub const MAX_REWIND_LIMIT_MS: u32 = (HISTORY_SECS * 1000.) as u32;
+
+//The location of a player at the end of each tick.
+#[derive(Component, Default)]
+pub struct PositionHistory {
+    positions: VecDeque<(u32, Vec3)>,
+}
+
+impl PositionHistory {
+    pub fn record(&mut self, tick: u32, location: Vec3, capacity: usize) {
+        while self.positions.len() >= capacity.max(1) {
+            self.positions.pop_front();
+        }
+        self.positions.push_back((tick, location));
+    }
+
+    pub fn clear(&mut self) {
+        self.positions.clear();
+    }
+
+    //The location rewind ticks before tick, interpolated between the recorded ticks. Older
+    //than the history is the oldest location.
+    pub fn location_at(&self, tick: u32, rewind: f32) -> Option<Vec3> {
+        let mut newer: Option<(f32, Vec3)> = None;
+        for (recorded_tick, location) in self.positions.iter().rev() {
+            let age = tick.wrapping_sub(*recorded_tick) as i32 as f32;
+            if age >= rewind {
+                return Some(match newer {
+                    Some((newer_age, newer_location)) if age > newer_age => {
+                        let t = (rewind - newer_age) / (age - newer_age);
+                        newer_location.lerp(*location, t)
+                    }
+                    _ => *location,
+                });
+            }
+            newer = Some((age, *location));
+        }
+        newer.map(|(_, location)| location)
+    }
+}
+
+//Shots are traced against the player locations the shooter saw.
+#[derive(Resource)]
+pub struct LagCompensation {
+    pub max_rewind_ms: u32,
+}
+
+impl LagCompensation {
+    //Ticks to rewind for a shot at tick reported by the client, which renders the remote
+    //players interpolation_delay_ms behind the newest server tick it received.
+    pub fn rewind_ticks(
+        &self,
+        tick: u32,
+        reported_tick: u32,
+        interpolation_delay_ms: u16,
+        tick_rate: u32,
+    ) -> f32 {
+        //No tick when the client has not received a remote player update.
+        let reported_age = if reported_tick == 0 {
+            0.
+        } else {
+            (tick.wrapping_sub(reported_tick) as i32).max(0) as f32
+        };
+        let delay = interpolation_delay_ms as f32 * tick_rate as f32 / 1000.;
+        let max_rewind = self.max_rewind_ms as f32 * tick_rate as f32 / 1000.;
+        (reported_age + delay).min(max_rewind)
+    }
+}
+
+#[test]
+fn test_position_history() {
+    let mut history = PositionHistory::default();
+    assert_eq!(history.location_at(10, 0.), None);
+    for tick in 1..=5 {
+        history.record(tick, Vec3::new(tick as f32, 0., 0.), 4);
+    }
+    //Tick 1 was dropped.
+    assert_eq!(history.positions.len(), 4);
+    assert_eq!(history.location_at(5, 0.), Some(Vec3::new(5., 0., 0.)));
+    assert_eq!(history.location_at(5, 1.5), Some(Vec3::new(3.5, 0., 0.)));
+    assert_eq!(history.location_at(6, 2.), Some(Vec3::new(4., 0., 0.)));
+    //Beyond the history.
+    assert_eq!(history.location_at(5, 10.), Some(Vec3::new(2., 0., 0.)));
+}
+
+#[test]
+fn test_rewind_ticks() {
+    let lag = LagCompensation { max_rewind_ms: 500 };
+    //20 Hz, 2 ticks since the reported tick and 100ms of interpolation delay.
+    assert_eq!(lag.rewind_ticks(100, 98, 100, 20), 4.);
+    assert_eq!(lag.rewind_ticks(100, 0, 100, 20), 2.);
+    //A tick from the future.
+    assert_eq!(lag.rewind_ticks(100, 105, 0, 20), 0.);
+    //Limited to the maximum rewind.
+    assert_eq!(lag.rewind_ticks(100, 50, 100, 20), 10.);
+}
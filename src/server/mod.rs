@@ -1,21 +1,70 @@
+use bevy::app::{PluginsState, ScheduleRunnerPlugin};
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use renetcode::{
     NetcodeServer, ServerAuthentication, ServerConfig, ServerResult, NETCODE_KEY_BYTES,
     NETCODE_MAX_PACKET_BYTES, NETCODE_USER_DATA_BYTES,
 };
-use std::time::Duration;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::Instant;
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{collections::HashMap, thread};
-use std::{
-    net::{SocketAddr, UdpSocket},
-    time::Instant,
+
+use crate::discovery::{
+    answer_discovery, read_query, DiscoveryReply, DiscoveryResponder, DISCOVERY_PORT,
 };
+use crate::logging::add_logging;
+use crate::master::{heartbeat_packet, HEARTBEAT_SECS};
+use crate::players::{is_sequence_newer, simulate_move, Movement, MOVE_STEP};
+use crate::protocol::{Header, MultiplayerMessage, PROTOCOL_VERSION};
+use crate::token::{load_private_key, spawn_token_service};
+use crate::weapon::{trace, FIRE_INTERVAL_SECS, WEAPON_DAMAGE, WEAPON_RANGE};
+use crate::ClientId;
+use access::AccessList;
+use chat::{relay_chat, ChatLimiter};
+use console::{run_console, Console};
+use errors::{ClientErrors, ServerError};
+use game_mode::{change_teams, team_sizes, update_match, Match, Team};
+use lag_compensation::{LagCompensation, PositionHistory, HISTORY_SECS, MAX_REWIND_LIMIT_MS};
+use reconnect::ReconnectGrace;
+use scoreboard::{send_scoreboard, PingState};
+use settings::ServerOptions;
+use spawn::{choose_spawn_point, Observer};
+use tick::TickScheduler;
 
-use crate::client::*;
+pub mod access;
+pub mod chat;
+pub mod console;
+pub mod errors;
+pub mod game_mode;
+pub mod lag_compensation;
+pub mod reconnect;
+pub mod scoreboard;
+pub mod settings;
+pub mod spawn;
+pub mod tick;
 
-pub const PRIVATE_KEY: &[u8; 32] = b"an example very very secret key."; // 32-bytes
 pub const PROTOCOL_ID: u64 = 123456789;
 pub const PORT: u32 = 5000;
+pub const MAX_CLIENTS: usize = 16;
+//Movement inputs applied to a player per tick, limits the player speed.
+const MAX_INPUTS_PER_TICK: usize = 2;
+//Inputs queued beyond this are dropped.
+const MAX_QUEUED_INPUTS: usize = 8;
+pub const MAX_HEALTH: u32 = 100;
+pub const START_ARMOR: u32 = 50;
+//Armor absorbs this fraction of the damage until it is used up.
+const ARMOR_ABSORB_PERCENT: u32 = 60;
+pub const RESPAWN_SECS: f32 = 3.0;
+//A shot origin further than this from the server location is rejected, the client location
+//is ahead of the server by the unacknowledged inputs.
+const MAX_FIRE_ORIGIN_OFFSET: f32 = MOVE_STEP * MAX_QUEUED_INPUTS as f32;
+//Default interval of the netcode keep alive for each client.
+pub const DEFAULT_KEEPALIVE_SECS: f32 = 2.0;
+//Status queries answered per tick, the rest are dropped. A reply is larger than the query.
+const MAX_STATUS_REPLIES: usize = 32;
 
 #[derive(serde::Deserialize, serde::Serialize, Reflect, Clone)]
 pub struct Server {
@@ -36,164 +85,485 @@ impl Username {
         user_data
     }
 
-    fn from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Self {
+    //None when the name is not utf8.
+    fn from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<Self> {
         let mut buffer = [0u8; 8];
         buffer.copy_from_slice(&user_data[0..8]);
         let mut len = u64::from_le_bytes(buffer) as usize;
         len = len.min(NETCODE_USER_DATA_BYTES - 8);
         let data = user_data[8..len + 8].to_vec();
-        let username = String::from_utf8(data).unwrap();
-        Self(username)
+        String::from_utf8(data).ok().map(Self)
     }
 }
 
-pub fn server_main(connection: Option<&str>) {
-    let server_addr: SocketAddr = match connection {
-        Some(con) => con
-            .parse()
-            .expect(format!("Not a valid connection: {} ", con).as_str()),
-        None => format!("127.0.0.1:{}", PORT).parse().unwrap(),
+//The name and message of the day of the server.
+#[derive(Resource)]
+pub struct ServerInfo {
+    pub name: String,
+    pub motd: String,
+}
+
+pub fn server_main(options: ServerOptions) {
+    let mut app: App = App::new();
+    add_logging(&mut app, &options.log, "server.log");
+    if let Some(path) = &options.config_path {
+        info!("Server config: {}", Path::new(path).display());
+    }
+    let private_key = match load_private_key(options.key_file.as_ref()) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Could not load the server private key. {}", e);
+            return;
+        }
     };
-    //let server_addr: SocketAddr = format!("127.0.0.1:{}", PORT).parse().unwrap();
-    server(server_addr, *PRIVATE_KEY);
-}
-
-fn server(addr: SocketAddr, private_key: [u8; NETCODE_KEY_BYTES]) {
-    let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-    let config = ServerConfig {
-        current_time,
-        max_clients: 16,
-        protocol_id: PROTOCOL_ID,
-        public_addresses: vec![addr],
-        authentication: ServerAuthentication::Secure { private_key },
+    let access = match AccessList::load_default(options.config_path.as_ref()) {
+        Ok(access) => access,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
     };
-    let mut server: NetcodeServer = NetcodeServer::new(config);
-    let udp_socket = UdpSocket::bind(addr).unwrap();
-    udp_socket.set_nonblocking(true).unwrap();
-    let mut messages_to_deliver: Vec<(Destination, MultiplayerMessage)> = vec![];
-    let mut last_updated = Instant::now();
-    let mut buffer = [0u8; NETCODE_MAX_PACKET_BYTES];
-    let mut usernames: HashMap<u64, String> = HashMap::new();
-    let mut players: HashMap<u64, Player> = HashMap::new();
-    let mut last_ping = Instant::now();
+    //A bind error is reported before the token service starts.
+    let socket = match UdpSocket::bind(options.bind).and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Could not listen on {}. {}", options.bind, e);
+            return;
+        }
+    };
+    info!(
+        "{} allow and {} ban rules.",
+        access.allow.len(),
+        access.ban.len()
+    );
+    if let Err(e) = spawn_token_service(
+        options.bind,
+        options.public_addresses.clone(),
+        private_key,
+        options.client_timeout_secs,
+    ) {
+        error!("{}", e);
+        return;
+    }
+    app.add_plugins(MinimalPlugins.build().disable::<ScheduleRunnerPlugin>());
+    app.add_plugins(ServerPlugin {
+        private_key,
+        options,
+        access,
+        socket,
+    });
+    app.insert_resource(Console::spawn_stdin());
+    app.set_runner(tick_runner);
+    app.run();
+}
+
+//The headless server app is updated once per tick of the TickScheduler.
+fn tick_runner(mut app: App) -> AppExit {
+    if app.plugins_state() != PluginsState::Cleaned {
+        while app.plugins_state() == PluginsState::Adding {
+            bevy::tasks::tick_global_task_pools_on_main_thread();
+        }
+        app.finish();
+        app.cleanup();
+    }
     loop {
-        server.update(Instant::now() - last_updated);
-        messages_to_deliver.clear();
-
-        loop {
-            match udp_socket.recv_from(&mut buffer) {
-                Ok((len, addr)) => {
-                    // println!("Received decrypted message {:?} from {}.", &buffer[..len], addr);
-                    let server_result = server.process_packet(addr, &mut buffer[..len]);
-                    handle_server_result(
-                        server_result,
-                        &udp_socket,
-                        &mut messages_to_deliver,
-                        &mut usernames,
-                        &mut players,
-                    );
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                Err(e) => panic!("Socket error: {}", e),
-            };
+        app.world_mut()
+            .resource_mut::<TickScheduler>()
+            .wait_for_tick();
+        app.update();
+        if let Some(exit) = app.should_exit() {
+            return exit;
         }
+    }
+}
 
-        for (destination, message) in messages_to_deliver.iter() {
-            for client_id in server.clients_id().iter().filter(|cid| match destination {
-                Destination::All => true,
-                Destination::Player(id) => id == *cid,
-                Destination::NotPlayer(id) => id != *cid,
-            }) {
-                match message.get_buf() {
-                    Ok(buf) => {
-                        let (addr, payload) =
-                            server.generate_payload_packet(*client_id, &buf).unwrap();
-                        udp_socket.send_to(payload, addr).unwrap();
-                    }
-                    Err(e) => {
-                        eprintln!("Error buffering MultiplayerMessage: {}", e);
-                    }
-                };
+pub struct ServerPlugin {
+    pub private_key: [u8; NETCODE_KEY_BYTES],
+    pub options: ServerOptions,
+    pub access: AccessList,
+    //Bound and non blocking.
+    pub socket: UdpSocket,
+}
+
+impl Plugin for ServerPlugin {
+    fn build(&self, app: &mut App) {
+        let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let config = ServerConfig {
+            current_time,
+            max_clients: self.options.max_clients,
+            protocol_id: PROTOCOL_ID,
+            public_addresses: self.options.public_addresses.clone(),
+            authentication: ServerAuthentication::Secure {
+                private_key: self.private_key,
+            },
+        };
+        let udp_socket = self
+            .socket
+            .try_clone()
+            .expect("Could not clone the server socket.");
+        info!(
+            "{} listening on {}, up to {} players.",
+            self.options.name, self.options.bind, self.options.max_clients
+        );
+        let scheduler = TickScheduler::new(self.options.tick_rate);
+        info!("Server tick rate {} Hz.", scheduler.tick_rate());
+        let max_rewind_ms = self.options.max_rewind_ms.min(MAX_REWIND_LIMIT_MS);
+        info!("Lag compensation up to {} ms.", max_rewind_ms);
+        app.insert_resource(LagCompensation { max_rewind_ms });
+        let options = &self.options;
+        info!(
+            "{} frag limit {} time limit {} s.",
+            options.mode.name(),
+            options.frag_limit,
+            options.time_limit_secs
+        );
+        let mut game = Match::new(
+            options.mode,
+            options.frag_limit,
+            options.time_limit_secs,
+            scheduler.ticks_in(options.time_limit_secs as f32),
+        );
+        game.friendly_fire = options.friendly_fire;
+        game.maps = options.maps.clone();
+        app.insert_resource(game);
+        app.insert_resource(ServerInfo {
+            name: options.name.clone(),
+            motd: options.motd.clone(),
+        });
+        app.insert_resource(NetServer {
+            server: NetcodeServer::new(config),
+            socket: udp_socket,
+            buffer: [0u8; NETCODE_MAX_PACKET_BYTES],
+            last_updated: Instant::now(),
+            pending: HashMap::new(),
+            kicks: vec![],
+            access: self.access.clone(),
+            keepalive_secs: options.keepalive_secs,
+            status_queries: vec![],
+            public_port: options.public_addresses[0].port(),
+            max_clients: options.max_clients as u16,
+            masters: options.masters.clone(),
+        });
+        for master in options.masters.iter() {
+            info!("Sending heartbeats to the master server {}.", master);
+        }
+        if options.lan_discovery {
+            match DiscoveryResponder::bind(
+                options.bind.ip(),
+                options.public_addresses[0].port(),
+                options.max_clients,
+            ) {
+                Ok(responder) => {
+                    info!("Answering LAN discovery on port {}.", DISCOVERY_PORT);
+                    app.insert_resource(responder);
+                }
+                Err(e) => warn!("No LAN discovery. {}", e),
             }
         }
+        app.insert_resource(ReconnectGrace::new(
+            scheduler.ticks_in(options.reconnect_grace_secs as f32),
+        ));
+        app.insert_resource(scheduler);
+        app.init_resource::<ClientEntities>();
+        app.init_resource::<MessagesToDeliver>();
+        app.init_resource::<PingState>();
+        app.init_resource::<ClientErrors>();
+        app.add_event::<ClientConnected>();
+        app.add_event::<ClientDisconnected>();
+        app.add_event::<ClientMessage>();
+        app.add_event::<PlayerKilled>();
+        app.add_systems(
+            Update,
+            (
+                run_console.run_if(resource_exists::<Console>),
+                receive_packets,
+                spawn_connected_players,
+                despawn_disconnected_players,
+                handle_client_messages,
+                resolve_shots.run_if(match_playing),
+                change_teams,
+                relay_chat,
+                update_match,
+                respawn_players,
+                simulate_players,
+                send_scoreboard,
+                deliver_messages,
+                answer_status_queries,
+                send_heartbeats,
+                answer_discovery.run_if(resource_exists::<DiscoveryResponder>),
+            )
+                .chain(),
+        );
+        app.add_systems(Last, end_tick);
+    }
+}
+
+//The netcode server and its socket.
+#[derive(Resource)]
+pub struct NetServer {
+    server: NetcodeServer,
+    socket: UdpSocket,
+    buffer: [u8; NETCODE_MAX_PACKET_BYTES],
+    last_updated: Instant,
+    //Names of the connected clients waiting for the protocol handshake.
+    pending: HashMap<u64, String>,
+    //Clients to disconnect with the reason told to them.
+    kicks: Vec<(u64, String)>,
+    access: AccessList,
+    keepalive_secs: f32,
+    //Status queries of the server browser by address and nonce, answered this tick.
+    status_queries: Vec<(SocketAddr, u64)>,
+    public_port: u16,
+    max_clients: u16,
+    //The master servers listing this server.
+    masters: Vec<SocketAddr>,
+}
+
+//Player entities by client id.
+#[derive(Resource, Default)]
+pub struct ClientEntities(HashMap<u64, Entity>);
+
+//Messages for the clients, sent at the end of the tick.
+#[derive(Resource, Default)]
+pub struct MessagesToDeliver(Vec<(Destination, MultiplayerMessage)>);
 
-        let lapsed = Instant::now() - last_ping;
-        if lapsed.as_secs_f32() > 2.0 {
-            last_ping = Instant::now();
-            for client_id in server.clients_id().into_iter() {
-                let server_result = server.update_client(client_id);
+#[derive(Event)]
+pub struct ClientConnected {
+    pub client_id: u64,
+    pub name: String,
+}
+
+#[derive(Event)]
+pub struct ClientDisconnected {
+    pub client_id: u64,
+}
+
+#[derive(Event)]
+pub struct ClientMessage {
+    pub client_id: u64,
+    pub message: MultiplayerMessage,
+}
+
+#[derive(Event)]
+pub struct PlayerKilled {
+    pub victim: u64,
+    pub attacker: u64,
+    pub victim_team: Option<Team>,
+    pub attacker_team: Option<Team>,
+}
+
+#[derive(SystemParam)]
+pub struct ServerEvents<'w> {
+    connected: EventWriter<'w, ClientConnected>,
+    disconnected: EventWriter<'w, ClientDisconnected>,
+    messages: EventWriter<'w, ClientMessage>,
+}
+
+fn receive_packets(
+    mut net: ResMut<NetServer>,
+    scheduler: Res<TickScheduler>,
+    mut errors: ResMut<ClientErrors>,
+    mut events: ServerEvents,
+) {
+    let errors = errors.as_mut();
+    let NetServer {
+        server,
+        socket,
+        buffer,
+        last_updated,
+        pending,
+        kicks,
+        access,
+        keepalive_secs,
+        status_queries,
+        ..
+    } = net.as_mut();
+    server.update(Instant::now() - *last_updated);
+    *last_updated = Instant::now();
+    //Clients with a different protocol version.
+    let mut rejected: Vec<u64> = vec![];
+
+    loop {
+        match socket.recv_from(buffer) {
+            Ok((len, addr)) => {
+                //Not a netcode packet, answered by answer_status_queries.
+                if let Some(nonce) = read_query(&buffer[..len]) {
+                    if status_queries.len() < MAX_STATUS_REPLIES {
+                        status_queries.push((addr, nonce));
+                    }
+                    continue;
+                }
+                // println!("Received decrypted message {:?} from {}.", &buffer[..len], addr);
+                let server_result = server.process_packet(addr, &mut buffer[..len]);
                 handle_server_result(
                     server_result,
-                    &udp_socket,
-                    &mut messages_to_deliver,
-                    &mut usernames,
-                    &mut players,
+                    socket,
+                    (pending, kicks, access, errors),
+                    &mut rejected,
+                    &mut events,
                 );
             }
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            //An ICMP unreachable from a client that went away (Windows), the packets of the
+            //other clients are still queued.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                errors.report(ServerError::Receive(e));
+            }
+            //Try again next tick.
+            Err(e) => {
+                errors.report(ServerError::Receive(e));
+                break;
+            }
+        };
+    }
+
+    if scheduler
+        .tick()
+        .is_multiple_of(scheduler.ticks_in(*keepalive_secs))
+    {
+        for client_id in server.clients_id().into_iter() {
+            let server_result = server.update_client(client_id);
+            handle_server_result(
+                server_result,
+                socket,
+                (pending, kicks, access, errors),
+                &mut rejected,
+                &mut events,
+            );
         }
+    }
+
+    for client_id in rejected {
+        let mismatch = MultiplayerMessage::VersionMismatch {
+            server_version: PROTOCOL_VERSION,
+        };
+        send_now(server, socket, client_id, &mismatch, errors);
+        let server_result = server.disconnect(client_id);
+        handle_server_result(
+            server_result,
+            socket,
+            (pending, kicks, access, errors),
+            &mut vec![],
+            &mut events,
+        );
+    }
+
+    for client_id in errors.take_misbehaving() {
+        warn!("Client {} disconnected for errors.", client_id);
+        kicks.push((client_id, "Too many invalid messages.".to_string()));
+    }
+
+    //Kicked clients are told the reason before the disconnect.
+    while let Some((client_id, reason)) = kicks.pop() {
+        send_now(
+            server,
+            socket,
+            client_id,
+            &MultiplayerMessage::Kicked { reason },
+            errors,
+        );
+        send_now(
+            server,
+            socket,
+            client_id,
+            &MultiplayerMessage::Disconnect { client_id },
+            errors,
+        );
+        let server_result = server.disconnect(client_id);
+        handle_server_result(
+            server_result,
+            socket,
+            (pending, kicks, access, errors),
+            &mut vec![],
+            &mut events,
+        );
+    }
+}
+
+//Send a message to a client right away instead of at the end of the tick.
+fn send_now(
+    server: &mut NetcodeServer,
+    socket: &UdpSocket,
+    client_id: u64,
+    message: &MultiplayerMessage,
+    errors: &mut ClientErrors,
+) {
+    match message.get_buf() {
+        Ok(buf) => match server.generate_payload_packet(client_id, &buf) {
+            Ok((addr, payload)) => send_packet(socket, payload, addr, errors),
+            Err(e) => errors.report(ServerError::Packet {
+                client_id,
+                error: e.to_string(),
+            }),
+        },
+        Err(e) => errors.report(ServerError::Encode(e)),
+    }
+}
 
-        last_updated = Instant::now();
-        thread::sleep(Duration::from_millis(50));
+fn send_packet(socket: &UdpSocket, payload: &[u8], addr: SocketAddr, errors: &mut ClientErrors) {
+    if let Err(error) = socket.send_to(payload, addr) {
+        errors.report(ServerError::Send { addr, error });
     }
 }
 
+//The NetServer state handle_server_result updates.
+type ConnectionState<'a> = (
+    &'a mut HashMap<u64, String>,
+    &'a mut Vec<(u64, String)>,
+    &'a AccessList,
+    &'a mut ClientErrors,
+);
+
 fn handle_server_result(
     server_result: ServerResult,
     socket: &UdpSocket,
-    messages_to_deliver: &mut Vec<(Destination, MultiplayerMessage)>,
-    usernames: &mut HashMap<u64, String>,
-    players: &mut HashMap<u64, Player>,
+    (pending, kicks, access, errors): ConnectionState,
+    rejected: &mut Vec<u64>,
+    events: &mut ServerEvents,
 ) {
     match server_result {
         ServerResult::Payload { client_id, payload } => {
             //let text = String::from_utf8(payload.to_vec()).unwrap();
-            let multiplayer_message = MultiplayerMessage::get(payload);
-            match multiplayer_message {
-                Ok(mess) => {
-                    let id = mess.get_id();
-                    //let username = usernames.get(&client_id).unwrap();
-                    let opt_player: Option<&mut Player> = players.get_mut(&client_id);
-                    match opt_player {
-                        Some(player) => {
-                            let username: &str = player.name.as_ref();
-                            //println!( "Client {} ({}) sent message {:?}.", username, client_id, text);
-                            println!("Client {} ({}) sent message {:?}.", username, client_id, id);
-                            match mess {
-                                //Note: Client connects and disconnects are handled at the renet client
-                                //level, so the server should not receive these messages.
-                                MultiplayerMessage::Connect { .. } => println!(
-                                   "Client should not send MultiplayerMessage::Connect to the server."
-                                ),
-                                MultiplayerMessage::Disconnect { .. } => println!(
-                                    "Client should not send MultiplayerMessage::Disconnect to the server."
-                                ),
-                                MultiplayerMessage::Move {
-                                    client_id,
-                                    location,
-                                } => {
-                                    println!("Player moved so setting player location");
-                                    player.location = location;
-                                    messages_to_deliver.push((Destination::NotPlayer(client_id), mess));
-                                },
-                                MultiplayerMessage::None => {
-                                    eprintln!("MultiplayerMessage::None received at the server from cid {}", client_id);
-                                }
-                            };
-                        }
-                        None => {
-                            println!("Player does not exist! Can not move.");
-                        }
+            if let Ok(header) = Header::read(payload) {
+                if header.version != PROTOCOL_VERSION {
+                    info!(
+                        "Client {} protocol version {} does not match {}, disconnecting.",
+                        client_id, header.version, PROTOCOL_VERSION
+                    );
+                    if !rejected.contains(&client_id) {
+                        rejected.push(client_id);
                     }
+                    return;
                 }
-                _ => {
-                    println!("multiplayer message error??")
+            }
+            let message = match MultiplayerMessage::get(payload) {
+                Ok(message) => message,
+                Err(error) => {
+                    errors.report(ServerError::InvalidMessage { client_id, error });
+                    return;
                 }
             };
-            //let text = format!("{}: {}", username, text);
-            //messages_to_deliver.push(text);
+            //The handshake is complete with the first message of the matching version, the
+            //Hello is unreliable and may be lost.
+            if let Some(name) = pending.remove(&client_id) {
+                events.connected.send(ClientConnected { client_id, name });
+            } else if matches!(message, MultiplayerMessage::Hello {}) {
+                debug!("Duplicate hello from client {}.", client_id);
+            }
+            if !matches!(message, MultiplayerMessage::Hello {}) {
+                events.messages.send(ClientMessage { client_id, message });
+            }
         }
         ServerResult::PacketToSend { payload, addr } => {
-            socket.send_to(payload, addr).unwrap();
+            send_packet(socket, payload, addr, errors);
         }
         ServerResult::ClientConnected {
             client_id,
@@ -201,36 +571,523 @@ fn handle_server_result(
             payload,
             addr,
         } => {
-            let username = Username::from_user_data(&user_data);
-            println!("Client {} with id {} connected.", username.0, client_id);
-            //Store references to new player.
-            usernames.insert(client_id, username.0.clone());
-            let player: Player = initialise_new_player(players, username.0);
-            players.insert(client_id, player);
             //Acknowledge ClientConnected message.
-            socket.send_to(payload, addr).unwrap();
-            //Send connect messages to the existing players and the new player.
-            push_new_client_messages(client_id, messages_to_deliver, players);
+            send_packet(socket, payload, addr, errors);
+            let Some(username) = Username::from_user_data(&user_data) else {
+                errors.report(ServerError::InvalidUsername { client_id });
+                kicks.push((client_id, "Invalid player name.".to_string()));
+                return;
+            };
+            info!("Client {} with id {} connected.", username.0, client_id);
+            if let Err(reason) = access.check(&username.0, client_id, addr.ip()) {
+                info!("Client {} refused. {}", client_id, reason);
+                kicks.push((client_id, reason));
+                return;
+            }
+            //The player is spawned after the protocol handshake.
+            pending.insert(client_id, username.0);
         }
         ServerResult::ClientDisconnected {
             client_id,
             addr,
             payload,
         } => {
-            println!("Client {} disconnected.", client_id);
-            usernames.remove_entry(&client_id);
-            players.remove_entry(&client_id);
+            info!("Client {} disconnected.", client_id);
             //Acknowledge disconnect.
             if let Some(payload) = payload {
-                socket.send_to(payload, addr).unwrap();
+                send_packet(socket, payload, addr, errors);
             }
-            push_disconnect_client_messages(client_id, messages_to_deliver);
+            pending.remove(&client_id);
+            errors.remove(client_id);
+            events.disconnected.send(ClientDisconnected { client_id });
         }
 
         ServerResult::None => {}
     }
 }
 
+fn spawn_connected_players(
+    mut commands: Commands,
+    mut connected: EventReader<ClientConnected>,
+    mut client_entities: ResMut<ClientEntities>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+    players: Query<(&ClientId, &Player)>,
+    (scheduler, mut game): (Res<TickScheduler>, ResMut<Match>),
+    (info, net, mut grace): (Res<ServerInfo>, Res<NetServer>, ResMut<ReconnectGrace>),
+) {
+    let mut num = get_player_num(players.iter().map(|(_, player)| player));
+    let mut observers = observers(players.iter().map(|(_, player)| player));
+    let mut sizes = team_sizes(players.iter().map(|(_, player)| player));
+    for ClientConnected { client_id, name } in connected.read() {
+        num += 1;
+        //Store references to new player.
+        let mut player: Player =
+            initialise_new_player(num, name.clone(), choose_spawn_point(&observers));
+        player.team = game.auto_team(sizes);
+        player.ip = net.server.client_addr(*client_id).map(|addr| addr.ip());
+        let mut score = Default::default();
+        if let Some(ip) = player.ip {
+            if let Some((kept, team)) = grace.take(name, ip, game.number, scheduler.tick()) {
+                info!("Client {} returned, score and team restored.", name);
+                score = kept;
+                if player.team.is_some() && team.is_some() {
+                    player.team = team;
+                }
+            }
+        }
+        if let Some(team) = player.team {
+            sizes[team.index()] += 1;
+        }
+        observers.push(Observer {
+            location: player.location,
+            rotation: player.rotation,
+        });
+        //Send connect messages to the existing players and the new player.
+        push_new_client_messages(*client_id, &player, &mut messages_to_deliver.0, &players);
+        //The match state and the scores for the new player.
+        game.scores.insert(*client_id, score);
+        messages_to_deliver.0.push((
+            Destination::Player(*client_id),
+            game.state_message(&scheduler),
+        ));
+        for (c_id, _) in players.iter() {
+            messages_to_deliver
+                .0
+                .push((Destination::Player(*client_id), game.score_message(c_id.id)));
+        }
+        messages_to_deliver
+            .0
+            .push((Destination::All, game.score_message(*client_id)));
+        for message in game.team_score_messages() {
+            messages_to_deliver
+                .0
+                .push((Destination::Player(*client_id), message));
+        }
+        if !info.motd.is_empty() {
+            messages_to_deliver
+                .0
+                .push(chat::server_notice(*client_id, &info.motd));
+        }
+        let entity = commands
+            .spawn((
+                Name::new(name.clone()),
+                ClientId { id: *client_id },
+                player,
+                PositionHistory::default(),
+                ChatLimiter::default(),
+            ))
+            .id();
+        client_entities.0.insert(*client_id, entity);
+    }
+}
+
+fn despawn_disconnected_players(
+    mut commands: Commands,
+    mut disconnected: EventReader<ClientDisconnected>,
+    mut client_entities: ResMut<ClientEntities>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+    mut game: ResMut<Match>,
+    (players, mut grace, scheduler): (Query<&Player>, ResMut<ReconnectGrace>, Res<TickScheduler>),
+) {
+    for ClientDisconnected { client_id } in disconnected.read() {
+        let score = game.scores.remove(client_id).unwrap_or_default();
+        //Clients rejected at the handshake were never spawned.
+        if let Some(entity) = client_entities.0.remove(client_id) {
+            if let Ok(player) = players.get(entity) {
+                if let Some(ip) = player.ip {
+                    grace.keep(
+                        &player.name,
+                        ip,
+                        score,
+                        player.team,
+                        game.number,
+                        scheduler.tick(),
+                    );
+                }
+            }
+            commands.entity(entity).despawn_recursive();
+            push_disconnect_client_messages(*client_id, &mut messages_to_deliver.0);
+        }
+    }
+}
+
+fn handle_client_messages(
+    mut messages: EventReader<ClientMessage>,
+    client_entities: Res<ClientEntities>,
+    mut players: Query<&mut Player>,
+    game: Res<Match>,
+    ping: Res<PingState>,
+    mut errors: ResMut<ClientErrors>,
+) {
+    for ClientMessage { client_id, message } in messages.read() {
+        let client_id = *client_id;
+        let id = message.get_id();
+        let opt_player = client_entities
+            .0
+            .get(&client_id)
+            .and_then(|entity| players.get_mut(*entity).ok());
+        match opt_player {
+            Some(mut player) => {
+                let username: &str = player.name.as_ref();
+                //println!( "Client {} ({}) sent message {:?}.", username, client_id, text);
+                debug!("Client {} ({}) sent message {:?}.", username, client_id, id);
+                match message {
+                    MultiplayerMessage::Input {
+                        client_id: _,
+                        sequence,
+                        movement,
+                        rotation,
+                    } => {
+                        if !player.is_alive() || !game.is_playing() {
+                            //Inputs sent before the client learnt of the death or the end of
+                            //the match.
+                        } else if player.inputs.len() >= MAX_QUEUED_INPUTS {
+                            warn!("Input queue full for client {}, input dropped.", client_id);
+                        } else {
+                            player.inputs.push_back((*sequence, *movement, *rotation));
+                        }
+                    }
+                    MultiplayerMessage::Rotate {
+                        client_id: _,
+                        rotation,
+                    } => player.set_rotation(*rotation),
+                    //Handled by resolve_shots.
+                    MultiplayerMessage::Fire { .. } => {}
+                    //Handled by change_teams.
+                    MultiplayerMessage::ChangeTeam { .. } => {}
+                    //Handled by relay_chat.
+                    MultiplayerMessage::Chat { .. } => {}
+                    MultiplayerMessage::Pong {
+                        client_id: _,
+                        sequence,
+                    } => {
+                        if let Some(ping_ms) = ping.round_trip_ms(*sequence, Instant::now()) {
+                            player.ping_ms = ping_ms;
+                        }
+                    }
+                    //Note: Client connects and disconnects are handled at the renet client
+                    //level, so the server should not receive these messages.
+                    MultiplayerMessage::Connect { .. }
+                    | MultiplayerMessage::Disconnect { .. }
+                    | MultiplayerMessage::Move { .. }
+                    | MultiplayerMessage::Hit { .. }
+                    | MultiplayerMessage::Damage { .. }
+                    | MultiplayerMessage::Died { .. }
+                    | MultiplayerMessage::Respawned { .. }
+                    | MultiplayerMessage::MatchState { .. }
+                    | MultiplayerMessage::Score { .. }
+                    | MultiplayerMessage::TeamAssigned { .. }
+                    | MultiplayerMessage::TeamScore { .. }
+                    | MultiplayerMessage::Ping { .. }
+                    | MultiplayerMessage::Scoreboard { .. }
+                    | MultiplayerMessage::Kicked { .. }
+                    | MultiplayerMessage::VersionMismatch { .. } => {
+                        errors.report(ServerError::UnexpectedMessage { client_id, id })
+                    }
+                    MultiplayerMessage::Hello {} => {
+                        debug!("Client {} is already connected.", client_id)
+                    }
+                };
+            }
+            None => {
+                debug!("Player does not exist! Can not move.");
+            }
+        }
+    }
+}
+
+//Trace the shots against the hitboxes of the other players, rewound to the time the shooter
+//saw them.
+fn resolve_shots(
+    (scheduler, game): (Res<TickScheduler>, Res<Match>),
+    lag_compensation: Res<LagCompensation>,
+    mut messages: EventReader<ClientMessage>,
+    client_entities: Res<ClientEntities>,
+    mut players: Query<(&ClientId, &mut Player, &PositionHistory)>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+    mut kills: EventWriter<PlayerKilled>,
+) {
+    for ClientMessage { client_id, message } in messages.read() {
+        let MultiplayerMessage::Fire {
+            client_id: _,
+            tick,
+            interpolation_delay_ms,
+            origin,
+            direction,
+        } = message
+        else {
+            continue;
+        };
+        let Some(entity) = client_entities.0.get(client_id) else {
+            continue;
+        };
+        let shooter_team = if let Ok((_, mut shooter, _)) = players.get_mut(*entity) {
+            let min_interval = scheduler.ticks_in(FIRE_INTERVAL_SECS);
+            if let Err(e) = shooter.check_fire(scheduler.tick(), min_interval, *origin, *direction)
+            {
+                debug!("Shot from client {} rejected. {}", client_id, e);
+                continue;
+            }
+            shooter.team
+        } else {
+            continue;
+        };
+        let direction = direction.normalize();
+        let rewind = lag_compensation.rewind_ticks(
+            scheduler.tick(),
+            *tick,
+            *interpolation_delay_ms,
+            scheduler.tick_rate(),
+        );
+        let targets = players
+            .iter()
+            .filter(|(cid, player, _)| cid.id != *client_id && player.is_alive())
+            .map(|(cid, player, history)| {
+                let location = history
+                    .location_at(scheduler.tick(), rewind)
+                    .unwrap_or(player.location);
+                ((cid.id, player.team), location)
+            });
+        let hit = trace(*origin, direction, targets);
+        let distance = hit.map_or(WEAPON_RANGE, |(_, distance)| distance);
+        //Team mates stop the shot but are not damaged without friendly fire.
+        let target_id = hit
+            .filter(|((_, team), _)| {
+                game.friendly_fire || shooter_team.is_none() || *team != shooter_team
+            })
+            .map(|((target_id, _), _)| target_id);
+        messages_to_deliver.0.push((
+            Destination::All,
+            MultiplayerMessage::Hit {
+                shooter_id: *client_id,
+                origin: *origin,
+                point: *origin + direction * distance,
+                target_id,
+            },
+        ));
+        let Some(target_id) = target_id else {
+            continue;
+        };
+        let Some(Ok((_, mut target, _))) = client_entities
+            .0
+            .get(&target_id)
+            .map(|entity| players.get_mut(*entity))
+        else {
+            continue;
+        };
+        let respawn_tick = scheduler
+            .tick()
+            .wrapping_add(scheduler.ticks_in(RESPAWN_SECS));
+        let killed = target.apply_damage(WEAPON_DAMAGE, respawn_tick);
+        messages_to_deliver.0.push((
+            Destination::Player(target_id),
+            MultiplayerMessage::Damage {
+                client_id: target_id,
+                attacker_id: *client_id,
+                amount: WEAPON_DAMAGE,
+                health: target.health,
+                armor: target.armor,
+            },
+        ));
+        if killed {
+            info!("{} was killed by client {}.", target.name, client_id);
+            kills.send(PlayerKilled {
+                victim: target_id,
+                attacker: *client_id,
+                victim_team: target.team,
+                attacker_team: shooter_team,
+            });
+            messages_to_deliver.0.push((
+                Destination::All,
+                MultiplayerMessage::Died {
+                    client_id: target_id,
+                    attacker_id: *client_id,
+                    respawn_secs: RESPAWN_SECS,
+                },
+            ));
+        }
+    }
+}
+
+fn match_playing(game: Res<Match>) -> bool {
+    game.is_playing()
+}
+
+//Respawn the dead players once their respawn tick is reached.
+fn respawn_players(
+    scheduler: Res<TickScheduler>,
+    mut players: Query<(&ClientId, &mut Player, &mut PositionHistory)>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+) {
+    let mut observers = observers(players.iter().map(|(_, player, _)| player));
+    for (client_id, mut player, mut history) in players.iter_mut() {
+        match player.respawn_tick {
+            Some(respawn_tick) if !is_sequence_newer(respawn_tick, scheduler.tick()) => {
+                let location = choose_spawn_point(&observers);
+                player.respawn(location);
+                history.clear();
+                observers.push(Observer {
+                    location,
+                    rotation: player.rotation,
+                });
+                messages_to_deliver.0.push((
+                    Destination::All,
+                    MultiplayerMessage::Respawned {
+                        client_id: client_id.id,
+                        location,
+                        health: player.health,
+                        armor: player.armor,
+                    },
+                ));
+            }
+            _ => {}
+        }
+    }
+}
+
+//The living players, for the spawn point selection.
+fn observers<'a>(players: impl Iterator<Item = &'a Player>) -> Vec<Observer> {
+    players
+        .filter(|player| player.is_alive())
+        .map(|player| Observer {
+            location: player.location,
+            rotation: player.rotation,
+        })
+        .collect()
+}
+
+//Apply the queued movement inputs and broadcast the resulting player locations.
+fn simulate_players(
+    scheduler: Res<TickScheduler>,
+    mut players: Query<(&ClientId, &mut Player, &mut PositionHistory)>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+) {
+    let history_ticks = scheduler.ticks_in(HISTORY_SECS) as usize;
+    for (client_id, mut player, mut history) in players.iter_mut() {
+        if !player.is_alive() {
+            continue;
+        }
+        let moved = player.simulate_inputs();
+        history.record(scheduler.tick(), player.location, history_ticks);
+        //A stopped player is sent once more so clients do not extrapolate the movement.
+        let was_moving = std::mem::replace(&mut player.moving, moved);
+        let rotated = std::mem::take(&mut player.rotated);
+        if moved || was_moving || rotated {
+            messages_to_deliver.0.push((
+                Destination::All,
+                MultiplayerMessage::Move {
+                    client_id: client_id.id,
+                    tick: scheduler.tick(),
+                    sequence: player.last_sequence,
+                    location: player.location,
+                    rotation: player.rotation,
+                },
+            ));
+        }
+    }
+}
+
+fn deliver_messages(
+    mut net: ResMut<NetServer>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+    mut errors: ResMut<ClientErrors>,
+) {
+    let NetServer { server, socket, .. } = net.as_mut();
+    for (destination, message) in messages_to_deliver.0.drain(..) {
+        for client_id in server.clients_id().iter().filter(|cid| match destination {
+            Destination::All => true,
+            Destination::Player(id) => id == **cid,
+            Destination::NotPlayer(id) => id != **cid,
+        }) {
+            send_now(server, socket, *client_id, &message, &mut errors);
+        }
+    }
+}
+
+//Reply to the status queries of the server browser on the game socket.
+fn answer_status_queries(
+    mut net: ResMut<NetServer>,
+    info: Res<ServerInfo>,
+    game: Res<Match>,
+    players: Query<&ClientId>,
+    mut errors: ResMut<ClientErrors>,
+) {
+    if net.status_queries.is_empty() {
+        return;
+    }
+    let reply = DiscoveryReply::new(
+        &info,
+        &game,
+        net.public_port,
+        players.iter().count(),
+        net.max_clients,
+    );
+    let NetServer {
+        socket,
+        status_queries,
+        ..
+    } = net.as_mut();
+    for (addr, nonce) in status_queries.drain(..) {
+        match reply.packet(nonce) {
+            Ok(packet) => send_packet(socket, &packet, addr, &mut errors),
+            Err(error) => errors.report(ServerError::Encode(error)),
+        }
+    }
+}
+
+//Tell the master servers this server is up, from the game socket so the master lists the
+//address clients reach.
+fn send_heartbeats(
+    net: Res<NetServer>,
+    scheduler: Res<TickScheduler>,
+    info: Res<ServerInfo>,
+    game: Res<Match>,
+    players: Query<&ClientId>,
+    mut errors: ResMut<ClientErrors>,
+) {
+    if net.masters.is_empty()
+        || !scheduler
+            .tick()
+            .is_multiple_of(scheduler.ticks_in(HEARTBEAT_SECS))
+    {
+        return;
+    }
+    let reply = DiscoveryReply::new(
+        &info,
+        &game,
+        net.public_port,
+        players.iter().count(),
+        net.max_clients,
+    );
+    match heartbeat_packet(&reply) {
+        Ok(packet) => {
+            for master in net.masters.iter() {
+                send_packet(&net.socket, &packet, *master, &mut errors);
+            }
+        }
+        Err(error) => errors.report(ServerError::Encode(error)),
+    }
+}
+
+fn end_tick(mut scheduler: ResMut<TickScheduler>) {
+    if scheduler.end_tick() {
+        warn!(
+            "Tick {} overran the tick duration {:?}.",
+            scheduler.tick(),
+            scheduler.tick_duration()
+        );
+    }
+    if let Some(stats) = scheduler.take_stats() {
+        debug!(
+            "Ticks: {} overruns: {} average: {:?} max: {:?}",
+            stats.ticks,
+            stats.overruns,
+            stats.average(),
+            stats.max
+        );
+    }
+}
+
 fn push_disconnect_client_messages(
     disconnect_client_id: u64,
     messages_to_deliver: &mut Vec<(Destination, MultiplayerMessage)>,
@@ -243,15 +1100,16 @@ fn push_disconnect_client_messages(
 
 fn push_new_client_messages(
     new_client_id: u64,
+    new_player: &Player,
     messages_to_deliver: &mut Vec<(Destination, MultiplayerMessage)>,
-    players: &mut HashMap<u64, Player>,
+    players: &Query<(&ClientId, &Player)>,
 ) {
-    let new_player: &Player = players.get(&new_client_id).unwrap();
     let msg = MultiplayerMessage::Connect {
         client_id: new_client_id,
         location: new_player.location,
-        direction: new_player.direction,
+        rotation: new_player.rotation,
         name: new_player.name.clone(),
+        team: new_player.team,
     };
 
     //Send the new player connect to itself and all existing players.
@@ -259,53 +1117,175 @@ fn push_new_client_messages(
 
     //Send Multiplayer::Connect to the new client for all existing players.
     for (c_id, player) in players.iter() {
-        if *c_id != new_client_id {
-            //get the details of an existing player
-            let existing_player_msg = MultiplayerMessage::Connect {
-                client_id: *c_id,
-                location: player.location,
-                direction: player.direction,
-                name: player.name.clone(),
-            };
-            //send the message to the new player.
-            messages_to_deliver.push((Destination::Player(new_client_id), existing_player_msg));
-            println!("send message for existing player:{}", player.name);
-        }
+        //get the details of an existing player
+        let existing_player_msg = MultiplayerMessage::Connect {
+            client_id: c_id.id,
+            location: player.location,
+            rotation: player.rotation,
+            name: player.name.clone(),
+            team: player.team,
+        };
+        //send the message to the new player.
+        messages_to_deliver.push((Destination::Player(new_client_id), existing_player_msg));
+        debug!("send message for existing player:{}", player.name);
     }
 }
 
-//Calculate the spawn point for the new player.
-fn initialise_new_player(players: &mut HashMap<u64, Player>, name: String) -> Player {
-    let mut num = get_player_num(players) as f32;
-    num = num + 1.0;
-    Player {
-        location: Vec3::new(num * 4., 4., 0.),
-        direction: Vec3::new(0., 0., 0.),
-        name,
-        num: num as u8,
-    }
+fn initialise_new_player(num: u8, name: String, location: Vec3) -> Player {
+    Player::new(name, num, location)
 }
 
-//Used to calculate the spawn point.
-fn get_player_num(players: &HashMap<u64, Player>) -> u8 {
-    players.iter().fold(0, |max_num, player| {
-        if player.1.num > max_num {
-            player.1.num
+//The highest player number.
+fn get_player_num<'a>(players: impl Iterator<Item = &'a Player>) -> u8 {
+    players.fold(0, |max_num, player| {
+        if player.num > max_num {
+            player.num
         } else {
             max_num
         }
     })
 }
 
+#[derive(Component)]
 struct Player {
     location: Vec3,
-    direction: Vec3,
     name: String,
-    //used to calculate the starting point of littleman.
+    //The player number, in order of connection.
     num: u8,
+    //Look rotation (yaw, pitch) from the last input or Rotate message.
+    rotation: Vec2,
+    //Rotated since the last broadcast.
+    rotated: bool,
+    //Movement inputs (sequence, movement, rotation) waiting for simulation.
+    inputs: VecDeque<(u32, Movement, Vec2)>,
+    last_sequence: u32,
+    //Moved in the last tick.
+    moving: bool,
+    last_fire_tick: Option<u32>,
+    health: u32,
+    armor: u32,
+    //Dead until the respawn tick.
+    respawn_tick: Option<u32>,
+    //None without a team mode.
+    team: Option<Team>,
+    //Round trip time of the last answered ping.
+    ping_ms: u16,
+    //The client address, a returning player is matched by name and address.
+    ip: Option<IpAddr>,
 }
 
-enum Destination {
+impl Player {
+    fn new(name: String, num: u8, location: Vec3) -> Player {
+        Player {
+            location,
+            name,
+            num,
+            rotation: Vec2::ZERO,
+            rotated: false,
+            inputs: VecDeque::new(),
+            last_sequence: 0,
+            moving: false,
+            last_fire_tick: None,
+            health: MAX_HEALTH,
+            armor: START_ARMOR,
+            respawn_tick: None,
+            team: None,
+            ping_ms: 0,
+            ip: None,
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.respawn_tick.is_none()
+    }
+
+    //Armor absorbs part of the damage. Returns true when the damage killed the player, who
+    //respawns at respawn_tick.
+    fn apply_damage(&mut self, amount: u32, respawn_tick: u32) -> bool {
+        if !self.is_alive() {
+            return false;
+        }
+        let absorbed = (amount * ARMOR_ABSORB_PERCENT / 100).min(self.armor);
+        self.armor -= absorbed;
+        self.health = self.health.saturating_sub(amount - absorbed);
+        if self.health == 0 {
+            self.respawn_tick = Some(respawn_tick);
+            self.inputs.clear();
+            self.moving = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn respawn(&mut self, location: Vec3) {
+        self.location = location;
+        self.health = MAX_HEALTH;
+        self.armor = START_ARMOR;
+        self.respawn_tick = None;
+        self.inputs.clear();
+    }
+
+    //Validate a shot and record the fire tick.
+    fn check_fire(
+        &mut self,
+        tick: u32,
+        min_interval: u32,
+        origin: Vec3,
+        direction: Vec3,
+    ) -> Result<(), String> {
+        if !self.is_alive() {
+            return Err("The player is dead.".to_string());
+        }
+        if !origin.is_finite() || !direction.is_finite() || direction.length_squared() < 0.01 {
+            return Err(format!("Invalid shot {} {}.", origin, direction));
+        }
+        if origin.distance(self.location) > MAX_FIRE_ORIGIN_OFFSET {
+            return Err(format!(
+                "Shot origin {} is too far from {}.",
+                origin, self.location
+            ));
+        }
+        //A tick of tolerance for network jitter.
+        if let Some(last_fire_tick) = self.last_fire_tick {
+            if tick.wrapping_sub(last_fire_tick) < min_interval.saturating_sub(1) {
+                return Err("Fire rate exceeded.".to_string());
+            }
+        }
+        self.last_fire_tick = Some(tick);
+        Ok(())
+    }
+
+    fn set_rotation(&mut self, rotation: Vec2) {
+        if !rotation.is_finite() {
+            warn!("Invalid rotation {} from player {}.", rotation, self.name);
+            return;
+        }
+        if rotation != self.rotation {
+            self.rotation = rotation;
+            self.rotated = true;
+        }
+    }
+
+    //Returns true when the player moved.
+    fn simulate_inputs(&mut self) -> bool {
+        let mut moved = false;
+        for _ in 0..MAX_INPUTS_PER_TICK {
+            match self.inputs.pop_front() {
+                Some((sequence, movement, rotation)) => {
+                    self.set_rotation(rotation);
+                    self.location = simulate_move(self.location, self.rotation, movement);
+                    self.last_sequence = sequence;
+                    moved = true;
+                }
+                None => break,
+            }
+        }
+        moved
+    }
+}
+
+pub enum Destination {
     Player(u64),
     NotPlayer(u64),
     All,
@@ -314,28 +1294,83 @@ enum Destination {
 #[test]
 fn test_get_player_num() {
     let mut players: HashMap<u64, Player> = HashMap::new();
-    assert_eq!(0, get_player_num(&players));
-    let player = Player {
-        location: Vec3::new(0., 0., 0.),
-        direction: Vec3::new(0., 0., 0.),
-        name: "shrubbo".to_string(),
-        num: 0,
-    };
+    assert_eq!(0, get_player_num(players.values()));
+    let player = Player::new("shrubbo".to_string(), 0, Vec3::new(0., 0., 0.));
     players.insert(111, player);
-    let player = Player {
-        location: Vec3::new(0., 0., 0.),
-        direction: Vec3::new(0., 0., 0.),
-        name: "shrubbo1".to_string(),
-        num: 5,
-    };
+    let player = Player::new("shrubbo1".to_string(), 5, Vec3::new(0., 0., 0.));
     players.insert(222, player);
-    assert_eq!(5, get_player_num(&players));
-    let player = Player {
-        location: Vec3::new(0., 0., 0.),
-        direction: Vec3::new(0., 0., 0.),
-        name: "shrubbo".to_string(),
-        num: 6,
-    };
+    assert_eq!(5, get_player_num(players.values()));
+    let player = Player::new("shrubbo".to_string(), 6, Vec3::new(0., 0., 0.));
     players.insert(311, player);
-    assert_eq!(6, get_player_num(&players));
+    assert_eq!(6, get_player_num(players.values()));
+}
+
+#[test]
+fn test_simulate_inputs() {
+    let mut player = Player::new("shrubbo".to_string(), 1, Vec3::ZERO);
+    assert!(!player.simulate_inputs());
+    for sequence in 1..=5 {
+        player
+            .inputs
+            .push_back((sequence, Movement::Forward, Vec2::ZERO));
+    }
+    //The movement per tick is limited.
+    assert!(player.simulate_inputs());
+    assert_eq!(player.last_sequence, MAX_INPUTS_PER_TICK as u32);
+    assert_eq!(player.inputs.len(), 5 - MAX_INPUTS_PER_TICK);
+    assert_eq!(
+        player.location,
+        Vec3::new(0., 0., -(MAX_INPUTS_PER_TICK as f32))
+    );
+}
+
+#[test]
+fn test_set_rotation() {
+    let mut player = Player::new("shrubbo".to_string(), 1, Vec3::ZERO);
+    player.set_rotation(Vec2::ZERO);
+    assert!(!player.rotated);
+    player.set_rotation(Vec2::new(0.5, -0.1));
+    assert!(player.rotated);
+    assert_eq!(player.rotation, Vec2::new(0.5, -0.1));
+    //Invalid rotations are ignored.
+    player.set_rotation(Vec2::new(f32::NAN, 0.));
+    assert_eq!(player.rotation, Vec2::new(0.5, -0.1));
+}
+
+#[test]
+fn test_check_fire() {
+    let mut player = Player::new("shrubbo".to_string(), 1, Vec3::ZERO);
+    assert!(player.check_fire(10, 5, Vec3::ZERO, Vec3::NEG_Z).is_ok());
+    //Fire rate.
+    assert!(player.check_fire(12, 5, Vec3::ZERO, Vec3::NEG_Z).is_err());
+    assert!(player.check_fire(14, 5, Vec3::ZERO, Vec3::NEG_Z).is_ok());
+    //Origin too far from the player and invalid directions.
+    let far = Vec3::new(MAX_FIRE_ORIGIN_OFFSET + 1., 0., 0.);
+    assert!(player.check_fire(30, 5, far, Vec3::NEG_Z).is_err());
+    assert!(player.check_fire(40, 5, Vec3::ZERO, Vec3::ZERO).is_err());
+    assert!(player
+        .check_fire(50, 5, Vec3::ZERO, Vec3::new(f32::NAN, 0., 0.))
+        .is_err());
+}
+
+#[test]
+fn test_damage_and_respawn() {
+    let mut player = Player::new("shrubbo".to_string(), 1, Vec3::ZERO);
+    //Armor absorbs part of the damage.
+    assert!(!player.apply_damage(25, 100));
+    assert_eq!(player.armor, START_ARMOR - 15);
+    assert_eq!(player.health, MAX_HEALTH - 10);
+    while !player.apply_damage(25, 100) {}
+    assert!(!player.is_alive());
+    assert_eq!(player.health, 0);
+    assert_eq!(player.armor, 0);
+    //Dead players take no damage and can not fire.
+    assert!(!player.apply_damage(25, 200));
+    assert_eq!(player.respawn_tick, Some(100));
+    assert!(player.check_fire(110, 5, Vec3::ZERO, Vec3::NEG_Z).is_err());
+    player.respawn(Vec3::ONE);
+    assert!(player.is_alive());
+    assert_eq!(player.location, Vec3::ONE);
+    assert_eq!(player.health, MAX_HEALTH);
+    assert_eq!(player.armor, START_ARMOR);
 }
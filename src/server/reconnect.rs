@@ -0,0 +1,122 @@
+use bevy::prelude::*;
+use std::net::IpAddr;
+
+use super::game_mode::{Score, Team};
+use crate::players::is_sequence_newer;
+
+//The default grace period of a player who lost the connection.
+pub const DEFAULT_RECONNECT_GRACE_SECS: u32 = 60;
+pub const MAX_RECONNECT_GRACE_SECS: u32 = 600;
+
+//A player who left, restored on returning with the same name from the same address.
+struct LeftPlayer {
+    name: String,
+    ip: IpAddr,
+    score: Score,
+    team: Option<Team>,
+    //The score is from this match.
+    match_number: u32,
+    expire_tick: u32,
+}
+
+//The players who left in the grace period.
+#[derive(Resource)]
+pub struct ReconnectGrace {
+    grace_ticks: u32,
+    left: Vec<LeftPlayer>,
+}
+
+impl ReconnectGrace {
+    pub fn new(grace_ticks: u32) -> ReconnectGrace {
+        ReconnectGrace {
+            grace_ticks,
+            left: vec![],
+        }
+    }
+
+    fn expire(&mut self, tick: u32) {
+        self.left
+            .retain(|player| is_sequence_newer(player.expire_tick, tick));
+    }
+
+    //Keep the score and team of a player who left.
+    pub fn keep(
+        &mut self,
+        name: &str,
+        ip: IpAddr,
+        score: Score,
+        team: Option<Team>,
+        match_number: u32,
+        tick: u32,
+    ) {
+        if self.grace_ticks == 0 {
+            return;
+        }
+        self.expire(tick);
+        self.left
+            .retain(|player| player.name != name || player.ip != ip);
+        self.left.push(LeftPlayer {
+            name: name.to_string(),
+            ip,
+            score,
+            team,
+            match_number,
+            expire_tick: tick.wrapping_add(self.grace_ticks),
+        });
+    }
+
+    //The score and team of a returning player. The score of an earlier match is not restored.
+    pub fn take(
+        &mut self,
+        name: &str,
+        ip: IpAddr,
+        match_number: u32,
+        tick: u32,
+    ) -> Option<(Score, Option<Team>)> {
+        self.expire(tick);
+        let index = self
+            .left
+            .iter()
+            .position(|player| player.name == name && player.ip == ip)?;
+        let player = self.left.swap_remove(index);
+        let score = if player.match_number == match_number {
+            player.score
+        } else {
+            Score::default()
+        };
+        Some((score, player.team))
+    }
+}
+
+#[test]
+fn test_reconnect_grace() {
+    let ip: IpAddr = "192.0.2.5".parse().unwrap();
+    let other_ip: IpAddr = "192.0.2.6".parse().unwrap();
+    let score = Score {
+        frags: 7,
+        deaths: 2,
+    };
+    let mut grace = ReconnectGrace::new(100);
+    grace.keep("ikky", ip, score, Some(Team::Blue), 1, 1000);
+    //Another address or name is a new player.
+    assert_eq!(grace.take("ikky", other_ip, 1, 1010), None);
+    assert_eq!(grace.take("shrubbo", ip, 1, 1010), None);
+    assert_eq!(
+        grace.take("ikky", ip, 1, 1050),
+        Some((score, Some(Team::Blue)))
+    );
+    //Taken once.
+    assert_eq!(grace.take("ikky", ip, 1, 1050), None);
+
+    grace.keep("ikky", ip, score, Some(Team::Red), 1, 1050);
+    assert_eq!(
+        grace.take("ikky", ip, 2, 1100),
+        Some((Score::default(), Some(Team::Red)))
+    );
+    grace.keep("ikky", ip, score, None, 2, u32::MAX - 10);
+    assert_eq!(grace.take("ikky", ip, 2, 90), None);
+
+    let mut off = ReconnectGrace::new(0);
+    off.keep("ikky", ip, score, None, 1, 1000);
+    assert_eq!(off.take("ikky", ip, 1, 1000), None);
+}
@@ -0,0 +1,112 @@
+use bevy::prelude::*;
+use std::time::Instant;
+
+use super::game_mode::Match;
+use super::tick::TickScheduler;
+use super::{Destination, MessagesToDeliver, Player};
+use crate::protocol::{MultiplayerMessage, ScoreboardEntry};
+use crate::ClientId;
+
+//The scoreboard and a ping go to the clients at this interval.
+pub const SCOREBOARD_INTERVAL_SECS: f32 = 1.0;
+//Longer names are cut on the scoreboard so a full server fits in one message.
+const MAX_SCOREBOARD_NAME_BYTES: usize = 24;
+
+//The last ping sent to the clients.
+#[derive(Resource, Default)]
+pub struct PingState {
+    sequence: u32,
+    sent: Option<Instant>,
+}
+
+impl PingState {
+    fn send(&mut self, now: Instant) -> u32 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sent = Some(now);
+        self.sequence
+    }
+
+    //The round trip time of a Pong to the last ping, answers to older pings are ignored. Pongs
+    //are only read once per tick, so the time is rounded up to the tick.
+    pub fn round_trip_ms(&self, sequence: u32, now: Instant) -> Option<u16> {
+        match self.sent {
+            Some(sent) if sequence == self.sequence => {
+                let rtt = now.saturating_duration_since(sent).as_millis();
+                Some(rtt.min(u16::MAX as u128) as u16)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn short_name(name: &str) -> String {
+    let mut end = name.len().min(MAX_SCOREBOARD_NAME_BYTES);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_string()
+}
+
+fn scoreboard_entry(client_id: u64, player: &Player, game: &Match) -> ScoreboardEntry {
+    let score = game.scores.get(&client_id).copied().unwrap_or_default();
+    ScoreboardEntry {
+        client_id,
+        name: short_name(&player.name),
+        team: player.team,
+        frags: score.frags,
+        deaths: score.deaths,
+        ping_ms: player.ping_ms,
+    }
+}
+
+//Ping the clients and send the scoreboard every SCOREBOARD_INTERVAL_SECS.
+pub(super) fn send_scoreboard(
+    scheduler: Res<TickScheduler>,
+    game: Res<Match>,
+    mut ping: ResMut<PingState>,
+    players: Query<(&ClientId, &Player)>,
+    mut messages_to_deliver: ResMut<MessagesToDeliver>,
+) {
+    if !scheduler
+        .tick()
+        .is_multiple_of(scheduler.ticks_in(SCOREBOARD_INTERVAL_SECS))
+    {
+        return;
+    }
+    let sequence = ping.send(Instant::now());
+    messages_to_deliver
+        .0
+        .push((Destination::All, MultiplayerMessage::Ping { sequence }));
+    let entries = players
+        .iter()
+        .map(|(client_id, player)| scoreboard_entry(client_id.id, player, &game))
+        .collect();
+    messages_to_deliver
+        .0
+        .push((Destination::All, MultiplayerMessage::Scoreboard { entries }));
+}
+
+#[test]
+fn test_round_trip() {
+    let mut ping = PingState::default();
+    let now = Instant::now();
+    assert_eq!(ping.round_trip_ms(0, now), None);
+    let sequence = ping.send(now);
+    let later = now + std::time::Duration::from_millis(80);
+    assert_eq!(ping.round_trip_ms(sequence, later), Some(80));
+    //An answer to an older ping.
+    ping.send(later);
+    assert_eq!(ping.round_trip_ms(sequence, later), None);
+}
+
+#[test]
+fn test_scoreboard_fits_a_message() {
+    use super::game_mode::GameMode;
+    let game = Match::new(GameMode::Deathmatch, 0, 600, 1000);
+    let player = Player::new("é".repeat(100), 1, Vec3::ZERO);
+    let entries: Vec<ScoreboardEntry> = (0..super::MAX_CLIENTS as u64)
+        .map(|client_id| scoreboard_entry(client_id, &player, &game))
+        .collect();
+    assert_eq!(entries[0].name, "é".repeat(MAX_SCOREBOARD_NAME_BYTES / 2));
+    assert!(MultiplayerMessage::Scoreboard { entries }.get_buf().is_ok());
+}
@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::net::SocketAddr;
+
+use super::chat::MAX_CHAT_BYTES;
+use super::game_mode::{GameMode, DEFAULT_FRAG_LIMIT, DEFAULT_TIME_LIMIT_SECS, MAPS};
+use super::lag_compensation::{DEFAULT_MAX_REWIND_MS, MAX_REWIND_LIMIT_MS};
+use super::reconnect::{DEFAULT_RECONNECT_GRACE_SECS, MAX_RECONNECT_GRACE_SECS};
+use super::tick::{DEFAULT_TICK_RATE, MAX_TICK_RATE, MIN_TICK_RATE};
+use super::{DEFAULT_KEEPALIVE_SECS, MAX_CLIENTS, PORT};
+use crate::logging::LogSettings;
+use crate::token::DEFAULT_CLIENT_TIMEOUT_SECS;
+
+const MAX_NAME_BYTES: usize = 32;
+const MAX_CLIENT_TIMEOUT_SECS: u32 = 300;
+
+//The server.toml settings, the command line overrides them. Checked by validate before the
+//server starts.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub name: String,
+    //Message of the day, sent to players as they join.
+    pub motd: String,
+    //The address of the game socket, the token service uses the next port.
+    pub bind: String,
+    //The addresses clients connect to, the bind address when empty.
+    pub public_addresses: Vec<String>,
+    pub max_clients: usize,
+    pub tick_rate: u32,
+    pub mode: String,
+    pub frag_limit: u32,
+    pub time_limit_secs: u32,
+    pub friendly_fire: bool,
+    pub max_rewind_ms: u32,
+    //The maps played in turn, a match on each.
+    pub maps: Vec<String>,
+    //The private key file, instead of server.key in the shooter config directory.
+    pub key_file: Option<String>,
+    //A client is dropped after this long without packets.
+    pub client_timeout_secs: u32,
+    //Interval of the netcode keep alive packets.
+    pub keepalive_secs: f32,
+    //A player returning within this long keeps the score and team, 0 for none.
+    pub reconnect_grace_secs: u32,
+    //Answer LAN discovery queries on DISCOVERY_PORT.
+    pub lan_discovery: bool,
+    //The master servers sent heartbeats, ie "203.0.113.1:5100".
+    pub masters: Vec<String>,
+    pub log: LogSettings,
+    //The file the settings were read from.
+    #[serde(skip)]
+    pub config_path: Option<OsString>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> ServerSettings {
+        ServerSettings {
+            name: "Shooter Server".to_string(),
+            motd: String::new(),
+            bind: format!("127.0.0.1:{}", PORT),
+            public_addresses: vec![],
+            max_clients: MAX_CLIENTS,
+            tick_rate: DEFAULT_TICK_RATE,
+            mode: "deathmatch".to_string(),
+            frag_limit: DEFAULT_FRAG_LIMIT,
+            time_limit_secs: DEFAULT_TIME_LIMIT_SECS,
+            friendly_fire: false,
+            max_rewind_ms: DEFAULT_MAX_REWIND_MS,
+            maps: vec![MAPS[0].to_string()],
+            key_file: None,
+            client_timeout_secs: DEFAULT_CLIENT_TIMEOUT_SECS as u32,
+            keepalive_secs: DEFAULT_KEEPALIVE_SECS,
+            reconnect_grace_secs: DEFAULT_RECONNECT_GRACE_SECS,
+            lan_discovery: true,
+            masters: vec![],
+            log: LogSettings::default(),
+            config_path: None,
+        }
+    }
+}
+
+//The checked server settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerOptions {
+    pub name: String,
+    pub motd: String,
+    pub bind: SocketAddr,
+    pub public_addresses: Vec<SocketAddr>,
+    pub max_clients: usize,
+    pub tick_rate: u32,
+    pub max_rewind_ms: u32,
+    pub mode: GameMode,
+    pub frag_limit: u32,
+    pub time_limit_secs: u32,
+    pub friendly_fire: bool,
+    pub maps: Vec<String>,
+    pub key_file: Option<OsString>,
+    pub client_timeout_secs: i32,
+    pub keepalive_secs: f32,
+    pub reconnect_grace_secs: u32,
+    pub lan_discovery: bool,
+    pub masters: Vec<SocketAddr>,
+    pub log: LogSettings,
+    //The server.toml read, the access list is kept next to it.
+    pub config_path: Option<OsString>,
+}
+
+fn parse_addr(addr: &str, setting: &str) -> Result<SocketAddr, String> {
+    addr.parse()
+        .map_err(|_| format!("{} {} is not an address with a port.", setting, addr))
+}
+
+impl ServerSettings {
+    pub fn validate(&self) -> Result<ServerOptions, String> {
+        let name = self.name.trim();
+        if name.is_empty() || name.len() > MAX_NAME_BYTES {
+            return Err(format!("name must be 1-{} bytes.", MAX_NAME_BYTES));
+        }
+        if self.motd.trim().len() > MAX_CHAT_BYTES || self.motd.chars().any(|c| c.is_control()) {
+            return Err(format!(
+                "motd must be at most {} bytes on one line.",
+                MAX_CHAT_BYTES
+            ));
+        }
+        let bind = parse_addr(&self.bind, "bind")?;
+        let public_addresses = if self.public_addresses.is_empty() {
+            if bind.ip().is_unspecified() {
+                return Err(format!(
+                    "bind {} is not an address clients can connect to, set public_addresses.",
+                    bind
+                ));
+            }
+            vec![bind]
+        } else {
+            self.public_addresses
+                .iter()
+                .map(|addr| parse_addr(addr, "public_addresses"))
+                .collect::<Result<Vec<SocketAddr>, String>>()?
+        };
+        if !(1..=MAX_CLIENTS).contains(&self.max_clients) {
+            return Err(format!("max_clients must be 1-{}.", MAX_CLIENTS));
+        }
+        if !(MIN_TICK_RATE..=MAX_TICK_RATE).contains(&self.tick_rate) {
+            return Err(format!(
+                "Invalid tick rate {}, use {}-{}.",
+                self.tick_rate, MIN_TICK_RATE, MAX_TICK_RATE
+            ));
+        }
+        if self.max_rewind_ms > MAX_REWIND_LIMIT_MS {
+            return Err(format!(
+                "Invalid maximum rewind {}, use 0-{}.",
+                self.max_rewind_ms, MAX_REWIND_LIMIT_MS
+            ));
+        }
+        if self.time_limit_secs == 0 {
+            return Err("Invalid time limit 0.".to_string());
+        }
+        if self.maps.is_empty() {
+            return Err("maps must have a map.".to_string());
+        }
+        if let Some(map) = self.maps.iter().find(|map| !MAPS.contains(&map.as_str())) {
+            return Err(format!(
+                "Unknown map {}, the maps are {}.",
+                map,
+                MAPS.join(", ")
+            ));
+        }
+        if !(1..=MAX_CLIENT_TIMEOUT_SECS).contains(&self.client_timeout_secs) {
+            return Err(format!(
+                "client_timeout_secs must be 1-{}.",
+                MAX_CLIENT_TIMEOUT_SECS
+            ));
+        }
+        if !(self.keepalive_secs > 0.0 && self.keepalive_secs < self.client_timeout_secs as f32) {
+            return Err(
+                "keepalive_secs must be above 0 and below client_timeout_secs.".to_string(),
+            );
+        }
+        if self.reconnect_grace_secs > MAX_RECONNECT_GRACE_SECS {
+            return Err(format!(
+                "reconnect_grace_secs must be 0-{}.",
+                MAX_RECONNECT_GRACE_SECS
+            ));
+        }
+        let masters = self
+            .masters
+            .iter()
+            .map(|addr| parse_addr(addr, "masters"))
+            .collect::<Result<Vec<SocketAddr>, String>>()?;
+        self.log.validate()?;
+        Ok(ServerOptions {
+            name: name.to_string(),
+            motd: self.motd.trim().to_string(),
+            bind,
+            public_addresses,
+            max_clients: self.max_clients,
+            tick_rate: self.tick_rate,
+            max_rewind_ms: self.max_rewind_ms,
+            mode: GameMode::parse(&self.mode)?,
+            frag_limit: self.frag_limit,
+            time_limit_secs: self.time_limit_secs,
+            friendly_fire: self.friendly_fire,
+            maps: self.maps.clone(),
+            key_file: self.key_file.as_ref().map(OsString::from),
+            client_timeout_secs: self.client_timeout_secs as i32,
+            keepalive_secs: self.keepalive_secs,
+            reconnect_grace_secs: self.reconnect_grace_secs,
+            lan_discovery: self.lan_discovery,
+            masters,
+            log: self.log.clone(),
+            config_path: self.config_path.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_validate_settings() {
+    let options = ServerSettings::default().validate().unwrap();
+    assert_eq!(options.public_addresses, vec![options.bind]);
+    assert_eq!(options.mode, GameMode::Deathmatch);
+
+    let settings: ServerSettings = toml::from_str(
+        "name = \"Arena\"\nbind = \"0.0.0.0:5000\"\npublic_addresses = [\"203.0.113.9:5000\"]\n\
+        mode = \"tdm\"\nmax_clients = 8\nmasters = [\"198.51.100.2:5100\"]\n\n[log]\nfile = true\nfilters = { network = \"debug\" }\n",
+    )
+    .unwrap();
+    let options = settings.validate().unwrap();
+    assert_eq!(options.public_addresses[0].port(), 5000);
+    assert_eq!(options.mode, GameMode::TeamDeathmatch);
+    assert_eq!(options.max_clients, 8);
+    assert_eq!(options.tick_rate, DEFAULT_TICK_RATE);
+    assert_eq!(options.masters, vec!["198.51.100.2:5100".parse().unwrap()]);
+    assert!(options.log.file);
+    assert_eq!(options.log.filters["network"], "debug");
+
+    let invalid = [
+        ServerSettings {
+            bind: "0.0.0.0:5000".to_string(),
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            max_clients: MAX_CLIENTS + 1,
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            maps: vec!["moon".to_string()],
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            mode: "ctf".to_string(),
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            reconnect_grace_secs: MAX_RECONNECT_GRACE_SECS + 1,
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            masters: vec!["master.example".to_string()],
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            keepalive_secs: 20.0,
+            ..ServerSettings::default()
+        },
+        ServerSettings {
+            log: LogSettings {
+                level: "verbose".to_string(),
+                ..LogSettings::default()
+            },
+            ..ServerSettings::default()
+        },
+    ];
+    for settings in invalid {
+        assert!(settings.validate().is_err(), "{:?}", settings);
+    }
+    assert!(toml::from_str::<ServerSettings>("tickrate = 30\n").is_err());
+}
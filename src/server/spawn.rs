@@ -0,0 +1,83 @@
+use bevy::prelude::*;
+
+use crate::players::look_rotation;
+use crate::weapon::WEAPON_RANGE;
+
+//Player spawn points around the arena.
+pub const SPAWN_POINTS: [Vec3; 8] = [
+    Vec3::new(-40., 4., -40.),
+    Vec3::new(0., 4., -40.),
+    Vec3::new(40., 4., -40.),
+    Vec3::new(40., 4., 0.),
+    Vec3::new(40., 4., 40.),
+    Vec3::new(0., 4., 40.),
+    Vec3::new(-40., 4., 40.),
+    Vec3::new(-40., 4., 0.),
+];
+//A spawn point closer than this to a player is occupied.
+const MIN_SPAWN_DISTANCE: f32 = 5.0;
+//Half of the horizontal field of view used to decide if a player can see a spawn point.
+const VIEW_HALF_ANGLE: f32 = std::f32::consts::FRAC_PI_4;
+
+//A player spawn points are chosen around, location and look rotation (yaw, pitch).
+pub struct Observer {
+    pub location: Vec3,
+    pub rotation: Vec2,
+}
+
+impl Observer {
+    fn can_see(&self, point: Vec3) -> bool {
+        let to_point = point - self.location;
+        let distance = to_point.length();
+        if distance > WEAPON_RANGE {
+            return false;
+        }
+        if distance < f32::EPSILON {
+            return true;
+        }
+        let forward = look_rotation(self.rotation) * Vec3::NEG_Z;
+        forward.angle_between(to_point) <= VIEW_HALF_ANGLE
+    }
+}
+
+//Choose a spawn point that is not occupied and not in view of the living players, preferring
+//the point furthest from the nearest player. Falls back to the furthest point.
+pub fn choose_spawn_point(observers: &[Observer]) -> Vec3 {
+    let nearest = |point: &Vec3| {
+        observers
+            .iter()
+            .map(|observer| observer.location.distance(*point))
+            .fold(f32::MAX, f32::min)
+    };
+    let furthest = |points: &mut dyn Iterator<Item = &Vec3>| {
+        points
+            .max_by(|a, b| nearest(a).total_cmp(&nearest(b)))
+            .copied()
+    };
+    let mut hidden = SPAWN_POINTS.iter().filter(|point| {
+        nearest(point) >= MIN_SPAWN_DISTANCE
+            && !observers.iter().any(|observer| observer.can_see(**point))
+    });
+    furthest(&mut hidden)
+        .or_else(|| furthest(&mut SPAWN_POINTS.iter()))
+        .unwrap_or(Vec3::ZERO)
+}
+
+#[test]
+fn test_choose_spawn_point() {
+    assert!(SPAWN_POINTS.contains(&choose_spawn_point(&[])));
+    //Avoid the occupied point.
+    let observers = [Observer {
+        location: SPAWN_POINTS[0],
+        rotation: Vec2::ZERO,
+    }];
+    assert_ne!(choose_spawn_point(&observers), SPAWN_POINTS[0]);
+    //A player at the centre looking towards -z sees the points at z = -40 within range.
+    let observers = [Observer {
+        location: Vec3::new(0., 4., 0.),
+        rotation: Vec2::ZERO,
+    }];
+    let point = choose_spawn_point(&observers);
+    assert!(!observers[0].can_see(point));
+    assert_ne!(point, SPAWN_POINTS[1]);
+}
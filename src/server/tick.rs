@@ -0,0 +1,162 @@
+use bevy::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_TICK_RATE: u32 = 20;
+pub const MIN_TICK_RATE: u32 = 1;
+pub const MAX_TICK_RATE: u32 = 128;
+//Tick timing statistics are reported at this interval.
+const STATS_INTERVAL_SECS: u32 = 10;
+
+//Processing time of the ticks since the last report.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct TickStats {
+    pub ticks: u32,
+    pub overruns: u32,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+impl TickStats {
+    fn record(&mut self, elapsed: Duration, overrun: bool) {
+        self.ticks += 1;
+        if overrun {
+            self.overruns += 1;
+        }
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.ticks == 0 {
+            Duration::ZERO
+        } else {
+            self.total / self.ticks
+        }
+    }
+}
+
+//Runs the server simulation on a fixed timestep. Ticks are scheduled from the start time, not
+//from the end of the previous tick, so the tick rate does not drift with the processing time.
+#[derive(Resource)]
+pub struct TickScheduler {
+    tick_rate: u32,
+    tick_duration: Duration,
+    next_tick: Instant,
+    tick_start: Instant,
+    tick: u32,
+    stats: TickStats,
+}
+
+impl TickScheduler {
+    pub fn new(tick_rate: u32) -> TickScheduler {
+        let tick_rate = tick_rate.clamp(MIN_TICK_RATE, MAX_TICK_RATE);
+        let now = Instant::now();
+        TickScheduler {
+            tick_rate,
+            tick_duration: Duration::from_secs_f64(1.0 / tick_rate as f64),
+            next_tick: now,
+            tick_start: now,
+            tick: 0,
+            stats: TickStats::default(),
+        }
+    }
+
+    pub fn tick(&self) -> u32 {
+        self.tick
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    pub fn tick_duration(&self) -> Duration {
+        self.tick_duration
+    }
+
+    //The number of ticks in secs, at least 1.
+    pub fn ticks_in(&self, secs: f32) -> u32 {
+        ((secs * self.tick_rate as f32).round() as u32).max(1)
+    }
+
+    //Sleep until the next tick is due and start it. Returns the new tick number.
+    pub fn wait_for_tick(&mut self) -> u32 {
+        let wait = self.until_next_tick(Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+        self.start_tick(Instant::now())
+    }
+
+    fn until_next_tick(&self, now: Instant) -> Duration {
+        self.next_tick.saturating_duration_since(now)
+    }
+
+    //Start a tick at now and schedule the next one.
+    fn start_tick(&mut self, now: Instant) -> u32 {
+        self.tick_start = now;
+        self.tick = self.tick.wrapping_add(1);
+        self.next_tick += self.tick_duration;
+        //More than a tick behind, restart the schedule rather than running a burst of ticks.
+        if self.next_tick + self.tick_duration < self.tick_start {
+            self.next_tick = self.tick_start + self.tick_duration;
+        }
+        self.tick
+    }
+
+    //Record the processing time of the current tick. Returns true if the tick overran.
+    pub fn end_tick(&mut self) -> bool {
+        self.record_tick(self.tick_start.elapsed())
+    }
+
+    fn record_tick(&mut self, elapsed: Duration) -> bool {
+        let overrun = elapsed > self.tick_duration;
+        self.stats.record(elapsed, overrun);
+        overrun
+    }
+
+    //The statistics for the last interval, once per STATS_INTERVAL_SECS.
+    pub fn take_stats(&mut self) -> Option<TickStats> {
+        if self.stats.ticks >= self.tick_rate * STATS_INTERVAL_SECS {
+            Some(std::mem::take(&mut self.stats))
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_tick_stats() {
+    let mut stats = TickStats::default();
+    assert_eq!(stats.average(), Duration::ZERO);
+    stats.record(Duration::from_millis(10), false);
+    stats.record(Duration::from_millis(30), true);
+    assert_eq!(stats.ticks, 2);
+    assert_eq!(stats.overruns, 1);
+    assert_eq!(stats.average(), Duration::from_millis(20));
+    assert_eq!(stats.max, Duration::from_millis(30));
+}
+
+#[test]
+fn test_tick_scheduler() {
+    let ms = Duration::from_millis;
+    let mut scheduler = TickScheduler::new(100);
+    assert_eq!(scheduler.tick_duration(), ms(10));
+    assert_eq!(scheduler.ticks_in(2.0), 200);
+    let start = scheduler.next_tick;
+    //The first tick starts immediately.
+    assert_eq!(scheduler.until_next_tick(start), Duration::ZERO);
+    assert_eq!(scheduler.start_tick(start), 1);
+    assert_eq!(scheduler.until_next_tick(start + ms(4)), ms(6));
+    assert!(!scheduler.record_tick(ms(5)));
+    //A late tick does not move the schedule.
+    assert_eq!(scheduler.start_tick(start + ms(14)), 2);
+    assert_eq!(scheduler.until_next_tick(start + ms(14)), ms(6));
+    assert!(scheduler.record_tick(ms(15)));
+    //More than a tick behind, the schedule restarts from the late tick.
+    assert_eq!(scheduler.start_tick(start + ms(50)), 3);
+    assert_eq!(scheduler.until_next_tick(start + ms(50)), ms(10));
+    assert_eq!(scheduler.tick(), 3);
+    assert_eq!(scheduler.stats.overruns, 1);
+    assert_eq!(TickScheduler::new(0).tick_rate(), MIN_TICK_RATE);
+}
@@ -0,0 +1,358 @@
+use bevy::log::{error, info, warn};
+use bevy::prelude::App;
+use renetcode::{ConnectToken, NETCODE_KEY_BYTES, NETCODE_USER_DATA_BYTES};
+use std::ffi::OsString;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{do_read_server_config, get_file, get_shooter_file_path};
+use crate::logging::add_logging;
+use crate::server::{Username, PORT, PROTOCOL_ID};
+
+//The token service listens on the game server port + TOKEN_PORT_OFFSET (tcp).
+pub const TOKEN_PORT_OFFSET: u16 = 1;
+//Hex encoded private key, overrides the key file.
+pub const PRIVATE_KEY_ENV: &str = "SHOOTER_PRIVATE_KEY";
+//Alternate key file location.
+pub const PRIVATE_KEY_FILE_ENV: &str = "SHOOTER_KEY_FILE";
+const PRIVATE_KEY_FILE: &str = "server.key";
+
+const TOKEN_EXPIRE_SECS: u64 = 300;
+//A client is dropped after this long without packets.
+pub const DEFAULT_CLIENT_TIMEOUT_SECS: i32 = 15;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+//A client has this long to send its name, each request has a thread so a slow client does not
+//hold up the others.
+const READ_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_REQUESTS: usize = 32;
+const MIN_NAME_LEN: usize = 4;
+
+//Reply status bytes, followed by the token or a utf8 reason.
+const TOKEN_OK: u8 = 0;
+const TOKEN_DENIED: u8 = 1;
+
+//Issues signed connect tokens for the game server. The private key never leaves the server
+//process, clients only receive the token. Player names are not authenticated.
+pub struct TokenService {
+    private_key: [u8; NETCODE_KEY_BYTES],
+    //The game server addresses in the tokens.
+    public_addresses: Vec<SocketAddr>,
+    client_timeout_secs: i32,
+    last_client_id: u64,
+}
+
+impl TokenService {
+    pub fn new(
+        public_addresses: Vec<SocketAddr>,
+        private_key: [u8; NETCODE_KEY_BYTES],
+        client_timeout_secs: i32,
+    ) -> TokenService {
+        TokenService {
+            private_key,
+            public_addresses,
+            client_timeout_secs,
+            last_client_id: 0,
+        }
+    }
+
+    pub fn issue_token(&mut self, user_name: &str) -> Result<ConnectToken, String> {
+        validate_user_name(user_name)?;
+        let username = Username(user_name.to_string());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        //Client ids are unique for the lifetime of the token service.
+        let client_id = (now.as_millis() as u64).max(self.last_client_id + 1);
+        self.last_client_id = client_id;
+        ConnectToken::generate(
+            now,
+            PROTOCOL_ID,
+            TOKEN_EXPIRE_SECS,
+            client_id,
+            self.client_timeout_secs,
+            self.public_addresses.clone(),
+            Some(&username.to_netcode_user_data()),
+            &self.private_key,
+        )
+        .map_err(|e| format!("Could not generate token. {}", e))
+    }
+}
+
+//Read the player name and reply with a token, the service is only locked to issue it.
+fn handle_request(
+    service: &Mutex<TokenService>,
+    mut stream: TcpStream,
+) -> Result<(), std::io::Error> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut user_name = String::new();
+    BufReader::new(&stream)
+        .take(NETCODE_USER_DATA_BYTES as u64)
+        .read_line(&mut user_name)?;
+    let user_name = user_name.trim_end();
+    let token = match service.lock() {
+        Ok(mut service) => service.issue_token(user_name),
+        Err(_) => Err("Token service error.".to_string()),
+    };
+    match token {
+        Ok(token) => {
+            info!("Issued token for {}.", user_name);
+            let mut reply: Vec<u8> = vec![TOKEN_OK];
+            token.write(&mut reply)?;
+            stream.write_all(&reply)
+        }
+        Err(reason) => {
+            info!("Token denied for {}: {}", user_name, reason);
+            stream.write_all(&[TOKEN_DENIED])?;
+            stream.write_all(reason.as_bytes())
+        }
+    }
+}
+
+pub fn validate_user_name(user_name: &str) -> Result<(), String> {
+    if user_name.len() < MIN_NAME_LEN {
+        return Err(format!(
+            "Player name must be at least {} characters.",
+            MIN_NAME_LEN
+        ));
+    }
+    if user_name.len() > NETCODE_USER_DATA_BYTES - 8 {
+        return Err("Player name is too long.".to_string());
+    }
+    if user_name.chars().any(|c| c.is_control()) {
+        return Err("Player name contains control characters.".to_string());
+    }
+    Ok(())
+}
+
+//The token service address for a game server address.
+pub fn token_addr(server_addr: SocketAddr) -> Result<SocketAddr, String> {
+    let port = server_addr
+        .port()
+        .checked_add(TOKEN_PORT_OFFSET)
+        .ok_or_else(|| {
+            format!(
+                "No token service port above game port {}.",
+                server_addr.port()
+            )
+        })?;
+    let mut addr = server_addr;
+    addr.set_port(port);
+    Ok(addr)
+}
+
+//Run the token service on a thread alongside the game server bound to server_addr.
+pub fn spawn_token_service(
+    server_addr: SocketAddr,
+    public_addresses: Vec<SocketAddr>,
+    private_key: [u8; NETCODE_KEY_BYTES],
+    client_timeout_secs: i32,
+) -> Result<thread::JoinHandle<()>, String> {
+    let listen_addr = token_addr(server_addr)?;
+    let listener = TcpListener::bind(listen_addr)
+        .map_err(|e| format!("Could not bind token service {}. {}", listen_addr, e))?;
+    info!("Token service listening on {}", listen_addr);
+    let service = Arc::new(Mutex::new(TokenService::new(
+        public_addresses,
+        private_key,
+        client_timeout_secs,
+    )));
+    let requests = Arc::new(AtomicUsize::new(0));
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if requests.load(Ordering::Relaxed) >= MAX_REQUESTS {
+                        warn!("Too many token requests, dropping a connection.");
+                        continue;
+                    }
+                    requests.fetch_add(1, Ordering::Relaxed);
+                    let service = service.clone();
+                    let requests = requests.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_request(&service, stream) {
+                            warn!("Token request failed. {}", e);
+                        }
+                        requests.fetch_sub(1, Ordering::Relaxed);
+                    });
+                }
+                Err(e) => warn!("Token service connection error. {}", e),
+            }
+        }
+    }))
+}
+
+//Standalone token service for the game server at connection.
+pub fn token_server_main(connection: Option<&str>) {
+    //The service logs with the server.toml log settings, there is no app to run.
+    let log_settings = do_read_server_config(None)
+        .map(|settings| settings.log)
+        .unwrap_or_default();
+    add_logging(&mut App::new(), &log_settings, "token.log");
+    let server_addr: SocketAddr = match connection {
+        Some(con) => match con.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                error!("Not a valid connection: {}", con);
+                return;
+            }
+        },
+        None => format!("127.0.0.1:{}", PORT).parse().unwrap(),
+    };
+    let private_key = match load_private_key(None) {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Could not load the private key. {}", e);
+            return;
+        }
+    };
+    match spawn_token_service(
+        server_addr,
+        vec![server_addr],
+        private_key,
+        DEFAULT_CLIENT_TIMEOUT_SECS,
+    ) {
+        Ok(handle) => {
+            if handle.join().is_err() {
+                error!("Token service failed.");
+            }
+        }
+        Err(e) => error!("{}", e),
+    }
+}
+
+//Ask the token service of the game server for a connect token.
+pub fn request_connect_token(
+    server_addr: SocketAddr,
+    user_name: &str,
+) -> Result<ConnectToken, String> {
+    let addr = token_addr(server_addr)?;
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)
+        .map_err(|e| format!("Could not reach token service {}. {}", addr, e))?;
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|e| format!("Token service error. {}", e))?;
+    stream
+        .write_all(format!("{}\n", user_name).as_bytes())
+        .map_err(|e| format!("Could not send token request. {}", e))?;
+    let mut reply: Vec<u8> = vec![];
+    stream
+        .read_to_end(&mut reply)
+        .map_err(|e| format!("Could not read token reply. {}", e))?;
+    match reply.split_first() {
+        Some((&TOKEN_OK, token)) => ConnectToken::read(&mut std::io::Cursor::new(token))
+            .map_err(|e| format!("Invalid connect token. {}", e)),
+        Some((&TOKEN_DENIED, reason)) => Err(String::from_utf8_lossy(reason).to_string()),
+        _ => Err("Empty reply from token service.".to_string()),
+    }
+}
+
+//The private key is read from SHOOTER_PRIVATE_KEY (64 hex digits) or from the key file: key_file,
+//SHOOTER_KEY_FILE or server.key in the shooter config directory. A random key file is created
+//on first use.
+pub fn load_private_key(key_file: Option<&OsString>) -> Result<[u8; NETCODE_KEY_BYTES], String> {
+    if let Ok(hex) = std::env::var(PRIVATE_KEY_ENV) {
+        return parse_hex_key(hex.trim());
+    }
+    let key_path: OsString = match (key_file, std::env::var_os(PRIVATE_KEY_FILE_ENV)) {
+        (Some(path), _) => path.clone(),
+        (None, Some(path)) => path,
+        (None, None) => get_shooter_file_path(PRIVATE_KEY_FILE)?,
+    };
+    read_key_file(&key_path)
+}
+
+fn read_key_file(key_path: &OsString) -> Result<[u8; NETCODE_KEY_BYTES], String> {
+    let path = Path::new(key_path);
+    match std::fs::read_to_string(path) {
+        Ok(hex) => parse_hex_key(hex.trim()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key: [u8; NETCODE_KEY_BYTES] = renetcode::generate_random_bytes();
+            let mut file = get_file(key_path, false)?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let _ = file.set_permissions(std::fs::Permissions::from_mode(0o600));
+            }
+            file.write_all(to_hex(&key).as_bytes())
+                .map_err(|e| format!("Could not write key file. {}", e))?;
+            info!("Generated a new private key file: {}", path.display());
+            Ok(key)
+        }
+        Err(e) => Err(format!("Could not read key file {}. {}", path.display(), e)),
+    }
+}
+
+fn parse_hex_key(hex: &str) -> Result<[u8; NETCODE_KEY_BYTES], String> {
+    if hex.len() != NETCODE_KEY_BYTES * 2 || !hex.is_ascii() {
+        return Err(format!(
+            "Private key must be {} hex digits.",
+            NETCODE_KEY_BYTES * 2
+        ));
+    }
+    let mut key = [0u8; NETCODE_KEY_BYTES];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|e| format!("Invalid private key. {}", e))?;
+    }
+    Ok(key)
+}
+
+fn to_hex(key: &[u8]) -> String {
+    key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_hex_key() {
+    let key: [u8; NETCODE_KEY_BYTES] = *b"an example very very secret key.";
+    let hex = to_hex(&key);
+    assert_eq!(hex.len(), NETCODE_KEY_BYTES * 2);
+    assert_eq!(parse_hex_key(&hex).unwrap(), key);
+    assert!(parse_hex_key("abc").is_err());
+    assert!(parse_hex_key(&"zz".repeat(NETCODE_KEY_BYTES)).is_err());
+}
+
+#[test]
+fn test_validate_user_name() {
+    assert!(validate_user_name("shrubbo").is_ok());
+    assert!(validate_user_name("abc").is_err());
+    assert!(validate_user_name(&"x".repeat(NETCODE_USER_DATA_BYTES)).is_err());
+    assert!(validate_user_name("shr\nubbo").is_err());
+}
+
+#[test]
+fn test_token_addr() {
+    let server_addr: SocketAddr = "192.0.2.5:5000".parse().unwrap();
+    assert_eq!(
+        token_addr(server_addr),
+        Ok("192.0.2.5:5001".parse().unwrap())
+    );
+    let server_addr: SocketAddr = "192.0.2.5:65535".parse().unwrap();
+    assert!(token_addr(server_addr).is_err());
+}
+
+#[test]
+fn test_idle_token_request() {
+    let server_addr: SocketAddr = "127.0.0.1:38520".parse().unwrap();
+    let key: [u8; NETCODE_KEY_BYTES] = *b"an example very very secret key.";
+    spawn_token_service(
+        server_addr,
+        vec![server_addr],
+        key,
+        DEFAULT_CLIENT_TIMEOUT_SECS,
+    )
+    .unwrap();
+    //A client that connects and sends nothing does not hold up the others.
+    let _idle = TcpStream::connect(token_addr(server_addr).unwrap()).unwrap();
+    let started = std::time::Instant::now();
+    let token = request_connect_token(server_addr, "shrubbo").unwrap();
+    assert!(started.elapsed() < READ_TIMEOUT);
+    assert_eq!(token.protocol_id, PROTOCOL_ID);
+    assert_eq!(
+        request_connect_token(server_addr, "abc").err(),
+        Some("Player name must be at least 4 characters.".to_string())
+    );
+}
@@ -0,0 +1,313 @@
+use bevy::prelude::*;
+
+use crate::client::ServerConnection;
+use crate::hud::player_active;
+use crate::input_n_state::{AppParams, MultiplayerState};
+use crate::players::interpolation::SnapshotBuffer;
+use crate::players::{is_sequence_newer, look_rotation, MouseRotation};
+use crate::protocol::MultiplayerMessage;
+use crate::{ClientId, FirstPerson};
+
+//Hitscan weapon.
+pub const WEAPON_RANGE: f32 = 100.0;
+pub const WEAPON_DAMAGE: u32 = 25;
+pub const FIRE_INTERVAL_SECS: f32 = 0.25;
+//The player hitbox is a capsule centred on the player location.
+pub const CAPSULE_RADIUS: f32 = 0.5;
+pub const CAPSULE_HALF_HEIGHT: f32 = 0.6;
+
+const TRACER_SECS: f64 = 0.1;
+const MUZZLE_FLASH_SECS: f64 = 0.05;
+const HIT_MARKER_SECS: f64 = 0.2;
+//Distance of the muzzle flash in front of the shooter.
+const MUZZLE_OFFSET: f32 = 0.7;
+
+#[derive(Event)]
+pub struct PlayerFireEvent;
+
+//A shot to draw, from the local player or from a server Hit message.
+#[derive(Event)]
+pub struct ShotEvent {
+    pub origin: Vec3,
+    pub point: Vec3,
+    //The server confirmed a hit by the local player.
+    pub hit_confirmed: bool,
+}
+
+pub struct Capsule {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub radius: f32,
+}
+
+impl Capsule {
+    pub fn player(location: Vec3) -> Capsule {
+        Capsule {
+            start: location - Vec3::Y * CAPSULE_HALF_HEIGHT,
+            end: location + Vec3::Y * CAPSULE_HALF_HEIGHT,
+            radius: CAPSULE_RADIUS,
+        }
+    }
+
+    //The distance along the ray to the capsule surface. The entry point is approximated
+    //from the closest approach of the ray to the capsule axis.
+    pub fn ray_hit(&self, origin: Vec3, direction: Vec3, range: f32) -> Option<f32> {
+        let ray_end = origin + direction * range;
+        let (s, closest_ray, closest_axis) = closest_points(origin, ray_end, self.start, self.end);
+        let distance = closest_ray.distance(closest_axis);
+        if distance > self.radius {
+            return None;
+        }
+        let entry = s * range - (self.radius * self.radius - distance * distance).sqrt();
+        Some(entry.max(0.))
+    }
+}
+
+//Closest points of the segments p1-q1 and p2-q2. Returns the fraction along p1-q1 and
+//the two points.
+fn closest_points(p1: Vec3, q1: Vec3, p2: Vec3, q2: Vec3) -> (f32, Vec3, Vec3) {
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = d1.length_squared();
+    let e = d2.length_squared();
+    let f = d2.dot(r);
+    let (s, t) = if a <= f32::EPSILON && e <= f32::EPSILON {
+        (0., 0.)
+    } else if a <= f32::EPSILON {
+        (0., (f / e).clamp(0., 1.))
+    } else {
+        let c = d1.dot(r);
+        if e <= f32::EPSILON {
+            ((-c / a).clamp(0., 1.), 0.)
+        } else {
+            let b = d1.dot(d2);
+            let denom = a * e - b * b;
+            let s = if denom > f32::EPSILON {
+                ((b * f - c * e) / denom).clamp(0., 1.)
+            } else {
+                0.
+            };
+            let t = (b * s + f) / e;
+            if t < 0. {
+                ((-c / a).clamp(0., 1.), 0.)
+            } else if t > 1. {
+                (((b - c) / a).clamp(0., 1.), 1.)
+            } else {
+                (s, t)
+            }
+        }
+    };
+    (s, p1 + d1 * s, p2 + d2 * t)
+}
+
+//The nearest target hit by the ray, with the distance along the ray.
+pub fn trace<T>(
+    origin: Vec3,
+    direction: Vec3,
+    targets: impl Iterator<Item = (T, Vec3)>,
+) -> Option<(T, f32)> {
+    targets
+        .filter_map(|(target, location)| {
+            Capsule::player(location)
+                .ray_hit(origin, direction, WEAPON_RANGE)
+                .map(|distance| (target, distance))
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+struct Tracer {
+    origin: Vec3,
+    point: Vec3,
+    expires: f64,
+}
+
+//Shot effects drawn with gizmos until they expire.
+#[derive(Resource, Default)]
+struct ShotEffects {
+    tracers: Vec<Tracer>,
+    hit_marker_expires: f64,
+}
+
+#[derive(Component)]
+struct HitMarker;
+
+pub struct WeaponPlugin;
+impl Plugin for WeaponPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<PlayerFireEvent>();
+        app.add_event::<ShotEvent>();
+        app.init_resource::<ShotEffects>();
+        app.add_systems(Startup, spawn_hit_marker);
+        app.add_systems(
+            Update,
+            fire_cmd.run_if(in_state(MultiplayerState::Connected).and(player_active)),
+        );
+        app.add_systems(
+            Update,
+            (add_shot_effects, draw_shot_effects)
+                .chain()
+                .after(fire_cmd),
+        );
+    }
+}
+
+//Fire along the look direction. The tracer is drawn immediately, the server decides the hit.
+fn fire_cmd(
+    mut fire_events: EventReader<PlayerFireEvent>,
+    time: Res<Time<Real>>,
+    mut next_fire: Local<f64>,
+    (mouse_rotation, app_params): (Res<MouseRotation>, Res<AppParams>),
+    players: Query<(
+        &ClientId,
+        &Transform,
+        Option<&FirstPerson>,
+        Option<&SnapshotBuffer>,
+    )>,
+    connection: ServerConnection,
+    mut shots: EventWriter<ShotEvent>,
+) {
+    for PlayerFireEvent in fire_events.read() {
+        let now = time.elapsed_secs_f64();
+        if now < *next_fire {
+            continue;
+        }
+        *next_fire = now + FIRE_INTERVAL_SECS as f64;
+        let Some((_, transform, _, _)) = players.iter().find(|(_, _, fp, _)| fp.is_some()) else {
+            continue;
+        };
+        let origin = transform.translation;
+        let direction = *(Transform::from_rotation(look_rotation(mouse_rotation.0)).forward());
+        //The newest server tick of the remote players on screen.
+        let tick = players
+            .iter()
+            .filter_map(|(_, _, _, buffer)| buffer.and_then(|b| b.snapshots.back()))
+            .map(|snapshot| snapshot.tick)
+            .reduce(|a, b| if is_sequence_newer(b, a) { b } else { a })
+            .unwrap_or(0);
+        connection.send(MultiplayerMessage::Fire {
+            client_id: connection.client_id(),
+            tick,
+            interpolation_delay_ms: app_params.interpolation_delay_ms.min(u16::MAX as u32) as u16,
+            origin,
+            direction,
+        });
+        let remote_players = players
+            .iter()
+            .filter(|(_, _, fp, _)| fp.is_none())
+            .map(|(cid, transform, _, _)| (cid.id, transform.translation));
+        let distance = trace(origin, direction, remote_players).map_or(WEAPON_RANGE, |(_, d)| d);
+        shots.send(ShotEvent {
+            origin,
+            point: origin + direction * distance,
+            hit_confirmed: false,
+        });
+    }
+}
+
+fn add_shot_effects(
+    time: Res<Time<Real>>,
+    mut shots: EventReader<ShotEvent>,
+    mut effects: ResMut<ShotEffects>,
+) {
+    let now = time.elapsed_secs_f64();
+    for shot in shots.read() {
+        if shot.hit_confirmed {
+            effects.hit_marker_expires = now + HIT_MARKER_SECS;
+        } else {
+            effects.tracers.push(Tracer {
+                origin: shot.origin,
+                point: shot.point,
+                expires: now + TRACER_SECS,
+            });
+        }
+    }
+}
+
+fn draw_shot_effects(
+    time: Res<Time<Real>>,
+    mut gizmos: Gizmos,
+    mut effects: ResMut<ShotEffects>,
+    mut hit_marker: Query<&mut Visibility, With<HitMarker>>,
+) {
+    let now = time.elapsed_secs_f64();
+    effects.tracers.retain(|tracer| tracer.expires > now);
+    for tracer in effects.tracers.iter() {
+        gizmos.line(tracer.origin, tracer.point, Color::srgb(1.0, 0.9, 0.4));
+        if tracer.expires - now > TRACER_SECS - MUZZLE_FLASH_SECS {
+            let muzzle =
+                tracer.origin + (tracer.point - tracer.origin).normalize_or_zero() * MUZZLE_OFFSET;
+            gizmos.sphere(
+                Isometry3d::from_translation(muzzle),
+                0.1,
+                Color::srgb(1.0, 0.6, 0.1),
+            );
+        }
+    }
+    if let Ok(mut visibility) = hit_marker.get_single_mut() {
+        *visibility = if effects.hit_marker_expires > now {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+}
+
+fn spawn_hit_marker(mut commands: Commands) {
+    commands
+        .spawn(Node {
+            width: Val::Percent(100.),
+            height: Val::Percent(100.),
+            justify_content: JustifyContent::Center,
+            align_items: AlignItems::Center,
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn((
+                Text::new("X"),
+                TextColor(Color::srgb(1.0, 0.2, 0.2)),
+                Visibility::Hidden,
+                HitMarker,
+            ));
+        });
+}
+
+#[test]
+fn test_capsule_ray_hit() {
+    let capsule = Capsule::player(Vec3::ZERO);
+    let distance = capsule
+        .ray_hit(Vec3::new(0., 0., 10.), Vec3::NEG_Z, WEAPON_RANGE)
+        .unwrap();
+    assert!((distance - (10. - CAPSULE_RADIUS)).abs() < 0.0001);
+    //Passing beside, above and pointing away.
+    assert!(capsule
+        .ray_hit(Vec3::new(0.6, 0., 10.), Vec3::NEG_Z, WEAPON_RANGE)
+        .is_none());
+    assert!(capsule
+        .ray_hit(Vec3::new(0., 1.2, 10.), Vec3::NEG_Z, WEAPON_RANGE)
+        .is_none());
+    assert!(capsule
+        .ray_hit(Vec3::new(0., 0., 10.), Vec3::Z, WEAPON_RANGE)
+        .is_none());
+    //Out of range.
+    assert!(capsule
+        .ray_hit(Vec3::new(0., 0., 10.), Vec3::NEG_Z, 5.)
+        .is_none());
+}
+
+#[test]
+fn test_trace_nearest() {
+    let targets = vec![
+        (1u64, Vec3::new(0., 0., -20.)),
+        (2u64, Vec3::new(0., 0., -10.)),
+    ];
+    let (target, distance) = trace(Vec3::ZERO, Vec3::NEG_Z, targets.into_iter()).unwrap();
+    assert_eq!(target, 2);
+    assert!((distance - (10. - CAPSULE_RADIUS)).abs() < 0.0001);
+    assert!(trace(
+        Vec3::ZERO,
+        Vec3::Z,
+        vec![(1u64, Vec3::new(0., 0., -20.))].into_iter()
+    )
+    .is_none());
+}